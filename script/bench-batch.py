@@ -0,0 +1,138 @@
+# Script comparing the throughput of the batch decryption functions with
+# decrypting message by message.
+#
+# Usage: python script/bench-batch.py [messages per run]
+
+import sys
+import time
+
+from signal_protocol import address, curve, group_cipher, identity_key, protocol
+from signal_protocol import ratchet, session_cipher, storage
+
+SENDERS = 10
+DISTRIBUTION_ID = "a6fe9593-2ca5-41bc-99e9-60a436fbef77"
+
+
+def new_store():
+    return storage.InMemSignalProtocolStore(
+        identity_key.IdentityKeyPair.generate(),
+        identity_key.generate_registration_id(),
+    )
+
+
+def session_pair():
+    alice_identity = identity_key.IdentityKeyPair.generate()
+    bob_identity = identity_key.IdentityKeyPair.generate()
+    alice_base_key = curve.KeyPair.generate()
+    bob_base_key = curve.KeyPair.generate()
+
+    alice_session = ratchet.initialize_alice_session(
+        ratchet.AliceSignalProtocolParameters(
+            alice_identity,
+            alice_base_key,
+            bob_identity.identity_key(),
+            bob_base_key.public_key(),
+            bob_base_key.public_key(),
+        )
+    )
+    bob_session = ratchet.initialize_bob_session(
+        ratchet.BobSignalProtocolParameters(
+            bob_identity,
+            bob_base_key,
+            None,
+            bob_base_key,
+            alice_identity.identity_key(),
+            alice_base_key.public_key(),
+        )
+    )
+    return alice_session, bob_session
+
+
+def session_messages(count):
+    bob_address = address.ProtocolAddress("+14158888888", 1)
+    bob_store = new_store()
+    messages = []
+    for i in range(SENDERS):
+        alice_address = address.ProtocolAddress(f"+141599999{i:02}", 1)
+        alice_store = new_store()
+        alice_session, bob_session = session_pair()
+        alice_store.store_session(bob_address, alice_session)
+        bob_store.store_session(alice_address, bob_session)
+        for _ in range(count // SENDERS):
+            ctext = session_cipher.message_encrypt(alice_store, bob_address, b"x" * 200)
+            messages.append((alice_address, ctext.message_type(), ctext.serialize()))
+    return bob_store, messages
+
+
+def group_messages(count):
+    bob_store = new_store()
+    messages = []
+    for i in range(SENDERS):
+        sender_address = address.ProtocolAddress(f"+141599999{i:02}", 1)
+        sender_store = new_store()
+        group_cipher.process_sender_key_distribution_message(
+            sender_address,
+            group_cipher.create_sender_key_distribution_message(
+                sender_address, DISTRIBUTION_ID, sender_store
+            ),
+            bob_store,
+        )
+        for _ in range(count // SENDERS):
+            ctext = group_cipher.group_encrypt(
+                sender_store, sender_address, DISTRIBUTION_ID, b"x" * 200
+            )
+            messages.append((sender_address, ctext))
+    return bob_store, messages
+
+
+def measure(name, count, setup, decrypt_each, decrypt_batch):
+    # Each run needs fresh stores, as decrypting advances the ratchets.
+    store, messages = setup(count)
+    start = time.perf_counter()
+    decrypt_each(store, messages)
+    per_call_rate = len(messages) / (time.perf_counter() - start)
+
+    store, messages = setup(count)
+    start = time.perf_counter()
+    results = decrypt_batch(store, messages)
+    batch_rate = len(messages) / (time.perf_counter() - start)
+    assert all(isinstance(result, bytes) for result in results)
+
+    print(
+        f"{name}: {per_call_rate:,.0f} msg/s per call, {batch_rate:,.0f} msg/s batched "
+        f"({batch_rate / per_call_rate:.2f}x)"
+    )
+
+
+def main():
+    count = int(sys.argv[1]) if len(sys.argv) > 1 else 10_000
+
+    # The sessions are set up directly, so all messages are SignalMessages.
+    def decrypt_each(store, messages):
+        for remote_address, _, data in messages:
+            session_cipher.message_decrypt_signal(
+                store, remote_address, protocol.SignalMessage.try_from(data)
+            )
+
+    def group_decrypt_each(store, messages):
+        for sender_address, data in messages:
+            group_cipher.group_decrypt(data, store, sender_address)
+
+    measure(
+        "session_cipher.decrypt_batch",
+        count,
+        session_messages,
+        decrypt_each,
+        session_cipher.decrypt_batch,
+    )
+    measure(
+        "group_cipher.group_decrypt_batch",
+        count,
+        group_messages,
+        group_decrypt_each,
+        group_cipher.group_decrypt_batch,
+    )
+
+
+if __name__ == "__main__":
+    main()
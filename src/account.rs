@@ -0,0 +1,149 @@
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict, PyList};
+use pyo3::wrap_pyfunction;
+
+use rand::rngs::OsRng;
+use rand::Rng;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use libsignal_protocol::GenericSignedPreKey;
+
+use crate::curve::KeyPair;
+use crate::error::SignalProtocolError;
+use crate::identity_key::{generate_registration_id, IdentityKeyPair};
+use crate::state::{KyberPreKeyRecord, PreKeyRecord};
+use crate::storage::InMemSignalProtocolStore;
+
+/// Prekey ids used by the official clients fit in 24 bits.
+pub const MAX_PRE_KEY_ID: u32 = 0xFF_FFFF;
+
+// A random first id, such that `count` sequential ids fit below MAX_PRE_KEY_ID.
+fn first_pre_key_id(count: u32) -> u32 {
+    OsRng.gen_range(1..=MAX_PRE_KEY_ID - count)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+fn signed_key_dict<'py>(
+    py: Python<'py>,
+    key_id: u32,
+    public_key: &[u8],
+    signature: &[u8],
+) -> PyResult<Bound<'py, PyDict>> {
+    let result = PyDict::new(py);
+    result.set_item("key_id", key_id)?;
+    result.set_item("public_key", PyBytes::new(py, public_key))?;
+    result.set_item("signature", PyBytes::new(py, signature))?;
+    Ok(result)
+}
+
+fn kyber_key_dict<'py>(
+    py: Python<'py>,
+    record: &KyberPreKeyRecord,
+) -> PyResult<Bound<'py, PyDict>> {
+    let err = SignalProtocolError::new_err;
+    signed_key_dict(
+        py,
+        record.state.id().map_err(err)?.into(),
+        &record.state.public_key().map_err(err)?.serialize(),
+        &record.state.signature().map_err(err)?,
+    )
+}
+
+/// Sets up a new account: generates the identity key pair, registration id,
+/// a signed prekey, a Kyber last-resort prekey and `num_one_time` one-time and
+/// `num_kyber` one-time Kyber prekeys, all saved in a new store.
+///
+/// Returns the store and the public payload to upload to the server: a dict with
+/// registration_id, identity_key, signed_pre_key, pq_last_resort_pre_key,
+/// pre_keys and pq_pre_keys. Signed keys are dicts of key_id, public_key and
+/// signature (made with the identity key); one-time prekeys have no signature.
+#[pyfunction]
+#[pyo3(signature = (num_one_time=100, num_kyber=100))]
+pub fn provision(
+    py: Python,
+    num_one_time: u16,
+    num_kyber: u16,
+) -> PyResult<(InMemSignalProtocolStore, PyObject)> {
+    let err = SignalProtocolError::new_err;
+    let identity_key_pair = IdentityKeyPair::generate();
+    let registration_id = generate_registration_id();
+    let protocol_store = InMemSignalProtocolStore::new(&identity_key_pair, registration_id)?;
+    let store = &protocol_store.store;
+    let identity_private_key = identity_key_pair.private_key()?;
+
+    let signed_pre_key_id = first_pre_key_id(1);
+    let signed_pre_key_pair = KeyPair::generate();
+    let signed_pre_key_public = signed_pre_key_pair.key.public_key.serialize();
+    let signed_pre_key_signature = identity_private_key
+        .key
+        .calculate_signature(&signed_pre_key_public, &mut OsRng)
+        .map_err(SignalProtocolError::from)?;
+    store.signed_pre_key_store.insert(
+        signed_pre_key_id.into(),
+        libsignal_protocol::SignedPreKeyRecord::new(
+            signed_pre_key_id.into(),
+            libsignal_protocol::Timestamp::from_epoch_millis(now_millis()),
+            &signed_pre_key_pair.key,
+            &signed_pre_key_signature,
+        ),
+    );
+
+    let pre_keys = PyList::empty(py);
+    let first_id = first_pre_key_id(u32::from(num_one_time));
+    for id in first_id..first_id + u32::from(num_one_time) {
+        let record = PreKeyRecord::new(id, &KeyPair::generate());
+        store.pre_key_store.insert(id.into(), record.state.clone());
+        let item = PyDict::new(py);
+        item.set_item("key_id", id)?;
+        item.set_item(
+            "public_key",
+            PyBytes::new(py, &record.state.public_key().map_err(err)?.serialize()),
+        )?;
+        pre_keys.append(item)?;
+    }
+
+    // The last-resort key takes the id following the one-time Kyber prekeys.
+    let pq_pre_keys = PyList::empty(py);
+    let first_id = first_pre_key_id(u32::from(num_kyber) + 1);
+    let last_resort_id = first_id + u32::from(num_kyber);
+    for id in first_id..last_resort_id {
+        let record = KyberPreKeyRecord::generate(id, &identity_private_key)?;
+        store.kyber_pre_key_store.insert(id.into(), record.state.clone());
+        pq_pre_keys.append(kyber_key_dict(py, &record)?)?;
+    }
+    let last_resort = KyberPreKeyRecord::generate(last_resort_id, &identity_private_key)?;
+    store
+        .kyber_pre_key_store
+        .insert(last_resort_id.into(), last_resort.state.clone());
+
+    let payload = PyDict::new(py);
+    payload.set_item("registration_id", registration_id)?;
+    payload.set_item(
+        "identity_key",
+        PyBytes::new(py, &identity_key_pair.key.identity_key().serialize()),
+    )?;
+    payload.set_item(
+        "signed_pre_key",
+        signed_key_dict(
+            py,
+            signed_pre_key_id,
+            &signed_pre_key_public,
+            &signed_pre_key_signature,
+        )?,
+    )?;
+    payload.set_item("pq_last_resort_pre_key", kyber_key_dict(py, &last_resort)?)?;
+    payload.set_item("pre_keys", pre_keys)?;
+    payload.set_item("pq_pre_keys", pq_pre_keys)?;
+    Ok((protocol_store, payload.into()))
+}
+
+pub fn init_submodule(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add("MAX_PRE_KEY_ID", MAX_PRE_KEY_ID)?;
+    module.add_wrapped(wrap_pyfunction!(provision))?;
+    Ok(())
+}
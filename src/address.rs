@@ -1,6 +1,20 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+
+use pyo3::basic::CompareOp;
 use pyo3::prelude::*;
+use pyo3::types::{PyBool, PyBytes};
+
+use crate::buffer::BytesLike;
+use crate::consts::MAX_DEVICE_ID;
+use crate::distribution_id::uuid_from_py;
+use crate::error::{InvalidArgumentException, SignalProtocolError};
+use crate::state::SessionRecord;
+use crate::storage::lock;
 
-#[pyclass]
+#[pyclass(module = "signal_protocol.address")]
 #[derive(Clone, Debug)]
 pub struct ProtocolAddress {
     pub state: libsignal_protocol::ProtocolAddress,
@@ -15,6 +29,12 @@ impl ProtocolAddress {
         }
     }
 
+    /// The address of a device of the account identified by `service_id`.
+    #[staticmethod]
+    fn from_service_id(service_id: PyRef<ServiceId>, device_id: u32) -> ProtocolAddress {
+        ProtocolAddress::new(service_id.inner.service_id_string(), device_id)
+    }
+
     pub fn name(&self) -> &str {
         self.state.name()
     }
@@ -23,6 +43,15 @@ impl ProtocolAddress {
         self.state.device_id().into()
     }
 
+    /// The ServiceId encoded in the name, or None if the name is not a ServiceId
+    /// string (e.g. an E.164 phone number).
+    fn service_id(&self, py: Python) -> PyResult<Option<PyObject>> {
+        match libsignal_core::ServiceId::parse_from_service_id_string(self.name()) {
+            Some(service_id) => Ok(Some(ServiceId::new_py(py, service_id)?)),
+            None => Ok(None),
+        }
+    }
+
     fn __str__(&self) -> PyResult<String> {
         Ok(String::from(format!(
             "{} {}",
@@ -38,9 +67,344 @@ impl ProtocolAddress {
             self.device_id()
         )))
     }
+
+    fn __richcmp__(&self, py: Python, other: PyRef<ProtocolAddress>, op: CompareOp) -> PyObject {
+        compare_eq(py, self.state == other.state, op)
+    }
+
+    fn __hash__(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.state.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn __reduce__(slf: &Bound<'_, Self>) -> (PyObject, (String, u32)) {
+        let address = slf.borrow();
+        (
+            slf.get_type().into_any().unbind(),
+            (address.name().to_string(), address.device_id()),
+        )
+    }
+}
+
+/// ServiceId is a Rust enum in the upstream crate. As with CiphertextMessage, it is
+/// mapped to a Python superclass, with Aci and Pni as subclasses.
+#[pyclass(subclass, module = "signal_protocol.address")]
+#[derive(Clone, Debug)]
+pub struct ServiceId {
+    pub inner: libsignal_core::ServiceId,
+}
+
+impl ServiceId {
+    /// Creates the Python object for `service_id` as an instance of Aci or Pni.
+    pub fn new_py(py: Python, service_id: libsignal_core::ServiceId) -> PyResult<PyObject> {
+        let base = ServiceId { inner: service_id };
+        Ok(match service_id {
+            libsignal_core::ServiceId::Aci(_) => Py::new(py, (Aci {}, base))?.into_any(),
+            libsignal_core::ServiceId::Pni(_) => Py::new(py, (Pni {}, base))?.into_any(),
+        })
+    }
+}
+
+#[pymethods]
+impl ServiceId {
+    /// Parses the string form: a bare UUID for an ACI, "PNI:"-prefixed for a PNI.
+    #[staticmethod]
+    fn parse_from_service_id_string(py: Python, input: &str) -> PyResult<PyObject> {
+        match libsignal_core::ServiceId::parse_from_service_id_string(input) {
+            Some(service_id) => ServiceId::new_py(py, service_id),
+            None => Err(InvalidArgumentException::new_err(format!(
+                "invalid service id string: {:?}",
+                input
+            ))),
+        }
+    }
+
+    /// Parses the binary form: 16 bytes for an ACI, 17 bytes with a type prefix for a PNI.
+    #[staticmethod]
+    fn parse_from_service_id_binary(py: Python, input: BytesLike) -> PyResult<PyObject> {
+        match libsignal_core::ServiceId::parse_from_service_id_binary(&input) {
+            Some(service_id) => ServiceId::new_py(py, service_id),
+            None => Err(InvalidArgumentException::new_err(format!(
+                "invalid service id binary of {} bytes",
+                input.len()
+            ))),
+        }
+    }
+
+    /// Parses the 17 byte fixed-width binary form, which always has a type prefix.
+    #[staticmethod]
+    fn parse_from_service_id_fixed_width_binary(py: Python, input: BytesLike) -> PyResult<PyObject> {
+        let bytes: &[u8; 17] = input[..].try_into().map_err(|_| {
+            InvalidArgumentException::new_err(format!(
+                "invalid fixed-width service id of {} bytes",
+                input.len()
+            ))
+        })?;
+        match libsignal_core::ServiceId::parse_from_service_id_fixed_width_binary(bytes) {
+            Some(service_id) => ServiceId::new_py(py, service_id),
+            None => Err(InvalidArgumentException::new_err(
+                "invalid fixed-width service id",
+            )),
+        }
+    }
+
+    /// "ACI" or "PNI".
+    fn kind(&self) -> &'static str {
+        match self.inner {
+            libsignal_core::ServiceId::Aci(_) => "ACI",
+            libsignal_core::ServiceId::Pni(_) => "PNI",
+        }
+    }
+
+    fn uuid(&self, py: Python) -> PyResult<PyObject> {
+        let uuid_class = py.import("uuid")?.getattr("UUID")?;
+        Ok(uuid_class
+            .call1((self.inner.raw_uuid().to_string(),))?
+            .unbind())
+    }
+
+    fn service_id_string(&self) -> String {
+        self.inner.service_id_string()
+    }
+
+    fn service_id_binary(&self, py: Python) -> PyObject {
+        PyBytes::new(py, &self.inner.service_id_binary()).into()
+    }
+
+    fn service_id_fixed_width_binary(&self, py: Python) -> PyObject {
+        PyBytes::new(py, &self.inner.service_id_fixed_width_binary()).into()
+    }
+
+    fn __str__(&self) -> String {
+        self.inner.service_id_string()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("{}({})", self.kind(), self.inner.service_id_string())
+    }
+
+    fn __richcmp__(&self, py: Python, other: PyRef<ServiceId>, op: CompareOp) -> PyObject {
+        compare_eq(py, self.inner == other.inner, op)
+    }
+
+    fn __hash__(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.inner.service_id_fixed_width_binary().hash(&mut hasher);
+        hasher.finish()
+    }
+
+    // Instances are always an Aci or a Pni, whose constructors take the UUID string.
+    fn __reduce__(slf: &Bound<'_, Self>) -> (PyObject, (String,)) {
+        (
+            slf.get_type().into_any().unbind(),
+            (slf.borrow().inner.raw_uuid().to_string(),),
+        )
+    }
+}
+
+/// Result of `__richcmp__` for types that only support equality: ordering
+/// comparisons return NotImplemented, so Python raises TypeError.
+fn compare_eq(py: Python, equal: bool, op: CompareOp) -> PyObject {
+    match op {
+        CompareOp::Eq => PyBool::new(py, equal).to_owned().into_any().unbind(),
+        CompareOp::Ne => PyBool::new(py, !equal).to_owned().into_any().unbind(),
+        _ => py.NotImplemented(),
+    }
+}
+
+/// Account identifier. Accepts uuid.UUID, 16 bytes or a UUID string.
+#[pyclass(extends=ServiceId, module = "signal_protocol.address")]
+pub struct Aci {}
+
+#[pymethods]
+impl Aci {
+    #[new]
+    fn new(uuid: &Bound<'_, PyAny>) -> PyResult<(Self, ServiceId)> {
+        let aci = libsignal_core::Aci::from(uuid_from_py(uuid, "ACI")?);
+        Ok((Aci {}, ServiceId { inner: aci.into() }))
+    }
+}
+
+/// Phone number identifier. Accepts uuid.UUID, 16 bytes or a UUID string.
+#[pyclass(extends=ServiceId, module = "signal_protocol.address")]
+pub struct Pni {}
+
+#[pymethods]
+impl Pni {
+    #[new]
+    fn new(uuid: &Bound<'_, PyAny>) -> PyResult<(Self, ServiceId)> {
+        let pni = libsignal_core::Pni::from(uuid_from_py(uuid, "PNI")?);
+        Ok((Pni {}, ServiceId { inner: pni.into() }))
+    }
+}
+
+/// A device id argument of DeviceId and DeviceRegistry, which must be in
+/// `1..=MAX_DEVICE_ID`. The other APIs take any u32 device id, as the upstream
+/// revision we depend on does not restrict them.
+#[derive(Clone, Copy, Debug)]
+pub struct DeviceIdArg(pub u32);
+
+fn check_device_id(value: i64) -> PyResult<u32> {
+    if (1..=i64::from(MAX_DEVICE_ID)).contains(&value) {
+        Ok(value as u32)
+    } else {
+        Err(InvalidArgumentException::new_err(format!(
+            "invalid device id: {} is not in 1..={}",
+            value, MAX_DEVICE_ID
+        )))
+    }
+}
+
+impl<'py> FromPyObject<'py> for DeviceIdArg {
+    fn extract_bound(ob: &Bound<'py, PyAny>) -> PyResult<Self> {
+        match ob.extract::<i64>() {
+            Ok(value) => Ok(DeviceIdArg(check_device_id(value)?)),
+            Err(_) => Err(InvalidArgumentException::new_err(format!(
+                "invalid device id: expected int, got {}",
+                ob.get_type().name()?
+            ))),
+        }
+    }
+}
+
+/// A validated device id. Behaves as an int: it compares and hashes equal to its value.
+#[pyclass(module = "signal_protocol.address")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct DeviceId {
+    pub value: u32,
+}
+
+impl From<libsignal_protocol::DeviceId> for DeviceId {
+    fn from(device_id: libsignal_protocol::DeviceId) -> Self {
+        DeviceId {
+            value: device_id.into(),
+        }
+    }
+}
+
+#[pymethods]
+impl DeviceId {
+    #[new]
+    fn new(value: DeviceIdArg) -> Self {
+        DeviceId { value: value.0 }
+    }
+
+    fn __int__(&self) -> u32 {
+        self.value
+    }
+
+    fn __index__(&self) -> u32 {
+        self.value
+    }
+
+    fn __richcmp__(&self, other: i64, op: CompareOp) -> bool {
+        op.matches(i64::from(self.value).cmp(&other))
+    }
+
+    // Same as hash(int(self)), so DeviceId and int are interchangeable as dict keys.
+    fn __hash__(&self) -> u64 {
+        u64::from(self.value)
+    }
+
+    fn __str__(&self) -> String {
+        self.value.to_string()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("DeviceId({})", self.value)
+    }
+
+    fn __reduce__(slf: &Bound<'_, Self>) -> (PyObject, (u32,)) {
+        (slf.get_type().into_any().unbind(), (slf.borrow().value,))
+    }
+}
+
+/// Tracks the known devices of each user name with their registration ids.
+///
+/// A device that shows up with a new registration id was re-registered (e.g. the
+/// app was reinstalled): existing sessions with it are stale and must be reset.
+#[pyclass(frozen, module = "signal_protocol.address")]
+#[derive(Debug, Default)]
+pub struct DeviceRegistry {
+    devices: Mutex<HashMap<String, BTreeMap<u32, u32>>>,
+}
+
+#[pymethods]
+impl DeviceRegistry {
+    #[new]
+    fn new() -> Self {
+        DeviceRegistry::default()
+    }
+
+    /// Records the registration id of the device at `address`. Returns True if the
+    /// device was known with a different registration id.
+    ///
+    /// Raises InvalidArgumentException if the device id of `address` is not in
+    /// `1..=MAX_DEVICE_ID`.
+    fn update(&self, address: &ProtocolAddress, registration_id: u32) -> PyResult<bool> {
+        let device_id = check_device_id(i64::from(address.device_id()))?;
+        let previous = lock(&self.devices)
+            .entry(address.name().to_string())
+            .or_default()
+            .insert(device_id, registration_id);
+        Ok(matches!(previous, Some(previous) if previous != registration_id))
+    }
+
+    /// As update(), with the registration id captured from the current session
+    /// with `address`.
+    fn update_from_session(
+        &self,
+        address: &ProtocolAddress,
+        session: &SessionRecord,
+    ) -> PyResult<bool> {
+        let registration_id = session
+            .state()
+            .remote_registration_id()
+            .map_err(SignalProtocolError::new_err)?;
+        self.update(address, registration_id)
+    }
+
+    fn registration_id(&self, address: &ProtocolAddress) -> Option<u32> {
+        lock(&self.devices)
+            .get(address.name())
+            .and_then(|devices| devices.get(&address.device_id()))
+            .copied()
+    }
+
+    /// The known devices of `name`, in ascending order.
+    fn devices(&self, name: &str) -> Vec<DeviceId> {
+        lock(&self.devices).get(name).map_or_else(Vec::new, |devices| {
+            devices.keys().map(|&value| DeviceId { value }).collect()
+        })
+    }
+
+    /// Forgets the device at `address`. Returns False if it was not known.
+    fn remove_device(&self, address: &ProtocolAddress) -> bool {
+        let mut all_devices = lock(&self.devices);
+        let devices = match all_devices.get_mut(address.name()) {
+            Some(devices) => devices,
+            None => return false,
+        };
+        let removed = devices.remove(&address.device_id()).is_some();
+        if devices.is_empty() {
+            all_devices.remove(address.name());
+        }
+        removed
+    }
+
+    fn __contains__(&self, address: &ProtocolAddress) -> bool {
+        self.registration_id(address).is_some()
+    }
 }
 
 pub fn init_submodule(module: &Bound<'_, PyModule>) -> PyResult<()> {
     module.add_class::<ProtocolAddress>()?;
+    module.add_class::<DeviceId>()?;
+    module.add_class::<DeviceRegistry>()?;
+    module.add("MAX_DEVICE_ID", MAX_DEVICE_ID)?;
+    module.add_class::<ServiceId>()?;
+    module.add_class::<Aci>()?;
+    module.add_class::<Pni>()?;
     Ok(())
 }
@@ -0,0 +1,623 @@
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use hmac::{Hmac, Mac};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use pyo3::wrap_pyfunction;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use zeroize::Zeroize;
+
+use crate::buffer::BytesLike;
+use crate::error::{InvalidArgumentException, SignalProtocolError};
+
+type Aes256CbcEnc = cbc::Encryptor<aes::Aes256>;
+type Aes256CbcDec = cbc::Decryptor<aes::Aes256>;
+type HmacSha256 = Hmac<Sha256>;
+
+/// An attachment key is an AES-256 key followed by an HMAC-SHA256 key.
+pub const KEY_LENGTH: usize = 64;
+const CIPHER_KEY_LENGTH: usize = 32;
+const IV_LENGTH: usize = 16;
+const BLOCK_LENGTH: usize = 16;
+const MAC_LENGTH: usize = 32;
+const DIGEST_LENGTH: usize = 32;
+
+const BUFFER_SIZE: usize = 64 * 1024;
+const MINIMUM_PADDED_SIZE: u64 = 541;
+
+// Chunk sizes of the incremental MAC, chosen such that the digests of an
+// attachment take about 8 KiB.
+const MINIMUM_CHUNK_SIZE: u64 = 64 * 1024;
+const MAXIMUM_CHUNK_SIZE: u64 = 2 * 1024 * 1024;
+const TARGET_CHUNK_COUNT: u64 = 8 * 1024 / DIGEST_LENGTH as u64;
+
+fn attachment_error(message: &str) -> PyErr {
+    SignalProtocolError::err_from_str(format!("invalid attachment: {}", message))
+}
+
+fn split_key(key: &[u8]) -> PyResult<(&[u8], &[u8])> {
+    if key.len() != KEY_LENGTH {
+        return Err(InvalidArgumentException::new_err(format!(
+            "attachment key must be {} bytes, got {}",
+            KEY_LENGTH,
+            key.len()
+        )));
+    }
+    Ok(key.split_at(CIPHER_KEY_LENGTH))
+}
+
+fn new_mac(mac_key: &[u8]) -> HmacSha256 {
+    HmacSha256::new_from_slice(mac_key).expect("HMAC accepts keys of any length")
+}
+
+// Reads up to `size` bytes, fewer only at the end of the stream.
+fn read_full(reader: &Bound<'_, PyAny>, size: usize) -> PyResult<Vec<u8>> {
+    let mut data = Vec::new();
+    while data.len() < size {
+        let chunk = reader
+            .call_method1("read", (size - data.len(),))?
+            .extract::<BytesLike>()?;
+        if chunk.is_empty() {
+            break;
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+fn write(writer: &Bound<'_, PyAny>, data: &[u8]) -> PyResult<()> {
+    if !data.is_empty() {
+        writer.call_method1("write", (PyBytes::new(writer.py(), data),))?;
+    }
+    Ok(())
+}
+
+/// The plaintext size of an attachment after padding. Signal clients pad
+/// attachments with zeros to one of these buckets, so that the size of the
+/// ciphertext leaks little about the content.
+#[pyfunction]
+pub fn padded_size(size: u64) -> u64 {
+    let bucket = 1.05f64.powf(((size.max(1) as f64).ln() / 1.05f64.ln()).ceil());
+    (bucket.floor() as u64).max(MINIMUM_PADDED_SIZE)
+}
+
+/// The size of an encrypted attachment, IV, ciphertext and MAC, for a
+/// plaintext of `size` bytes after padding.
+#[pyfunction]
+pub fn encrypted_size(size: u64) -> u64 {
+    let block_length = BLOCK_LENGTH as u64;
+    IV_LENGTH as u64 + (size / block_length + 1) * block_length + MAC_LENGTH as u64
+}
+
+/// The chunk size of the incremental MAC of an encrypted attachment of
+/// `encrypted_size` bytes.
+#[pyfunction]
+pub fn chunk_size(encrypted_size: u64) -> u64 {
+    if encrypted_size < TARGET_CHUNK_COUNT * MINIMUM_CHUNK_SIZE {
+        MINIMUM_CHUNK_SIZE
+    } else if encrypted_size < TARGET_CHUNK_COUNT * MAXIMUM_CHUNK_SIZE {
+        (encrypted_size + TARGET_CHUNK_COUNT - 1) / TARGET_CHUNK_COUNT
+    } else {
+        MAXIMUM_CHUNK_SIZE
+    }
+}
+
+/// Generates a random attachment key.
+#[pyfunction]
+pub fn generate_key(py: Python) -> Py<PyBytes> {
+    let mut key = [0u8; KEY_LENGTH];
+    OsRng.fill_bytes(&mut key);
+    let result = PyBytes::new(py, &key).unbind();
+    key.zeroize();
+    result
+}
+
+// An HMAC-SHA256 over the encrypted attachment that is also output at the end
+// of every chunk, so a download can be verified chunk by chunk. The digest of
+// a chunk covers all data up to its end, and the digest of all data follows
+// the last chunk. Like the attachment MAC, it is keyed with the second half of
+// the attachment key as is: the clients do not derive a separate key for it.
+struct IncrementalMac {
+    mac: HmacSha256,
+    chunk_size: usize,
+    unused_length: usize,
+    digests: Vec<u8>,
+}
+
+impl IncrementalMac {
+    fn new(mac_key: &[u8], chunk_size: usize) -> Self {
+        IncrementalMac {
+            mac: new_mac(mac_key),
+            chunk_size,
+            unused_length: chunk_size,
+            digests: Vec::new(),
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let length = self.unused_length.min(data.len());
+            self.mac.update(&data[..length]);
+            data = &data[length..];
+            self.unused_length -= length;
+            if self.unused_length == 0 {
+                self.unused_length = self.chunk_size;
+                let digest = self.mac.clone().finalize().into_bytes();
+                self.digests.extend_from_slice(&digest);
+            }
+        }
+    }
+
+    fn finalize(mut self) -> Vec<u8> {
+        let digest = self.mac.finalize().into_bytes();
+        self.digests.extend_from_slice(&digest);
+        self.digests
+    }
+}
+
+/// The result of encrypt_attachment: what an attachment pointer needs to
+/// download and decrypt the attachment.
+#[pyclass(frozen)]
+pub struct EncryptedAttachment {
+    key: Vec<u8>,
+    digest: Vec<u8>,
+    size: u64,
+    encrypted_size: u64,
+    incremental_mac: Option<Vec<u8>>,
+    chunk_size: Option<u64>,
+}
+
+impl Drop for EncryptedAttachment {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+#[pymethods]
+impl EncryptedAttachment {
+    #[getter]
+    fn key<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.key)
+    }
+
+    /// SHA-256 of the encrypted attachment.
+    #[getter]
+    fn digest<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.digest)
+    }
+
+    /// The plaintext size, without padding.
+    #[getter]
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    #[getter]
+    fn encrypted_size(&self) -> u64 {
+        self.encrypted_size
+    }
+
+    /// The chunk digests, if requested.
+    #[getter]
+    fn incremental_mac<'py>(&self, py: Python<'py>) -> Option<Bound<'py, PyBytes>> {
+        self.incremental_mac
+            .as_ref()
+            .map(|digests| PyBytes::new(py, digests))
+    }
+
+    #[getter]
+    fn chunk_size(&self) -> Option<u64> {
+        self.chunk_size
+    }
+}
+
+// Writes IV || ciphertext || MAC, keeping the MACs and digest of the output.
+struct Encryptor<'a, 'py> {
+    writer: &'a Bound<'py, PyAny>,
+    cipher: Aes256CbcEnc,
+    pending: Vec<u8>,
+    mac: HmacSha256,
+    digest: Sha256,
+    incremental_mac: Option<IncrementalMac>,
+    plaintext_size: u64,
+    encrypted_size: u64,
+}
+
+impl<'a, 'py> Encryptor<'a, 'py> {
+    fn emit(&mut self, data: &[u8], authenticated: bool) -> PyResult<()> {
+        if authenticated {
+            self.mac.update(data);
+        }
+        self.digest.update(data);
+        if let Some(incremental_mac) = &mut self.incremental_mac {
+            incremental_mac.update(data);
+        }
+        self.encrypted_size += data.len() as u64;
+        write(self.writer, data)
+    }
+
+    fn encrypt_pending(&mut self) -> PyResult<()> {
+        let length = self.pending.len() - self.pending.len() % BLOCK_LENGTH;
+        let mut blocks: Vec<u8> = self.pending.drain(..length).collect();
+        for block in blocks.chunks_exact_mut(BLOCK_LENGTH) {
+            self.cipher
+                .encrypt_block_mut(GenericArray::from_mut_slice(block));
+        }
+        self.emit(&blocks, true)
+    }
+
+    fn update(&mut self, plaintext: &[u8]) -> PyResult<()> {
+        self.plaintext_size += plaintext.len() as u64;
+        self.pending.extend_from_slice(plaintext);
+        self.encrypt_pending()
+    }
+
+    fn pad(&mut self, size: u64) -> PyResult<()> {
+        let mut remaining = size - self.plaintext_size;
+        let zeros = vec![0u8; BUFFER_SIZE];
+        while remaining > 0 {
+            let length = remaining.min(BUFFER_SIZE as u64) as usize;
+            self.pending.extend_from_slice(&zeros[..length]);
+            self.encrypt_pending()?;
+            remaining -= length as u64;
+        }
+        Ok(())
+    }
+
+    // Applies PKCS#7 padding and appends the MAC.
+    fn finish(mut self) -> PyResult<(Vec<u8>, Option<Vec<u8>>, u64)> {
+        let padding = BLOCK_LENGTH - self.pending.len();
+        self.pending.resize(BLOCK_LENGTH, padding as u8);
+        self.encrypt_pending()?;
+        let mac = self.mac.clone().finalize().into_bytes();
+        self.emit(&mac, false)?;
+        Ok((
+            self.digest.finalize().to_vec(),
+            self.incremental_mac.map(IncrementalMac::finalize),
+            self.encrypted_size,
+        ))
+    }
+}
+
+/// Encrypts an attachment from the binary file object `reader` to `writer`.
+///
+/// The attachment is read in chunks, padded to `padded_size` unless `pad` is
+/// False, and written as IV || AES-256-CBC ciphertext || HMAC-SHA256. A new
+/// key is generated unless `key` is given.
+///
+/// The incremental MAC depends on the total size, so `incremental_mac=True`
+/// requires the plaintext `size`. If given, `size` must match the data read.
+#[pyfunction]
+#[pyo3(signature = (reader, writer, key=None, size=None, pad=true, incremental_mac=false))]
+pub fn encrypt_attachment(
+    reader: &Bound<'_, PyAny>,
+    writer: &Bound<'_, PyAny>,
+    key: Option<BytesLike>,
+    size: Option<u64>,
+    pad: bool,
+    incremental_mac: bool,
+) -> PyResult<EncryptedAttachment> {
+    let key = match key {
+        Some(key) => key.to_vec(),
+        None => {
+            let mut key = vec![0u8; KEY_LENGTH];
+            OsRng.fill_bytes(&mut key);
+            key
+        }
+    };
+    let (cipher_key, mac_key) = split_key(&key)?;
+
+    let chunk_size = match (incremental_mac, size) {
+        (false, _) => None,
+        (true, Some(size)) => {
+            let padded = if pad { padded_size(size) } else { size };
+            Some(chunk_size(encrypted_size(padded)))
+        }
+        (true, None) => {
+            return Err(InvalidArgumentException::new_err(
+                "the incremental MAC requires the attachment size",
+            ))
+        }
+    };
+
+    let mut iv = [0u8; IV_LENGTH];
+    OsRng.fill_bytes(&mut iv);
+    let mut encryptor = Encryptor {
+        writer,
+        cipher: Aes256CbcEnc::new_from_slices(cipher_key, &iv)
+            .expect("key and IV have valid lengths"),
+        pending: Vec::with_capacity(BUFFER_SIZE + BLOCK_LENGTH),
+        mac: new_mac(mac_key),
+        digest: Sha256::new(),
+        incremental_mac: chunk_size
+            .map(|chunk_size| IncrementalMac::new(mac_key, chunk_size as usize)),
+        plaintext_size: 0,
+        encrypted_size: 0,
+    };
+    encryptor.emit(&iv, true)?;
+
+    loop {
+        let plaintext = read_full(reader, BUFFER_SIZE)?;
+        if plaintext.is_empty() {
+            break;
+        }
+        encryptor.update(&plaintext)?;
+    }
+    let plaintext_size = encryptor.plaintext_size;
+    if let Some(size) = size {
+        if size != plaintext_size {
+            return Err(InvalidArgumentException::new_err(format!(
+                "attachment size is {}, but {} bytes were read",
+                size, plaintext_size
+            )));
+        }
+    }
+    if pad {
+        encryptor.pad(padded_size(plaintext_size))?;
+    }
+    let (digest, incremental_mac, encrypted_size) = encryptor.finish()?;
+
+    Ok(EncryptedAttachment {
+        key,
+        digest,
+        size: plaintext_size,
+        encrypted_size,
+        incremental_mac,
+        chunk_size,
+    })
+}
+
+// Splits IV || ciphertext || MAC as it is read, holding back the last
+// MAC_LENGTH bytes until the end of the stream.
+struct Splitter {
+    mac: HmacSha256,
+    iv: Vec<u8>,
+    tail: Vec<u8>,
+}
+
+impl Splitter {
+    fn new(mac_key: &[u8]) -> Self {
+        Splitter {
+            mac: new_mac(mac_key),
+            iv: Vec::with_capacity(IV_LENGTH),
+            tail: Vec::new(),
+        }
+    }
+
+    // Returns the ciphertext that is known not to be part of the MAC.
+    fn update(&mut self, mut data: &[u8]) -> Vec<u8> {
+        if self.iv.len() < IV_LENGTH {
+            let length = (IV_LENGTH - self.iv.len()).min(data.len());
+            self.iv.extend_from_slice(&data[..length]);
+            self.mac.update(&data[..length]);
+            data = &data[length..];
+        }
+        self.tail.extend_from_slice(data);
+        let length = self.tail.len().saturating_sub(MAC_LENGTH);
+        let ciphertext: Vec<u8> = self.tail.drain(..length).collect();
+        self.mac.update(&ciphertext);
+        ciphertext
+    }
+
+    fn verify(self) -> PyResult<()> {
+        if self.iv.len() < IV_LENGTH || self.tail.len() < MAC_LENGTH {
+            return Err(attachment_error("too short"));
+        }
+        self.mac
+            .verify_slice(&self.tail)
+            .map_err(|_| attachment_error("MAC mismatch"))
+    }
+}
+
+// Decrypts the ciphertext, holding back the last block to remove the PKCS#7
+// padding, and drops the bucket padding after `size` bytes.
+struct Decryptor<'a, 'py> {
+    writer: &'a Bound<'py, PyAny>,
+    cipher: Option<Aes256CbcDec>,
+    pending: Vec<u8>,
+    size: Option<u64>,
+    written: u64,
+}
+
+impl<'a, 'py> Decryptor<'a, 'py> {
+    fn output(&mut self, plaintext: &[u8]) -> PyResult<()> {
+        let length = match self.size {
+            Some(size) => (size - self.written).min(plaintext.len() as u64) as usize,
+            None => plaintext.len(),
+        };
+        self.written += length as u64;
+        write(self.writer, &plaintext[..length])
+    }
+
+    fn update(&mut self, cipher_key: &[u8], iv: &[u8], ciphertext: &[u8]) -> PyResult<()> {
+        self.pending.extend_from_slice(ciphertext);
+        // Keep at least one block, which may be the last.
+        let length = self.pending.len().saturating_sub(1) / BLOCK_LENGTH * BLOCK_LENGTH;
+        if length == 0 {
+            return Ok(());
+        }
+        let cipher = self.cipher.get_or_insert_with(|| {
+            Aes256CbcDec::new_from_slices(cipher_key, iv).expect("key and IV have valid lengths")
+        });
+        let mut blocks: Vec<u8> = self.pending.drain(..length).collect();
+        for block in blocks.chunks_exact_mut(BLOCK_LENGTH) {
+            cipher.decrypt_block_mut(GenericArray::from_mut_slice(block));
+        }
+        let result = self.output(&blocks);
+        blocks.zeroize();
+        result
+    }
+
+    fn finish(mut self, cipher_key: &[u8], iv: &[u8]) -> PyResult<u64> {
+        if self.pending.len() != BLOCK_LENGTH {
+            return Err(attachment_error(
+                "ciphertext is not a multiple of the block size",
+            ));
+        }
+        let cipher = self.cipher.get_or_insert_with(|| {
+            Aes256CbcDec::new_from_slices(cipher_key, iv).expect("key and IV have valid lengths")
+        });
+        let mut block = std::mem::take(&mut self.pending);
+        cipher.decrypt_block_mut(GenericArray::from_mut_slice(&mut block));
+        let padding = block[BLOCK_LENGTH - 1] as usize;
+        if padding == 0
+            || padding > BLOCK_LENGTH
+            || block[BLOCK_LENGTH - padding..]
+                .iter()
+                .any(|&byte| byte as usize != padding)
+        {
+            return Err(attachment_error("bad padding"));
+        }
+        let result = self.output(&block[..BLOCK_LENGTH - padding]);
+        block.zeroize();
+        result?;
+        if let Some(size) = self.size {
+            if self.written != size {
+                return Err(attachment_error("shorter than its size"));
+            }
+        }
+        Ok(self.written)
+    }
+}
+
+// Reads the whole attachment, checking its MAC and digest, and decrypting it
+// to `decryptor` if given. With an incremental MAC, each chunk is checked
+// before it is decrypted.
+fn read_attachment(
+    reader: &Bound<'_, PyAny>,
+    key: &[u8],
+    digest: &[u8],
+    incremental_mac: Option<(&[u8], usize)>,
+    mut decryptor: Option<Decryptor>,
+) -> PyResult<u64> {
+    let (cipher_key, mac_key) = split_key(key)?;
+    let mut splitter = Splitter::new(mac_key);
+    let mut hash = Sha256::new();
+    let mut validator =
+        incremental_mac.map(|(_, chunk_size)| IncrementalMac::new(mac_key, chunk_size));
+    let read_size = incremental_mac.map_or(BUFFER_SIZE, |(_, chunk_size)| chunk_size);
+
+    loop {
+        let data = read_full(reader, read_size)?;
+        let last = data.len() < read_size;
+        hash.update(&data);
+        if let (Some(validator), Some((expected, _))) = (&mut validator, incremental_mac) {
+            validator.update(&data);
+            let valid = if last {
+                validator.digests.len() + DIGEST_LENGTH == expected.len()
+                    && expected.starts_with(&validator.digests)
+                    && expected.ends_with(&validator.mac.clone().finalize().into_bytes())
+            } else {
+                expected.starts_with(&validator.digests)
+            };
+            if !valid {
+                return Err(attachment_error("incremental MAC mismatch"));
+            }
+        }
+        let ciphertext = splitter.update(&data);
+        if let Some(decryptor) = &mut decryptor {
+            decryptor.update(cipher_key, &splitter.iv, &ciphertext)?;
+        }
+        if last {
+            break;
+        }
+    }
+
+    let iv = splitter.iv.clone();
+    splitter.verify()?;
+    if hash.finalize().as_slice() != digest {
+        return Err(attachment_error("digest mismatch"));
+    }
+    match decryptor {
+        Some(decryptor) => decryptor.finish(cipher_key, &iv),
+        None => Ok(0),
+    }
+}
+
+/// Decrypts an attachment from the binary file object `reader` to `writer`,
+/// and returns the number of bytes written.
+///
+/// The MAC and the SHA-256 `digest` are verified before any plaintext is
+/// written. Without an incremental MAC, this reads the attachment twice, so
+/// `reader` must be seekable. With `incremental_mac` and its `chunk_size`, the
+/// attachment is read once and each chunk is verified before it is decrypted.
+/// If verification fails after the first chunk, the plaintext written so far
+/// is authentic but incomplete.
+///
+/// `size` is the plaintext size from the attachment pointer: the padding
+/// after it is dropped.
+#[pyfunction]
+#[pyo3(signature = (reader, writer, key, digest, size=None, incremental_mac=None, chunk_size=None))]
+pub fn decrypt_attachment(
+    reader: &Bound<'_, PyAny>,
+    writer: &Bound<'_, PyAny>,
+    key: BytesLike,
+    digest: BytesLike,
+    size: Option<u64>,
+    incremental_mac: Option<BytesLike>,
+    chunk_size: Option<usize>,
+) -> PyResult<u64> {
+    split_key(&key)?;
+    if digest.len() != DIGEST_LENGTH {
+        return Err(InvalidArgumentException::new_err(format!(
+            "attachment digest must be {} bytes, got {}",
+            DIGEST_LENGTH,
+            digest.len()
+        )));
+    }
+    let decryptor = Decryptor {
+        writer,
+        cipher: None,
+        pending: Vec::new(),
+        size,
+        written: 0,
+    };
+
+    match (&incremental_mac, chunk_size) {
+        (Some(incremental_mac), Some(chunk_size)) => {
+            if chunk_size == 0 || incremental_mac.len() % DIGEST_LENGTH != 0 {
+                return Err(InvalidArgumentException::new_err(
+                    "invalid incremental MAC or chunk size",
+                ));
+            }
+            // The chunk size comes from the sender: never buffer more than
+            // chunk_size() would have chosen for any attachment.
+            if chunk_size as u64 > MAXIMUM_CHUNK_SIZE {
+                return Err(InvalidArgumentException::new_err(format!(
+                    "chunk size must be at most {} bytes, got {}",
+                    MAXIMUM_CHUNK_SIZE, chunk_size
+                )));
+            }
+            read_attachment(
+                reader,
+                &key,
+                &digest,
+                Some((&incremental_mac[..], chunk_size)),
+                Some(decryptor),
+            )
+        }
+        (None, None) => {
+            let start = reader.call_method0("tell")?;
+            read_attachment(reader, &key, &digest, None, None)?;
+            reader.call_method1("seek", (start,))?;
+            read_attachment(reader, &key, &digest, None, Some(decryptor))
+        }
+        _ => Err(InvalidArgumentException::new_err(
+            "incremental_mac and chunk_size must be given together",
+        )),
+    }
+}
+
+pub fn init_submodule(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add("KEY_LENGTH", KEY_LENGTH)?;
+    module.add_class::<EncryptedAttachment>()?;
+    module.add_function(wrap_pyfunction!(generate_key, module)?)?;
+    module.add_function(wrap_pyfunction!(padded_size, module)?)?;
+    module.add_function(wrap_pyfunction!(encrypted_size, module)?)?;
+    module.add_function(wrap_pyfunction!(chunk_size, module)?)?;
+    module.add_function(wrap_pyfunction!(encrypt_attachment, module)?)?;
+    module.add_function(wrap_pyfunction!(decrypt_attachment, module)?)?;
+    Ok(())
+}
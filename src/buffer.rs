@@ -0,0 +1,111 @@
+use std::ops::Deref;
+
+use pyo3::buffer::PyBuffer;
+use pyo3::exceptions::PyBufferError;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use crate::error::InvalidArgumentException;
+
+fn contiguous_buffer(ob: &Bound<'_, PyAny>) -> PyResult<PyBuffer<u8>> {
+    let buffer = PyBuffer::<u8>::get(ob)?;
+    if !buffer.is_c_contiguous() {
+        return Err(PyBufferError::new_err("buffer is not contiguous"));
+    }
+    Ok(buffer)
+}
+
+/// A byte string argument: any contiguous buffer of bytes (bytes, bytearray,
+/// memoryview, a numpy uint8 array...).
+///
+/// Exact `bytes` objects are immutable and read in place. Other buffers are
+/// copied, including bytes subclasses, which can export a mutable buffer from
+/// `__buffer__`:
+/// the GIL may be released while the bytes are read, and without it (or on
+/// free-threaded builds) another thread could write to them meanwhile. Even a
+/// read-only buffer may be a view on a mutable one, e.g. `memoryview.toreadonly()`.
+pub enum BytesLike {
+    Shared(PyBuffer<u8>),
+    Copied(Vec<u8>),
+}
+
+impl<'py> FromPyObject<'py> for BytesLike {
+    fn extract_bound(ob: &Bound<'py, PyAny>) -> PyResult<Self> {
+        let buffer = contiguous_buffer(ob)?;
+        if ob.is_exact_instance_of::<PyBytes>() {
+            Ok(BytesLike::Shared(buffer))
+        } else {
+            Ok(BytesLike::Copied(buffer.to_vec(ob.py())?))
+        }
+    }
+}
+
+impl Deref for BytesLike {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            BytesLike::Shared(buffer) if buffer.len_bytes() > 0 => {
+                // SAFETY: the buffer is contiguous, stays exported as long as
+                // self, and belongs to an immutable bytes object.
+                unsafe {
+                    std::slice::from_raw_parts(buffer.buf_ptr() as *const u8, buffer.len_bytes())
+                }
+            }
+            BytesLike::Shared(_) => &[],
+            BytesLike::Copied(data) => data,
+        }
+    }
+}
+
+/// A writable contiguous buffer for the `out=` arguments, e.g. a bytearray.
+///
+/// The result is written in place, which is only sound if no other thread
+/// reads or writes the buffer until the call returns: on free-threaded builds
+/// the GIL does not keep them out. Callers sharing a buffer between threads
+/// must synchronize themselves, or leave out `out`.
+pub struct WritableBuffer(PyBuffer<u8>);
+
+impl<'py> FromPyObject<'py> for WritableBuffer {
+    fn extract_bound(ob: &Bound<'py, PyAny>) -> PyResult<Self> {
+        let buffer = contiguous_buffer(ob)?;
+        if buffer.readonly() {
+            return Err(PyBufferError::new_err("buffer is read-only"));
+        }
+        Ok(WritableBuffer(buffer))
+    }
+}
+
+impl WritableBuffer {
+    /// Copies `data` to the start of the buffer and returns its length.
+    pub fn write(&self, data: &[u8]) -> PyResult<usize> {
+        if data.len() > self.0.len_bytes() {
+            return Err(InvalidArgumentException::new_err(format!(
+                "output buffer of {} bytes is too small for {} bytes",
+                self.0.len_bytes(),
+                data.len()
+            )));
+        }
+        if !data.is_empty() {
+            // SAFETY: the buffer is contiguous, large enough and writable, and
+            // the caller does not access it concurrently (see WritableBuffer).
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    data.as_ptr(),
+                    self.0.buf_ptr() as *mut u8,
+                    data.len(),
+                )
+            };
+        }
+        Ok(data.len())
+    }
+}
+
+/// Returns `data` as bytes, or writes it to `out` and returns the number of
+/// bytes written.
+pub fn bytes_or_write(py: Python, data: &[u8], out: Option<WritableBuffer>) -> PyResult<PyObject> {
+    match out {
+        Some(out) => Ok(out.write(data)?.into_pyobject(py)?.into_any().unbind()),
+        None => Ok(PyBytes::new(py, data).into_any().unbind()),
+    }
+}
@@ -0,0 +1,15 @@
+//! Limits hardcoded in the upstream crate's `consts.rs`, which are not exported.
+//! These must be kept in sync when bumping the upstream revision.
+
+/// How far ahead of a chain's current index a message counter may be.
+pub const MAX_FORWARD_JUMPS: u32 = 25_000;
+/// Skipped message keys kept per receiver chain; the oldest are dropped first.
+pub const MAX_MESSAGE_KEYS: usize = 2000;
+/// Receiver chains kept per session; the oldest are dropped first.
+pub const MAX_RECEIVER_CHAINS: usize = 5;
+/// Archived (previous) session states kept per session record.
+pub const ARCHIVED_STATES_MAX_LENGTH: usize = 40;
+/// Sender key states kept per sender key record; the oldest are dropped first.
+pub const MAX_SENDER_KEY_STATES: usize = 5;
+/// Device ids are in `1..=MAX_DEVICE_ID`, as enforced by newer upstream revisions.
+pub const MAX_DEVICE_ID: u32 = 127;
@@ -0,0 +1,525 @@
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use pyo3::wrap_pyfunction;
+
+use prost::Message;
+use rand::rngs::OsRng;
+use rand::{Rng, RngCore};
+
+use crate::address::ServiceId;
+use crate::buffer::BytesLike;
+use crate::error::{InvalidArgumentException, SignalProtocolError};
+use crate::proto;
+
+/// Plaintexts are padded to a multiple of this size (minus one byte) before
+/// encryption, so that the ciphertext length only leaks a rough message size.
+pub const PADDING_BLOCK_SIZE: usize = 160;
+const PADDING_TERMINATOR: u8 = 0x80;
+const MAX_NULL_MESSAGE_PADDING: usize = 512;
+
+fn padded_length(length: usize) -> usize {
+    // Matches the official clients: the terminator and one more byte are
+    // counted before rounding up, and the result is one byte short of a block.
+    let parts = (length + 2 + PADDING_BLOCK_SIZE - 1) / PADDING_BLOCK_SIZE;
+    parts * PADDING_BLOCK_SIZE - 1
+}
+
+/// Pads a serialized Content the way the official clients do before
+/// message_encrypt: a 0x80 terminator, then zeros.
+#[pyfunction]
+pub fn pad<'py>(py: Python<'py>, plaintext: BytesLike) -> Bound<'py, PyBytes> {
+    let mut padded = vec![0u8; padded_length(plaintext.len())];
+    padded[..plaintext.len()].copy_from_slice(&plaintext);
+    padded[plaintext.len()] = PADDING_TERMINATOR;
+    PyBytes::new(py, &padded)
+}
+
+/// Removes the padding added by `pad`. Raises SignalProtocolException if the
+/// plaintext does not end with a terminator followed by zeros.
+#[pyfunction]
+pub fn unpad<'py>(py: Python<'py>, padded: BytesLike) -> PyResult<Bound<'py, PyBytes>> {
+    match padded.iter().rposition(|&byte| byte != 0) {
+        Some(end) if padded[end] == PADDING_TERMINATOR => Ok(PyBytes::new(py, &padded[..end])),
+        _ => Err(SignalProtocolError::err_from_str(
+            "invalid content padding".to_string(),
+        )),
+    }
+}
+
+fn decode_error(what: &str, err: prost::DecodeError) -> PyErr {
+    SignalProtocolError::err_from_str(format!("failed to decode {}: {}", what, err))
+}
+
+fn parse_service_id(py: Python, service_id: &Option<String>) -> PyResult<Option<PyObject>> {
+    match service_id {
+        Some(service_id) => {
+            match libsignal_core::ServiceId::parse_from_service_id_string(service_id) {
+                Some(service_id) => Ok(Some(ServiceId::new_py(py, service_id)?)),
+                None => Err(SignalProtocolError::err_from_str(format!(
+                    "invalid service id in envelope: {:?}",
+                    service_id
+                ))),
+            }
+        }
+        None => Ok(None),
+    }
+}
+
+/// Envelope.Type of the Signal service protocol.
+#[pyclass(eq, eq_int)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EnvelopeType {
+    Unknown = 0,
+    Ciphertext = 1,
+    KeyExchange = 2,
+    PrekeyBundle = 3,
+    Receipt = 5,
+    UnidentifiedSender = 6,
+    PlaintextContent = 8,
+}
+
+impl EnvelopeType {
+    fn from_i32(value: i32) -> Self {
+        match value {
+            1 => EnvelopeType::Ciphertext,
+            2 => EnvelopeType::KeyExchange,
+            3 => EnvelopeType::PrekeyBundle,
+            5 => EnvelopeType::Receipt,
+            6 => EnvelopeType::UnidentifiedSender,
+            8 => EnvelopeType::PlaintextContent,
+            _ => EnvelopeType::Unknown,
+        }
+    }
+}
+
+#[pymethods]
+impl EnvelopeType {
+    /// The envelope type for a CiphertextMessage.message_type(). Sender key
+    /// messages are only sent sealed, as UnidentifiedSender.
+    #[staticmethod]
+    fn from_message_type(message_type: u8) -> PyResult<Self> {
+        match message_type {
+            2 => Ok(EnvelopeType::Ciphertext),
+            3 => Ok(EnvelopeType::PrekeyBundle),
+            7 => Ok(EnvelopeType::UnidentifiedSender),
+            8 => Ok(EnvelopeType::PlaintextContent),
+            _ => Err(InvalidArgumentException::new_err(format!(
+                "no envelope type for message type {}",
+                message_type
+            ))),
+        }
+    }
+}
+
+/// The wrapper of an encrypted message as delivered by the service.
+#[pyclass(frozen)]
+#[derive(Clone)]
+pub struct Envelope {
+    pub inner: proto::Envelope,
+}
+
+#[pymethods]
+impl Envelope {
+    #[new]
+    #[pyo3(signature = (
+        r#type,
+        timestamp,
+        content=None,
+        source_service_id=None,
+        source_device=None,
+        destination_service_id=None,
+        server_guid=None,
+        server_timestamp=None,
+        urgent=true
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        r#type: EnvelopeType,
+        timestamp: u64,
+        content: Option<BytesLike>,
+        source_service_id: Option<PyRef<ServiceId>>,
+        source_device: Option<u32>,
+        destination_service_id: Option<PyRef<ServiceId>>,
+        server_guid: Option<String>,
+        server_timestamp: Option<u64>,
+        urgent: bool,
+    ) -> Self {
+        Envelope {
+            inner: proto::Envelope {
+                r#type: Some(r#type as i32),
+                timestamp: Some(timestamp),
+                source_device,
+                content: content.map(|content| content.to_vec()),
+                server_guid,
+                server_timestamp,
+                source_service_id: source_service_id
+                    .map(|service_id| service_id.inner.service_id_string()),
+                destination_service_id: destination_service_id
+                    .map(|service_id| service_id.inner.service_id_string()),
+                urgent: Some(urgent),
+            },
+        }
+    }
+
+    #[staticmethod]
+    fn deserialize(data: BytesLike) -> PyResult<Self> {
+        let inner =
+            proto::Envelope::decode(&data[..]).map_err(|err| decode_error("envelope", err))?;
+        Ok(Envelope { inner })
+    }
+
+    fn serialize<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.inner.encode_to_vec())
+    }
+
+    /// Unknown types are returned as EnvelopeType.Unknown.
+    #[getter]
+    fn r#type(&self) -> EnvelopeType {
+        EnvelopeType::from_i32(self.inner.r#type.unwrap_or_default())
+    }
+
+    #[getter]
+    fn timestamp(&self) -> u64 {
+        self.inner.timestamp.unwrap_or_default()
+    }
+
+    /// The encrypted content, to be decrypted according to `type`.
+    #[getter]
+    fn content<'py>(&self, py: Python<'py>) -> Option<Bound<'py, PyBytes>> {
+        self.inner
+            .content
+            .as_ref()
+            .map(|content| PyBytes::new(py, content))
+    }
+
+    /// An Aci or Pni, or None for sealed sender envelopes.
+    #[getter]
+    fn source_service_id(&self, py: Python) -> PyResult<Option<PyObject>> {
+        parse_service_id(py, &self.inner.source_service_id)
+    }
+
+    #[getter]
+    fn source_device(&self) -> Option<u32> {
+        self.inner.source_device
+    }
+
+    #[getter]
+    fn destination_service_id(&self, py: Python) -> PyResult<Option<PyObject>> {
+        parse_service_id(py, &self.inner.destination_service_id)
+    }
+
+    #[getter]
+    fn server_guid(&self) -> Option<String> {
+        self.inner.server_guid.clone()
+    }
+
+    #[getter]
+    fn server_timestamp(&self) -> Option<u64> {
+        self.inner.server_timestamp
+    }
+
+    #[getter]
+    fn urgent(&self) -> bool {
+        self.inner.urgent.unwrap_or(true)
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "Envelope(type={:?}, timestamp={}, source_service_id={:?}, source_device={:?})",
+            self.r#type(),
+            self.timestamp(),
+            self.inner.source_service_id,
+            self.inner.source_device
+        )
+    }
+}
+
+/// A user-visible message. Only the body, timestamp, expiration timer, profile
+/// key and flags are covered.
+#[pyclass(frozen)]
+#[derive(Clone)]
+pub struct DataMessage {
+    pub inner: proto::content::DataMessage,
+}
+
+#[pymethods]
+impl DataMessage {
+    /// `timestamp` must match the timestamp of the envelope.
+    #[new]
+    #[pyo3(signature = (timestamp, body=None, expire_timer=None, profile_key=None, flags=None))]
+    fn new(
+        timestamp: u64,
+        body: Option<String>,
+        expire_timer: Option<u32>,
+        profile_key: Option<BytesLike>,
+        flags: Option<u32>,
+    ) -> Self {
+        DataMessage {
+            inner: proto::content::DataMessage {
+                body,
+                flags,
+                expire_timer,
+                profile_key: profile_key.map(|key| key.to_vec()),
+                timestamp: Some(timestamp),
+            },
+        }
+    }
+
+    #[getter]
+    fn timestamp(&self) -> u64 {
+        self.inner.timestamp.unwrap_or_default()
+    }
+
+    #[getter]
+    fn body(&self) -> Option<String> {
+        self.inner.body.clone()
+    }
+
+    #[getter]
+    fn expire_timer(&self) -> Option<u32> {
+        self.inner.expire_timer
+    }
+
+    #[getter]
+    fn profile_key<'py>(&self, py: Python<'py>) -> Option<Bound<'py, PyBytes>> {
+        self.inner
+            .profile_key
+            .as_ref()
+            .map(|key| PyBytes::new(py, key))
+    }
+
+    #[getter]
+    fn flags(&self) -> u32 {
+        self.inner.flags.unwrap_or_default()
+    }
+}
+
+/// ReceiptMessage.Type of the Signal service protocol.
+#[pyclass(eq, eq_int)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReceiptType {
+    Delivery = 0,
+    Read = 1,
+    Viewed = 2,
+}
+
+/// Acknowledges the messages sent at `timestamps`.
+#[pyclass(frozen)]
+#[derive(Clone)]
+pub struct ReceiptMessage {
+    pub inner: proto::content::ReceiptMessage,
+}
+
+#[pymethods]
+impl ReceiptMessage {
+    #[new]
+    fn new(r#type: ReceiptType, timestamps: Vec<u64>) -> Self {
+        ReceiptMessage {
+            inner: proto::content::ReceiptMessage {
+                r#type: Some(r#type as i32),
+                timestamp: timestamps,
+            },
+        }
+    }
+
+    #[getter]
+    fn r#type(&self) -> PyResult<ReceiptType> {
+        match self.inner.r#type.unwrap_or_default() {
+            0 => Ok(ReceiptType::Delivery),
+            1 => Ok(ReceiptType::Read),
+            2 => Ok(ReceiptType::Viewed),
+            value => Err(SignalProtocolError::err_from_str(format!(
+                "unknown receipt type {}",
+                value
+            ))),
+        }
+    }
+
+    #[getter]
+    fn timestamps(&self) -> Vec<u64> {
+        self.inner.timestamp.clone()
+    }
+}
+
+/// TypingMessage.Action of the Signal service protocol.
+#[pyclass(eq, eq_int)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TypingAction {
+    Started = 0,
+    Stopped = 1,
+}
+
+#[pyclass(frozen)]
+#[derive(Clone)]
+pub struct TypingMessage {
+    pub inner: proto::content::TypingMessage,
+}
+
+#[pymethods]
+impl TypingMessage {
+    #[new]
+    #[pyo3(signature = (action, timestamp, group_id=None))]
+    fn new(action: TypingAction, timestamp: u64, group_id: Option<BytesLike>) -> Self {
+        TypingMessage {
+            inner: proto::content::TypingMessage {
+                timestamp: Some(timestamp),
+                action: Some(action as i32),
+                group_id: group_id.map(|group_id| group_id.to_vec()),
+            },
+        }
+    }
+
+    #[getter]
+    fn action(&self) -> PyResult<TypingAction> {
+        match self.inner.action.unwrap_or_default() {
+            0 => Ok(TypingAction::Started),
+            1 => Ok(TypingAction::Stopped),
+            value => Err(SignalProtocolError::err_from_str(format!(
+                "unknown typing action {}",
+                value
+            ))),
+        }
+    }
+
+    #[getter]
+    fn timestamp(&self) -> u64 {
+        self.inner.timestamp.unwrap_or_default()
+    }
+
+    #[getter]
+    fn group_id<'py>(&self, py: Python<'py>) -> Option<Bound<'py, PyBytes>> {
+        self.inner
+            .group_id
+            .as_ref()
+            .map(|group_id| PyBytes::new(py, group_id))
+    }
+}
+
+/// The plaintext of an encrypted message. Serialize and `pad` it before
+/// encrypting; `unpad` and deserialize it after decrypting.
+///
+/// Only some of the message kinds are covered: parsing drops the others (sync,
+/// call, story and edit messages).
+#[pyclass(frozen)]
+#[derive(Clone)]
+pub struct Content {
+    pub inner: proto::Content,
+}
+
+#[pymethods]
+impl Content {
+    /// A `null_message` gets random padding, as sent by the official clients.
+    #[new]
+    #[pyo3(signature = (
+        data_message=None,
+        null_message=false,
+        receipt_message=None,
+        typing_message=None,
+        sender_key_distribution_message=None,
+        decryption_error_message=None
+    ))]
+    fn new(
+        data_message: Option<DataMessage>,
+        null_message: bool,
+        receipt_message: Option<ReceiptMessage>,
+        typing_message: Option<TypingMessage>,
+        sender_key_distribution_message: Option<BytesLike>,
+        decryption_error_message: Option<BytesLike>,
+    ) -> PyResult<Self> {
+        let null_message = if null_message {
+            let mut padding = vec![0u8; OsRng.gen_range(1..=MAX_NULL_MESSAGE_PADDING)];
+            OsRng.fill_bytes(&mut padding);
+            Some(proto::content::NullMessage {
+                padding: Some(padding),
+            })
+        } else {
+            None
+        };
+        let inner = proto::Content {
+            data_message: data_message.map(|message| message.inner),
+            null_message,
+            receipt_message: receipt_message.map(|message| message.inner),
+            typing_message: typing_message.map(|message| message.inner),
+            sender_key_distribution_message: sender_key_distribution_message
+                .map(|message| message.to_vec()),
+            decryption_error_message: decryption_error_message.map(|message| message.to_vec()),
+        };
+        if inner == proto::Content::default() {
+            return Err(InvalidArgumentException::new_err("content is empty"));
+        }
+        Ok(Content { inner })
+    }
+
+    #[staticmethod]
+    fn deserialize(data: BytesLike) -> PyResult<Self> {
+        let inner =
+            proto::Content::decode(&data[..]).map_err(|err| decode_error("content", err))?;
+        Ok(Content { inner })
+    }
+
+    fn serialize<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.inner.encode_to_vec())
+    }
+
+    #[getter]
+    fn data_message(&self) -> Option<DataMessage> {
+        self.inner
+            .data_message
+            .clone()
+            .map(|inner| DataMessage { inner })
+    }
+
+    #[getter]
+    fn null_message(&self) -> bool {
+        self.inner.null_message.is_some()
+    }
+
+    #[getter]
+    fn receipt_message(&self) -> Option<ReceiptMessage> {
+        self.inner
+            .receipt_message
+            .clone()
+            .map(|inner| ReceiptMessage { inner })
+    }
+
+    #[getter]
+    fn typing_message(&self) -> Option<TypingMessage> {
+        self.inner
+            .typing_message
+            .clone()
+            .map(|inner| TypingMessage { inner })
+    }
+
+    /// A serialized SenderKeyDistributionMessage.
+    #[getter]
+    fn sender_key_distribution_message<'py>(&self, py: Python<'py>) -> Option<Bound<'py, PyBytes>> {
+        self.inner
+            .sender_key_distribution_message
+            .as_ref()
+            .map(|message| PyBytes::new(py, message))
+    }
+
+    /// A serialized DecryptionErrorMessage.
+    #[getter]
+    fn decryption_error_message<'py>(&self, py: Python<'py>) -> Option<Bound<'py, PyBytes>> {
+        self.inner
+            .decryption_error_message
+            .as_ref()
+            .map(|message| PyBytes::new(py, message))
+    }
+}
+
+pub fn init_submodule(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add("PADDING_BLOCK_SIZE", PADDING_BLOCK_SIZE)?;
+    module.add_class::<EnvelopeType>()?;
+    module.add_class::<Envelope>()?;
+    module.add_class::<DataMessage>()?;
+    module.add_class::<ReceiptType>()?;
+    module.add_class::<ReceiptMessage>()?;
+    module.add_class::<TypingAction>()?;
+    module.add_class::<TypingMessage>()?;
+    module.add_class::<Content>()?;
+    module.add_function(wrap_pyfunction!(pad, module)?)?;
+    module.add_function(wrap_pyfunction!(unpad, module)?)?;
+    Ok(())
+}
@@ -4,9 +4,80 @@ use pyo3::prelude::*;
 use pyo3::types::PyBytes;
 use pyo3::wrap_pyfunction;
 
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use curve25519_dalek::scalar::clamp_integer;
 use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha512};
+use zeroize::Zeroizing;
 
-use crate::error::Result;
+use crate::buffer::BytesLike;
+use crate::error::{InvalidArgumentException, Result, SignalProtocolError};
+use crate::vxeddsa;
+
+// DER prefixes of an X25519 (OID 1.3.101.110) SubjectPublicKeyInfo and PKCS#8
+// PrivateKeyInfo; the 32 byte key follows.
+const X25519_SPKI_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x6e, 0x03, 0x21, 0x00,
+];
+const X25519_PKCS8_PREFIX: [u8; 16] = [
+    0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x6e, 0x04, 0x22, 0x04, 0x20,
+];
+
+#[pyclass(eq, eq_int)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyType {
+    Djb = 0x05,
+}
+
+impl From<libsignal_protocol::KeyType> for KeyType {
+    fn from(key_type: libsignal_protocol::KeyType) -> Self {
+        match key_type {
+            libsignal_protocol::KeyType::Djb => KeyType::Djb,
+        }
+    }
+}
+
+fn key_from_der<'a>(der: &'a [u8], prefix: &[u8], what: &str) -> PyResult<&'a [u8]> {
+    match der.strip_prefix(prefix) {
+        Some(key) if key.len() == 32 => Ok(key),
+        _ => Err(InvalidArgumentException::new_err(format!(
+            "invalid {}: expected {} DER bytes for an X25519 key",
+            what,
+            prefix.len() + 32
+        ))),
+    }
+}
+
+fn to_pem(label: &str, der: &[u8]) -> String {
+    let encoded = BASE64.encode(der);
+    let mut pem = format!("-----BEGIN {}-----\n", label);
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).expect("base64 is ascii"));
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {}-----\n", label));
+    pem
+}
+
+fn from_pem(pem: &str, label: &str) -> PyResult<Vec<u8>> {
+    let begin = format!("-----BEGIN {}-----", label);
+    let end = format!("-----END {}-----", label);
+    let body = pem
+        .trim()
+        .strip_prefix(begin.as_str())
+        .and_then(|rest| rest.strip_suffix(end.as_str()))
+        .ok_or_else(|| {
+            InvalidArgumentException::new_err(format!("invalid PEM: expected a {} block", label))
+        })?;
+    let body: String = body.split_whitespace().collect();
+    BASE64
+        .decode(body)
+        .map_err(|err| InvalidArgumentException::new_err(format!("invalid PEM: {}", err)))
+}
 
 #[pyfunction]
 pub fn generate_keypair(py: Python) -> PyResult<(PyObject, PyObject)> {
@@ -25,6 +96,12 @@ pub struct KeyPair {
     pub key: libsignal_protocol::KeyPair,
 }
 
+impl Drop for KeyPair {
+    fn drop(&mut self) {
+        zeroize_key(&mut self.key);
+    }
+}
+
 #[pymethods]
 impl KeyPair {
     #[new]
@@ -41,11 +118,11 @@ impl KeyPair {
     }
 
     pub fn public_key(&self) -> Result<PublicKey> {
-        Ok(PublicKey::deserialize(&self.key.public_key.serialize())?)
+        Ok(PublicKey::new(self.key.public_key))
     }
 
     pub fn private_key(&self) -> Result<PrivateKey> {
-        Ok(PrivateKey::deserialize(&self.key.private_key.serialize())?)
+        Ok(PrivateKey::new(self.key.private_key))
     }
 
     pub fn serialize(&self, py: Python) -> PyObject {
@@ -53,7 +130,7 @@ impl KeyPair {
         PyBytes::new(py, &result).into()
     }
 
-    pub fn calculate_signature(&self, py: Python, message: &[u8]) -> Result<PyObject> {
+    pub fn calculate_signature(&self, py: Python, message: BytesLike) -> Result<PyObject> {
         let mut csprng = rand::rngs::OsRng;
         let sig = self.key.calculate_signature(&message, &mut csprng)?;
         Ok(PyBytes::new(py, &sig).into())
@@ -65,12 +142,9 @@ impl KeyPair {
     }
 
     #[staticmethod]
-    pub fn from_public_and_private(public_key: &[u8], private_key: &[u8]) -> Result<Self> {
+    pub fn from_public_and_private(public_key: BytesLike, private_key: BytesLike) -> Result<Self> {
         Ok(KeyPair {
-            key: libsignal_protocol::KeyPair::from_public_and_private(
-                public_key,
-                private_key,
-            )?,
+            key: libsignal_protocol::KeyPair::from_public_and_private(&public_key, &private_key)?,
         })
     }
 }
@@ -85,15 +159,20 @@ impl PublicKey {
     pub fn new(key: libsignal_protocol::PublicKey) -> Self {
         PublicKey { key }
     }
+
+    fn from_djb_bytes(key: &[u8]) -> Result<Self> {
+        Ok(PublicKey {
+            key: libsignal_protocol::PublicKey::from_djb_public_key_bytes(key)?,
+        })
+    }
 }
 
-/// key_type is not implemented for PublicKey.
 #[pymethods]
 impl PublicKey {
     #[staticmethod]
-    pub fn deserialize(key: &[u8]) -> Result<Self> {
+    pub fn deserialize(key: BytesLike) -> Result<Self> {
         Ok(Self {
-            key: libsignal_protocol::PublicKey::deserialize(key)?,
+            key: libsignal_protocol::PublicKey::deserialize(&key)?,
         })
     }
 
@@ -101,10 +180,95 @@ impl PublicKey {
         PyBytes::new(py, &self.key.serialize()).into()
     }
 
-    pub fn verify_signature(&self, message: &[u8], signature: &[u8]) -> Result<bool> {
+    pub fn key_type(&self) -> KeyType {
+        self.key.key_type().into()
+    }
+
+    /// The raw 32 byte Montgomery u coordinate, without the key type prefix.
+    pub fn public_key_bytes(&self, py: Python) -> PyObject {
+        PyBytes::new(py, &self.key.serialize()[1..]).into()
+    }
+
+    #[staticmethod]
+    pub fn from_djb_public_key_bytes(key: BytesLike) -> Result<Self> {
+        PublicKey::from_djb_bytes(&key)
+    }
+
+    /// The Ed25519 public key with the same Edwards y coordinate, using the sign
+    /// bit of zero as XEdDSA does.
+    pub fn to_ed25519_public_key_bytes(&self, py: Python) -> PyResult<PyObject> {
+        let u: [u8; 32] = self.key.serialize()[1..]
+            .try_into()
+            .map_err(|_| InvalidArgumentException::new_err("not a Curve25519 public key"))?;
+        match MontgomeryPoint(u).to_edwards(0) {
+            Some(point) => Ok(PyBytes::new(py, point.compress().as_bytes()).into()),
+            None => Err(InvalidArgumentException::new_err(
+                "public key has no Ed25519 equivalent",
+            )),
+        }
+    }
+
+    #[staticmethod]
+    pub fn from_ed25519_public_key_bytes(key: BytesLike) -> PyResult<Self> {
+        let point = CompressedEdwardsY::from_slice(&key)
+            .ok()
+            .and_then(|compressed| compressed.decompress())
+            .ok_or_else(|| InvalidArgumentException::new_err("invalid Ed25519 public key"))?;
+        Ok(PublicKey::from_djb_bytes(point.to_montgomery().as_bytes())?)
+    }
+
+    /// DER encoded X25519 SubjectPublicKeyInfo.
+    pub fn to_der(&self, py: Python) -> PyObject {
+        let der = [&X25519_SPKI_PREFIX[..], &self.key.serialize()[1..]].concat();
+        PyBytes::new(py, &der).into()
+    }
+
+    #[staticmethod]
+    pub fn from_der(der: BytesLike) -> PyResult<Self> {
+        let key = key_from_der(&der, &X25519_SPKI_PREFIX, "public key")?;
+        Ok(PublicKey::from_djb_bytes(key)?)
+    }
+
+    pub fn to_pem(&self) -> String {
+        let der = [&X25519_SPKI_PREFIX[..], &self.key.serialize()[1..]].concat();
+        to_pem("PUBLIC KEY", &der)
+    }
+
+    #[staticmethod]
+    pub fn from_pem(pem: &str) -> PyResult<Self> {
+        let der = from_pem(pem, "PUBLIC KEY")?;
+        let key = key_from_der(&der, &X25519_SPKI_PREFIX, "public key")?;
+        Ok(PublicKey::from_djb_bytes(key)?)
+    }
+
+    pub fn verify_signature(&self, message: BytesLike, signature: BytesLike) -> Result<bool> {
         Ok(self.key.verify_signature(&message, &signature))
     }
 
+    /// Verifies an XEdDSA signature over the concatenation of `message_parts`.
+    pub fn verify_signature_for_multipart_message(
+        &self,
+        message_parts: Vec<BytesLike>,
+        signature: BytesLike,
+    ) -> bool {
+        let parts: Vec<&[u8]> = message_parts.iter().map(|part| &part[..]).collect();
+        self.key
+            .verify_signature_for_multipart_message(&parts, &signature)
+    }
+
+    /// Verifies a VXEdDSA signature, returning the 32 byte VRF output if it is
+    /// valid and None otherwise.
+    pub fn verify_vrf_signature(
+        &self,
+        py: Python,
+        message: BytesLike,
+        signature: BytesLike,
+    ) -> Option<PyObject> {
+        let serialized = self.key.serialize();
+        let u: &[u8; 32] = serialized.get(1..)?.try_into().ok()?;
+        vxeddsa::verify(u, &message, &signature).map(|output| PyBytes::new(py, &output).into())
+    }
+
     fn __richcmp__(&self, other: PublicKey, op: CompareOp) -> PyResult<bool> {
         match op {
             CompareOp::Eq => Ok(self.key.serialize() == other.key.serialize()),
@@ -115,7 +279,7 @@ impl PublicKey {
 }
 
 #[pyclass]
-#[derive(Clone, Copy, Eq, PartialEq)]
+#[derive(Clone, Eq, PartialEq)]
 pub struct PrivateKey {
     pub key: libsignal_protocol::PrivateKey,
 }
@@ -124,26 +288,157 @@ impl PrivateKey {
     pub fn new(key: libsignal_protocol::PrivateKey) -> Self {
         PrivateKey { key }
     }
+
+    fn from_bytes(key: &[u8]) -> Result<Self> {
+        Ok(PrivateKey {
+            key: libsignal_protocol::PrivateKey::deserialize(key)?,
+        })
+    }
+
+    fn sign(&self, message: &[u8], py: Python) -> Result<PyObject> {
+        let mut csprng = rand::rngs::OsRng;
+        let sig = self.key.calculate_signature(message, &mut csprng)?;
+        Ok(PyBytes::new(py, &sig).into())
+    }
+}
+
+impl Drop for PrivateKey {
+    fn drop(&mut self) {
+        zeroize_key(&mut self.key);
+    }
+}
+
+/// Upstream key types that are `Copy` and hold their key bytes inline, without
+/// pointers or a `Drop` implementation.
+pub(crate) trait FlatKey: Copy {}
+
+impl FlatKey for libsignal_protocol::PrivateKey {}
+impl FlatKey for libsignal_protocol::KeyPair {}
+impl FlatKey for libsignal_protocol::IdentityKeyPair {}
+
+// At the pinned upstream revision (ef5f5b9) a private or public key is a
+// single-variant enum around a 32 byte array, and the pairs are two of them.
+// If a later revision adds a pointer or a Drop implementation, the sizes or
+// needs_drop change and these stop compiling, so zeroize_key gets re-audited.
+const _: () = assert!(
+    std::mem::size_of::<libsignal_protocol::PrivateKey>() == 32
+        && !std::mem::needs_drop::<libsignal_protocol::PrivateKey>()
+);
+const _: () = assert!(
+    std::mem::size_of::<libsignal_protocol::KeyPair>() == 64
+        && !std::mem::needs_drop::<libsignal_protocol::KeyPair>()
+);
+const _: () = assert!(
+    std::mem::size_of::<libsignal_protocol::IdentityKeyPair>() == 64
+        && !std::mem::needs_drop::<libsignal_protocol::IdentityKeyPair>()
+);
+
+/// Overwrites an upstream key in place with zeros, on a best-effort basis. The
+/// wrappers holding private keys call this when dropped, but copies made by the
+/// upstream crate itself (e.g. in the stores), by moves on the stack, or in
+/// serializations handed to Python are out of reach.
+pub(crate) fn zeroize_key<T: FlatKey>(key: &mut T) {
+    // SAFETY: FlatKey types are plain bytes with no Drop implementation (see
+    // the assertions above), so zero bytes leave nothing to free, and the
+    // wrappers never read the key again after zeroizing it in drop().
+    unsafe { zeroize::zeroize_flat_type(key as *mut T) }
 }
 
-/// key_type() is not implemented on this struct.
 #[pymethods]
 impl PrivateKey {
     #[staticmethod]
-    pub fn deserialize(key: &[u8]) -> Result<Self> {
-        Ok(Self {
-            key: libsignal_protocol::PrivateKey::deserialize(key)?,
-        })
+    pub fn deserialize(key: BytesLike) -> Result<Self> {
+        PrivateKey::from_bytes(&key)
     }
 
     pub fn serialize(&self, py: Python) -> PyObject {
         PyBytes::new(py, &self.key.serialize()).into()
     }
 
-    pub fn calculate_signature(&self, message: &[u8], py: Python) -> Result<PyObject> {
-        let mut csprng = rand::rngs::OsRng;
-        let sig = self.key.calculate_signature(message, &mut csprng)?;
-        Ok(PyBytes::new(py, &sig).into())
+    pub fn key_type(&self) -> KeyType {
+        self.key.key_type().into()
+    }
+
+    /// The X25519 key derived from an Ed25519 private key (the 32 byte seed), as
+    /// in RFC 8032: the clamped first half of its SHA-512 hash.
+    #[staticmethod]
+    pub fn from_ed25519_private_key_bytes(seed: BytesLike) -> PyResult<Self> {
+        if seed.len() != 32 {
+            return Err(InvalidArgumentException::new_err(format!(
+                "invalid Ed25519 private key of {} bytes",
+                seed.len()
+            )));
+        }
+        let hash = Zeroizing::new(<[u8; 64]>::from(Sha512::digest(&seed[..])));
+        let mut scalar = Zeroizing::new([0u8; 32]);
+        scalar.copy_from_slice(&hash[..32]);
+        Ok(PrivateKey::from_bytes(&Zeroizing::new(clamp_integer(*scalar))[..])?)
+    }
+
+    /// DER encoded X25519 PKCS#8 PrivateKeyInfo.
+    pub fn to_der(&self, py: Python) -> PyObject {
+        let der = [&X25519_PKCS8_PREFIX[..], &self.key.serialize()].concat();
+        PyBytes::new(py, &der).into()
+    }
+
+    #[staticmethod]
+    pub fn from_der(der: BytesLike) -> PyResult<Self> {
+        let key = key_from_der(&der, &X25519_PKCS8_PREFIX, "private key")?;
+        Ok(PrivateKey::from_bytes(key)?)
+    }
+
+    pub fn to_pem(&self) -> String {
+        let der = [&X25519_PKCS8_PREFIX[..], &self.key.serialize()].concat();
+        to_pem("PRIVATE KEY", &der)
+    }
+
+    #[staticmethod]
+    pub fn from_pem(pem: &str) -> PyResult<Self> {
+        let der = from_pem(pem, "PRIVATE KEY")?;
+        let key = key_from_der(&der, &X25519_PKCS8_PREFIX, "private key")?;
+        Ok(PrivateKey::from_bytes(key)?)
+    }
+
+    pub fn calculate_signature(&self, message: BytesLike, py: Python) -> Result<PyObject> {
+        self.sign(&message, py)
+    }
+
+    /// Signs the concatenation of `message_parts`, for verification with
+    /// PublicKey.verify_signature_for_multipart_message().
+    pub fn calculate_signature_for_multipart_message(
+        &self,
+        message_parts: Vec<BytesLike>,
+        py: Python,
+    ) -> Result<PyObject> {
+        let parts: Vec<&[u8]> = message_parts.iter().map(|part| &part[..]).collect();
+        self.sign(&parts.concat(), py)
+    }
+
+    /// Signs `message` with VXEdDSA. Returns the 96 byte signature, which proves
+    /// the VRF output, and the 32 byte VRF output itself.
+    pub fn calculate_vrf_signature(
+        &self,
+        py: Python,
+        message: BytesLike,
+    ) -> PyResult<(PyObject, PyObject)> {
+        let serialized = Zeroizing::new(self.key.serialize());
+        let private_key: Zeroizing<[u8; 32]> =
+            Zeroizing::new(serialized.as_slice().try_into().map_err(|_| {
+                SignalProtocolError::err_from_str(
+                    "VXEdDSA requires a Curve25519 private key".to_string(),
+                )
+            })?);
+        let mut random = Zeroizing::new([0u8; 64]);
+        OsRng.fill_bytes(&mut random[..]);
+        match vxeddsa::sign(&private_key, &message, &random) {
+            Some((signature, output)) => Ok((
+                PyBytes::new(py, &signature).into(),
+                PyBytes::new(py, &output).into(),
+            )),
+            None => Err(SignalProtocolError::err_from_str(
+                "failed to compute VXEdDSA signature".to_string(),
+            )),
+        }
     }
 
     pub fn calculate_agreement(&self, py: Python, their_key: &PublicKey) -> Result<PyObject> {
@@ -159,16 +454,22 @@ impl PrivateKey {
 }
 
 #[pyfunction]
-pub fn verify_signature(public_key: &PublicKey, message: &[u8], signature: &[u8]) -> Result<bool> {
-    Ok(public_key.verify_signature(message, signature)?)
+pub fn verify_signature(
+    public_key: &PublicKey,
+    message: BytesLike,
+    signature: BytesLike,
+) -> Result<bool> {
+    Ok(public_key.key.verify_signature(&message, &signature))
 }
 
-/// KeyType is not exposed as part of the Python API.
 pub fn init_curve_submodule(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<KeyType>()?;
     module.add_class::<KeyPair>()?;
     module.add_class::<PublicKey>()?;
     module.add_class::<PrivateKey>()?;
     module.add_wrapped(wrap_pyfunction!(generate_keypair))?;
     module.add_wrapped(wrap_pyfunction!(verify_signature))?;
+    module.add("VRF_SIGNATURE_LENGTH", vxeddsa::SIGNATURE_LENGTH)?;
+    module.add("VRF_OUTPUT_LENGTH", vxeddsa::OUTPUT_LENGTH)?;
     Ok(())
 }
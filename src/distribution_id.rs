@@ -0,0 +1,40 @@
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyString};
+
+use uuid::Uuid;
+
+use crate::error::InvalidArgumentException;
+
+/// Distribution ids are accepted from Python as uuid.UUID, 16 bytes or a string.
+#[derive(Clone, Copy, Debug)]
+pub struct DistributionId(pub Uuid);
+
+impl<'py> FromPyObject<'py> for DistributionId {
+    fn extract_bound(ob: &Bound<'py, PyAny>) -> PyResult<Self> {
+        Ok(DistributionId(uuid_from_py(ob, "distribution id")?))
+    }
+}
+
+/// Converts uuid.UUID, 16 bytes or a string into a Uuid, raising
+/// InvalidArgumentException (naming `what`) for anything else.
+pub fn uuid_from_py(ob: &Bound<'_, PyAny>, what: &str) -> PyResult<Uuid> {
+    let invalid = |reason: String| InvalidArgumentException::new_err(format!("invalid {}: {}", what, reason));
+
+    if let Ok(string) = ob.downcast::<PyString>() {
+        return Uuid::parse_str(string.to_str()?).map_err(|err| invalid(err.to_string()));
+    }
+    if let Ok(bytes) = ob.downcast::<PyBytes>() {
+        let bytes = bytes.as_bytes();
+        return Uuid::from_slice(bytes)
+            .map_err(|_| invalid(format!("expected 16 bytes, got {}", bytes.len())));
+    }
+    let uuid_class = ob.py().import("uuid")?.getattr("UUID")?;
+    if ob.is_instance(&uuid_class)? {
+        let bytes: Vec<u8> = ob.getattr("bytes")?.extract()?;
+        return Uuid::from_slice(&bytes).map_err(|err| invalid(err.to_string()));
+    }
+    Err(invalid(format!(
+        "expected uuid.UUID, bytes or str, got {}",
+        ob.get_type().name()?
+    )))
+}
@@ -12,6 +12,9 @@ create_exception!(
     pyo3::exceptions::PyException
 );
 
+// Raised for malformed arguments that are validated before reaching the upstream crate.
+create_exception!(error, InvalidArgumentException, SignalProtocolException);
+
 #[derive(Debug)]
 pub struct SignalProtocolError {
     pub err: libsignal_protocol::SignalProtocolError,
@@ -63,5 +66,9 @@ pub fn init_submodule(module: &Bound<'_, PyModule>) -> PyResult<()> {
         "SignalProtocolException",
         module.py().get_type::<SignalProtocolException>(),
     )?;
+    module.add(
+        "InvalidArgumentException",
+        module.py().get_type::<InvalidArgumentException>(),
+    )?;
     Ok(())
 }
@@ -1,8 +1,18 @@
 use pyo3::prelude::*;
 use pyo3::types::PyBytes;
+use pyo3::wrap_pyfunction;
 
-use crate::error::{Result, SignalProtocolError};
+use prost::Message;
+use uuid::Uuid;
+
+use crate::address::ProtocolAddress;
+use crate::buffer::BytesLike;
+use crate::error::{InvalidArgumentException, Result, SignalProtocolError};
 use crate::identity_key::IdentityKey;
+use crate::proto;
+
+/// Iterations used by the official clients when generating safety numbers.
+pub const SAFETY_NUMBER_ITERATIONS: u32 = 5200;
 
 #[pyclass]
 #[derive(Clone, Debug)]
@@ -10,10 +20,8 @@ pub struct Fingerprint {
     pub state: libsignal_protocol::Fingerprint,
 }
 
-#[pymethods]
 impl Fingerprint {
-    #[new]
-    pub fn new(
+    fn from_ids(
         version: u32,
         iterations: u32,
         local_id: &[u8],
@@ -33,13 +41,28 @@ impl Fingerprint {
             Err(err) => Err(SignalProtocolError::new_err(err)),
         }
     }
+}
+
+#[pymethods]
+impl Fingerprint {
+    #[new]
+    pub fn new(
+        version: u32,
+        iterations: u32,
+        local_id: BytesLike,
+        local_key: &IdentityKey,
+        remote_id: BytesLike,
+        remote_key: &IdentityKey,
+    ) -> PyResult<Self> {
+        Self::from_ids(version, iterations, &local_id, local_key, &remote_id, remote_key)
+    }
 
     pub fn display_string(&self) -> Result<String> {
         Ok(self.state.display_string()?)
     }
 
-    pub fn compare(&self, combined: &[u8]) -> Result<bool> {
-        Ok(self.state.scannable.compare(combined)?)
+    pub fn compare(&self, combined: BytesLike) -> Result<bool> {
+        Ok(self.state.scannable.compare(&combined)?)
     }
 
     pub fn serialize(&self, py: Python) -> Result<PyObject> {
@@ -47,6 +70,22 @@ impl Fingerprint {
         Ok(PyBytes::new(py, &fingerprint).into())
     }
 
+    pub fn scannable(&self) -> ScannableFingerprint {
+        ScannableFingerprint {
+            state: self.state.scannable.clone(),
+        }
+    }
+
+    pub fn displayable(&self) -> PyResult<DisplayableFingerprint> {
+        self.scannable().displayable()
+    }
+
+    /// The bytes encoded in the QR code shown on the verification screen of the
+    /// Signal clients (as an ISO-8859-1 string): the serialized scannable fingerprint.
+    pub fn qr_payload(&self, py: Python) -> Result<PyObject> {
+        self.serialize(py)
+    }
+
     fn __str__(&self) -> Result<String> {
         self.display_string()
     }
@@ -56,14 +95,191 @@ impl Fingerprint {
     }
 }
 
+#[pyclass]
+#[derive(Clone, Debug)]
+pub struct ScannableFingerprint {
+    pub state: libsignal_protocol::ScannableFingerprint,
+}
+
+impl ScannableFingerprint {
+    fn decode(&self) -> PyResult<proto::CombinedFingerprints> {
+        let bytes = self.state.serialize().map_err(SignalProtocolError::new_err)?;
+        proto::CombinedFingerprints::decode(&bytes[..]).map_err(|err| {
+            SignalProtocolError::err_from_str(format!("failed to decode fingerprint: {}", err))
+        })
+    }
+}
+
+#[pymethods]
+impl ScannableFingerprint {
+    #[staticmethod]
+    pub fn deserialize(data: BytesLike) -> Result<Self> {
+        Ok(Self {
+            state: libsignal_protocol::ScannableFingerprint::deserialize(&data)?,
+        })
+    }
+
+    pub fn serialize(&self, py: Python) -> Result<PyObject> {
+        let fingerprint = self.state.serialize()?;
+        Ok(PyBytes::new(py, &fingerprint).into())
+    }
+
+    /// Compares against the other party's scannable fingerprint, given either as a
+    /// ScannableFingerprint or as the bytes scanned from their QR code. Raises if the
+    /// fingerprint versions differ.
+    pub fn compare(&self, other: &Bound<'_, PyAny>) -> PyResult<bool> {
+        let combined = match other.downcast::<ScannableFingerprint>() {
+            Ok(other) => other
+                .borrow()
+                .state
+                .serialize()
+                .map_err(SignalProtocolError::new_err)?,
+            Err(_) => other.extract::<BytesLike>()?.to_vec(),
+        };
+        self.state
+            .compare(&combined)
+            .map_err(SignalProtocolError::new_err)
+    }
+
+    pub fn version(&self) -> PyResult<u32> {
+        Ok(self.decode()?.version)
+    }
+
+    pub fn local_fingerprint(&self, py: Python) -> PyResult<PyObject> {
+        let content = self.decode()?.local_fingerprint.unwrap_or_default().content;
+        Ok(PyBytes::new(py, &content).into())
+    }
+
+    pub fn remote_fingerprint(&self, py: Python) -> PyResult<PyObject> {
+        let content = self.decode()?.remote_fingerprint.unwrap_or_default().content;
+        Ok(PyBytes::new(py, &content).into())
+    }
+
+    /// The safety number derived from the same per-party fingerprints.
+    pub fn displayable(&self) -> PyResult<DisplayableFingerprint> {
+        let combined = self.decode()?;
+        Ok(DisplayableFingerprint {
+            local: encode_displayable_half(
+                &combined.local_fingerprint.unwrap_or_default().content,
+            )?,
+            remote: encode_displayable_half(
+                &combined.remote_fingerprint.unwrap_or_default().content,
+            )?,
+        })
+    }
+}
+
+/// The 60 digit safety number, made of a 30 digit half per party.
+#[pyclass]
+#[derive(Clone, Debug)]
+pub struct DisplayableFingerprint {
+    local: String,
+    remote: String,
+}
+
+#[pymethods]
+impl DisplayableFingerprint {
+    pub fn local(&self) -> String {
+        self.local.clone()
+    }
+
+    pub fn remote(&self) -> String {
+        self.remote.clone()
+    }
+
+    /// Both halves with the smaller one first, so both parties see the same string.
+    pub fn display_string(&self) -> String {
+        if self.local <= self.remote {
+            format!("{}{}", self.local, self.remote)
+        } else {
+            format!("{}{}", self.remote, self.local)
+        }
+    }
+
+    fn __str__(&self) -> String {
+        self.display_string()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("DisplayableFingerprint({})", self.display_string())
+    }
+}
+
+// Mirrors the upstream encoding: six 5-byte big-endian chunks, each reduced mod 100000.
+fn encode_displayable_half(fingerprint: &[u8]) -> PyResult<String> {
+    if fingerprint.len() < 30 {
+        return Err(SignalProtocolError::err_from_str(format!(
+            "fingerprint too short to display: {} bytes",
+            fingerprint.len()
+        )));
+    }
+    Ok(fingerprint[..30]
+        .chunks(5)
+        .map(|chunk| {
+            let value = chunk.iter().fold(0u64, |acc, &byte| (acc << 8) | u64::from(byte));
+            format!("{:05}", value % 100_000)
+        })
+        .collect())
+}
 
-/// Instead of DisplayableFingerprint, ScannableFingerprint, and Fingerprint, we
-/// just expose Fingerprint, with the relevant methods on the DisplayableFingerprint
-/// and ScannableFingerprint implemented on the Fingerprint directly.
+// Version 2 safety numbers are computed over the binary ACI, version 1 over the E.164.
+fn stable_id(address: &ProtocolAddress, version: u32) -> PyResult<Vec<u8>> {
+    let name = address.state.name();
+    match version {
+        1 => {
+            let digits = name.strip_prefix('+').unwrap_or("");
+            if digits.is_empty() || !digits.bytes().all(|c| c.is_ascii_digit()) {
+                return Err(InvalidArgumentException::new_err(format!(
+                    "version 1 safety numbers need an E.164 address name, got {:?}",
+                    name
+                )));
+            }
+            Ok(name.as_bytes().to_vec())
+        }
+        2 => match Uuid::parse_str(name) {
+            Ok(aci) => Ok(aci.as_bytes().to_vec()),
+            Err(_) => Err(InvalidArgumentException::new_err(format!(
+                "version 2 safety numbers need an ACI address name, got {:?}",
+                name
+            ))),
+        },
+        _ => Err(InvalidArgumentException::new_err(format!(
+            "unsupported safety number version {}",
+            version
+        ))),
+    }
+}
+
+/// Generates the safety number between two parties the way the official clients do.
 ///
-/// ScannableFingerprint::deserialize() is not implemented.
+/// Version 2 (the current one) requires the address names to be ACI UUID strings;
+/// version 1 requires them to be E.164 phone numbers.
+#[pyfunction]
+#[pyo3(signature = (local_address, local_identity, remote_address, remote_identity, version=2))]
+pub fn safety_number(
+    local_address: &ProtocolAddress,
+    local_identity: &IdentityKey,
+    remote_address: &ProtocolAddress,
+    remote_identity: &IdentityKey,
+    version: u32,
+) -> PyResult<Fingerprint> {
+    Fingerprint::from_ids(
+        version,
+        SAFETY_NUMBER_ITERATIONS,
+        &stable_id(local_address, version)?,
+        local_identity,
+        &stable_id(remote_address, version)?,
+        remote_identity,
+    )
+}
+
+/// ScannableFingerprint and DisplayableFingerprint are exposed alongside Fingerprint,
+/// which also keeps their most used methods for convenience.
 pub fn init_submodule(module: &Bound<'_, PyModule>) -> PyResult<()> {
     module.add_class::<Fingerprint>()?;
+    module.add_class::<ScannableFingerprint>()?;
+    module.add_class::<DisplayableFingerprint>()?;
+    module.add("SAFETY_NUMBER_ITERATIONS", SAFETY_NUMBER_ITERATIONS)?;
+    module.add_wrapped(wrap_pyfunction!(safety_number))?;
     Ok(())
 }
-
@@ -4,74 +4,130 @@ use pyo3::wrap_pyfunction;
 
 use futures::executor::block_on;
 use rand::rngs::OsRng;
-use uuid::Uuid;
-
-use crate::error::{Result, SignalProtocolError};
+use crate::buffer::{bytes_or_write, BytesLike, WritableBuffer};
+use crate::consts;
+use crate::distribution_id::DistributionId;
+use crate::error::SignalProtocolError;
 use crate::protocol::SenderKeyDistributionMessage;
 use crate::address::ProtocolAddress;
-use crate::storage::InMemSignalProtocolStore;
+use crate::storage::{InMemSignalProtocolStore, SharedStore};
+use crate::transaction::ProtocolStoreArg;
 
+/// Returns the serialized SenderKeyMessage, or with `out` writes it to `out`
+/// and returns the number of bytes written.
 #[pyfunction]
+#[pyo3(signature = (protocol_store, sender, distribution_id, plaintext, out=None))]
 pub fn group_encrypt(
     py: Python,
-    protocol_store: &mut InMemSignalProtocolStore,
+    mut protocol_store: ProtocolStoreArg,
     sender: &ProtocolAddress,
-    distribution_id: String,
-    plaintext: &[u8],
-) -> Result<PyObject> {
+    distribution_id: DistributionId,
+    plaintext: BytesLike,
+    out: Option<WritableBuffer>,
+) -> PyResult<PyObject> {
     let mut csprng = OsRng;
-    let ciphertext = block_on(libsignal_protocol::group_encrypt(
-        &mut protocol_store.store.sender_key_store,
-        &sender.state,
-        Uuid::parse_str(&distribution_id).unwrap(),
-        plaintext,
-        &mut csprng,
-    ))?;
-    Ok(PyBytes::new(py, &ciphertext.serialized()).into())
+    let ciphertext = protocol_store.with_stores(&sender.state, |stores| {
+        block_on(libsignal_protocol::group_encrypt(
+            stores.sender_key_store,
+            &sender.state,
+            distribution_id.0,
+            &plaintext,
+            &mut csprng,
+        ))
+        .map_err(SignalProtocolError::new_err)
+    })?;
+    bytes_or_write(py, ciphertext.serialized(), out)
 }
 
+/// Returns the plaintext, or with `out` writes it to `out` and returns the
+/// number of bytes written.
 #[pyfunction]
+#[pyo3(signature = (skm_bytes, protocol_store, protocol_address, out=None))]
 pub fn group_decrypt(
     py: Python,
-    skm_bytes: &[u8],
-    protocol_store: &mut InMemSignalProtocolStore,
+    skm_bytes: BytesLike,
+    mut protocol_store: ProtocolStoreArg,
     protocol_address: &ProtocolAddress,
-) -> Result<PyObject> {
-    let plaintext = block_on(libsignal_protocol::group_decrypt(
-        skm_bytes,
-        &mut protocol_store.store.sender_key_store,
-        &protocol_address.state,
-    ))?;
-    Ok(PyBytes::new(py, &plaintext).into())
+    out: Option<WritableBuffer>,
+) -> PyResult<PyObject> {
+    let plaintext = protocol_store.with_stores(&protocol_address.state, |stores| {
+        block_on(libsignal_protocol::group_decrypt(
+            &skm_bytes,
+            stores.sender_key_store,
+            &protocol_address.state,
+        ))
+        .map_err(SignalProtocolError::new_err)
+    })?;
+    bytes_or_write(py, &plaintext, out)
+}
+
+/// Decrypts a list of (sender address, serialized SenderKeyMessage) tuples in
+/// order and with a single release of the GIL.
+///
+/// Returns a list with the plaintext of each message, or the exception raised
+/// decrypting it: a failed message does not stop the batch.
+#[pyfunction]
+pub fn group_decrypt_batch(
+    py: Python,
+    mut protocol_store: ProtocolStoreArg,
+    messages: Vec<(PyRef<ProtocolAddress>, BytesLike)>,
+) -> PyResult<Vec<PyObject>> {
+    let messages: Vec<_> = messages
+        .into_iter()
+        .map(|(sender, skm_bytes)| (sender.state.clone(), skm_bytes))
+        .collect();
+    let results = protocol_store.without_gil(|access| {
+        Ok(messages
+            .iter()
+            .map(|(sender, skm_bytes)| {
+                access.with_address(sender, |stores| {
+                    block_on(libsignal_protocol::group_decrypt(
+                        skm_bytes,
+                        stores.sender_key_store,
+                        sender,
+                    ))
+                    .map_err(SignalProtocolError::new_err)
+                })
+            })
+            .collect::<Vec<_>>())
+    })?;
+    Ok(results
+        .into_iter()
+        .map(|result| match result {
+            Ok(plaintext) => PyBytes::new(py, &plaintext).into_any().unbind(),
+            Err(err) => err.into_value(py).into_any(),
+        })
+        .collect())
 }
 
 #[pyfunction]
 pub fn process_sender_key_distribution_message(
     protocol_address: &ProtocolAddress,
     skdm: &SenderKeyDistributionMessage,
-    protocol_store: &mut InMemSignalProtocolStore,
-) -> Result<()> {
-    Ok(block_on(
-        libsignal_protocol::process_sender_key_distribution_message(
+    mut protocol_store: ProtocolStoreArg,
+) -> PyResult<()> {
+    protocol_store.with_stores(&protocol_address.state, |stores| {
+        block_on(libsignal_protocol::process_sender_key_distribution_message(
             &protocol_address.state,
             &skdm.data,
-            &mut protocol_store.store.sender_key_store,
-        ),
-    )?)
+            stores.sender_key_store,
+        ))
+        .map_err(SignalProtocolError::new_err)
+    })
 }
 
-#[pyfunction]
-pub fn create_sender_key_distribution_message(
+// Called with the lock for `sender` held.
+fn sender_key_distribution_message(
+    store: &SharedStore,
     sender: &ProtocolAddress,
-    distribution_id: String,
-    protocol_store: &mut InMemSignalProtocolStore,
+    distribution_id: DistributionId,
 ) -> PyResult<SenderKeyDistributionMessage> {
     let mut csprng = OsRng;
     let upstream_data = match block_on(
         libsignal_protocol::create_sender_key_distribution_message(
             &sender.state,
-            Uuid::parse_str(&distribution_id).unwrap(),
-            &mut protocol_store.store.sender_key_store,
+            distribution_id.0,
+            &mut &store.sender_key_store,
             &mut csprng,
         ),
     ) {
@@ -79,14 +135,56 @@ pub fn create_sender_key_distribution_message(
         Err(err) => return Err(SignalProtocolError::new_err(err)),
     };
     Ok(SenderKeyDistributionMessage {
-        data: upstream_data.clone(),
+        data: upstream_data,
+    })
+}
+
+#[pyfunction]
+pub fn create_sender_key_distribution_message(
+    py: Python,
+    sender: &ProtocolAddress,
+    distribution_id: DistributionId,
+    protocol_store: &InMemSignalProtocolStore,
+) -> PyResult<SenderKeyDistributionMessage> {
+    let store = &protocol_store.store;
+    py.allow_threads(|| {
+        store.with_address_lock(&sender.state, || {
+            sender_key_distribution_message(store, sender, distribution_id)
+        })
+    })
+}
+
+/// Discards the sender's current sender key chain for `distribution_id` and
+/// starts a new one, returning the SenderKeyDistributionMessage for it.
+///
+/// Call this after a member leaves the group and send the returned message only
+/// to the remaining members, so the departed member cannot decrypt new messages.
+#[pyfunction]
+pub fn rotate_sender_key(
+    py: Python,
+    sender: &ProtocolAddress,
+    distribution_id: DistributionId,
+    protocol_store: &InMemSignalProtocolStore,
+) -> PyResult<SenderKeyDistributionMessage> {
+    let store = &protocol_store.store;
+    py.allow_threads(|| {
+        store.with_address_lock(&sender.state, || {
+            store
+                .sender_key_store
+                .remove(&(sender.state.clone(), distribution_id.0));
+            sender_key_distribution_message(store, sender, distribution_id)
+        })
     })
 }
 
+/// The upstream limits are exposed as module constants.
 pub fn init_submodule(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add("MAX_SENDER_KEY_STATES", consts::MAX_SENDER_KEY_STATES)?;
     module.add_wrapped(wrap_pyfunction!(group_encrypt))?;
     module.add_wrapped(wrap_pyfunction!(group_decrypt))?;
+    module.add_wrapped(wrap_pyfunction!(group_decrypt_batch))?;
     module.add_wrapped(wrap_pyfunction!(process_sender_key_distribution_message))?;
     module.add_wrapped(wrap_pyfunction!(create_sender_key_distribution_message))?;
+    module.add_wrapped(wrap_pyfunction!(rotate_sender_key))?;
     Ok(())
 }
@@ -0,0 +1,220 @@
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use futures::executor::block_on;
+use rand::rngs::OsRng;
+use std::time::SystemTime;
+use uuid::Uuid;
+
+use crate::address::ProtocolAddress;
+use crate::buffer::BytesLike;
+use crate::distribution_id::DistributionId;
+use crate::error::SignalProtocolError;
+use crate::protocol::CiphertextMessage;
+use crate::storage::{lock, sort_addresses, InMemSignalProtocolStore, SharedStore};
+
+/// Sends to a group using sender keys, bound to one sender and distribution id.
+///
+/// On each send the SenderKeyDistributionMessage of the current chain is encrypted
+/// pairwise to every member device that has not received it yet, so a session must
+/// already exist with each member. Removing a member rotates the chain, so the
+/// remaining members receive a fresh SenderKeyDistributionMessage with the next send.
+///
+/// The membership state is locked by every method, so a GroupSession can be
+/// shared between threads.
+#[pyclass(frozen)]
+pub struct GroupSession {
+    sender: ProtocolAddress,
+    distribution_id: Uuid,
+    membership: Mutex<Membership>,
+}
+
+#[derive(Default)]
+struct Membership {
+    members: Vec<ProtocolAddress>,
+    // Members that were sent the distribution message for `chain_id`.
+    distributed_to: HashSet<libsignal_protocol::ProtocolAddress>,
+    chain_id: Option<u32>,
+}
+
+impl Membership {
+    fn pending_members(&self) -> Vec<ProtocolAddress> {
+        self.members
+            .iter()
+            .filter(|member| !self.distributed_to.contains(&member.state))
+            .cloned()
+            .collect()
+    }
+
+    fn rotate(&mut self) {
+        self.distributed_to.clear();
+        self.chain_id = None;
+    }
+}
+
+#[pymethods]
+impl GroupSession {
+    #[new]
+    fn new(
+        sender: ProtocolAddress,
+        distribution_id: DistributionId,
+        members: Vec<ProtocolAddress>,
+    ) -> Self {
+        let session = GroupSession {
+            sender,
+            distribution_id: distribution_id.0,
+            membership: Mutex::new(Membership::default()),
+        };
+        for member in members {
+            session.add_member(member);
+        }
+        session
+    }
+
+    fn sender(&self) -> ProtocolAddress {
+        self.sender.clone()
+    }
+
+    fn distribution_id(&self) -> String {
+        self.distribution_id.to_string()
+    }
+
+    fn members(&self) -> Vec<ProtocolAddress> {
+        lock(&self.membership).members.clone()
+    }
+
+    /// Member devices that will be sent the distribution message on the next send.
+    fn pending_members(&self) -> Vec<ProtocolAddress> {
+        lock(&self.membership).pending_members()
+    }
+
+    /// Adds a member device. Returns False if it was already a member.
+    fn add_member(&self, member: ProtocolAddress) -> bool {
+        let mut membership = lock(&self.membership);
+        if member.state == self.sender.state
+            || membership.members.iter().any(|known| known.state == member.state)
+        {
+            return false;
+        }
+        membership.members.push(member);
+        true
+    }
+
+    /// Removes a member device and rotates the sender key chain. Returns False
+    /// (without rotating) if it was not a member.
+    fn remove_member(&self, member: &ProtocolAddress, protocol_store: &InMemSignalProtocolStore) -> bool {
+        let mut membership = lock(&self.membership);
+        let count = membership.members.len();
+        membership.members.retain(|known| known.state != member.state);
+        if membership.members.len() == count {
+            return false;
+        }
+        self.remove_sender_key(protocol_store);
+        membership.rotate();
+        true
+    }
+
+    /// Starts a new sender key chain, to be distributed to all members on the next send.
+    fn rotate(&self, protocol_store: &InMemSignalProtocolStore) {
+        let mut membership = lock(&self.membership);
+        self.remove_sender_key(protocol_store);
+        membership.rotate();
+    }
+
+    /// Encrypts `plaintext` for the group.
+    ///
+    /// Returns the group ciphertext (to be sent to every member) and a list of
+    /// (member address, CiphertextMessage) pairs holding the pairwise-encrypted
+    /// SenderKeyDistributionMessage for members that have not received it yet.
+    fn encrypt(
+        &self,
+        py: Python,
+        protocol_store: &InMemSignalProtocolStore,
+        plaintext: BytesLike,
+    ) -> PyResult<(PyObject, Vec<(ProtocolAddress, CiphertextMessage)>)> {
+        let store = &protocol_store.store;
+        let (ciphertext, envelopes) = py.allow_threads(|| {
+            let mut membership = lock(&self.membership);
+            // All the address locks are taken at once, in the order transaction
+            // commits take them. Every member is locked, not only the pending
+            // ones: a new sender key chain makes them all pending again.
+            let mut addresses: Vec<_> = std::iter::once(&self.sender)
+                .chain(&membership.members)
+                .map(|address| address.state.clone())
+                .collect();
+            sort_addresses(&mut addresses);
+            store.with_address_locks(&addresses, || {
+                self.encrypt_locked(&mut membership, store, &plaintext)
+            })
+        })?;
+        Ok((PyBytes::new(py, &ciphertext).into(), envelopes))
+    }
+}
+
+impl GroupSession {
+    fn remove_sender_key(&self, protocol_store: &InMemSignalProtocolStore) {
+        protocol_store
+            .store
+            .sender_key_store
+            .remove(&(self.sender.state.clone(), self.distribution_id));
+    }
+
+    // Called holding the locks of the sender and of every member.
+    fn encrypt_locked(
+        &self,
+        membership: &mut Membership,
+        store: &SharedStore,
+        plaintext: &[u8],
+    ) -> PyResult<(Vec<u8>, Vec<(ProtocolAddress, CiphertextMessage)>)> {
+        let mut csprng = OsRng;
+        let skdm = block_on(libsignal_protocol::create_sender_key_distribution_message(
+            &self.sender.state,
+            self.distribution_id,
+            &mut &store.sender_key_store,
+            &mut csprng,
+        ))
+        .map_err(SignalProtocolError::new_err)?;
+        let chain_id = skdm.chain_id().map_err(SignalProtocolError::new_err)?;
+        if membership.chain_id != Some(chain_id) {
+            membership.distributed_to.clear();
+            membership.chain_id = Some(chain_id);
+        }
+
+        // Members are only marked as distributed to once every envelope was created,
+        // so a failure leaves them pending for the next send.
+        let mut envelopes = Vec::new();
+        for member in membership.pending_members() {
+            let ciphertext = block_on(libsignal_protocol::message_encrypt(
+                skdm.serialized(),
+                &member.state,
+                &mut &store.session_store,
+                &mut &store.identity_store,
+                SystemTime::now(),
+            ))
+            .map_err(SignalProtocolError::new_err)?;
+            envelopes.push((member, CiphertextMessage::new(ciphertext)));
+        }
+
+        let ciphertext = block_on(libsignal_protocol::group_encrypt(
+            &mut &store.sender_key_store,
+            &self.sender.state,
+            self.distribution_id,
+            plaintext,
+            &mut csprng,
+        ))
+        .map_err(SignalProtocolError::new_err)?;
+
+        for (member, _) in &envelopes {
+            membership.distributed_to.insert(member.state.clone());
+        }
+        Ok((ciphertext.serialized().to_vec(), envelopes))
+    }
+}
+
+pub fn init_submodule(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<GroupSession>()?;
+    Ok(())
+}
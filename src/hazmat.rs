@@ -0,0 +1,352 @@
+//! Low-level primitives with the parameters libsignal uses: HKDF-SHA256, the
+//! AES-256 modes of the signal-crypto crate and the hashes next to them.
+//!
+//! AES-256-GCM and AES-256-CTR are signal-crypto's own, at the same revision as
+//! libsignal-protocol. signal-crypto has no GCM-SIV at that revision:
+//! libsignal's bindings use the aes-gcm-siv crate for it, and so does this.
+//! HKDF, CBC, HMAC and the hashes are the RustCrypto implementations the
+//! upstream crates are built on. Nothing here checks that keys and nonces are
+//! used correctly, hence the module name.
+
+use aes_gcm_siv::aead::{Aead, KeyInit, Payload};
+use cbc::cipher::block_padding::Pkcs7;
+use cbc::cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use pyo3::wrap_pyfunction;
+use sha2::{Digest, Sha256, Sha512};
+use zeroize::Zeroize;
+
+use crate::buffer::BytesLike;
+use crate::error::{InvalidArgumentException, SignalProtocolError};
+
+pub const AES_256_KEY_LENGTH: usize = 32;
+pub const AES_BLOCK_LENGTH: usize = 16;
+pub const AEAD_NONCE_LENGTH: usize = 12;
+const AEAD_TAG_LENGTH: usize = 16;
+const HKDF_PRK_LENGTH: usize = 32;
+const HKDF_MAX_LENGTH: usize = 255 * 32;
+
+fn check_length(what: &str, value: &[u8], length: usize) -> PyResult<()> {
+    if value.len() != length {
+        return Err(InvalidArgumentException::new_err(format!(
+            "{} must be {} bytes, got {}",
+            what,
+            length,
+            value.len()
+        )));
+    }
+    Ok(())
+}
+
+fn to_bytes(py: Python, data: Vec<u8>) -> Bound<PyBytes> {
+    PyBytes::new(py, &data)
+}
+
+pub fn hkdf_sha256(
+    ikm: &[u8],
+    salt: Option<&[u8]>,
+    info: &[u8],
+    length: usize,
+) -> PyResult<Vec<u8>> {
+    let mut okm = vec![0u8; length];
+    Hkdf::<Sha256>::new(salt, ikm)
+        .expand(info, &mut okm)
+        .map_err(|_| {
+            InvalidArgumentException::new_err(format!(
+                "HKDF output length must be at most {}, got {}",
+                HKDF_MAX_LENGTH, length
+            ))
+        })?;
+    Ok(okm)
+}
+
+pub fn hmac_sha256_digest(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+pub fn cbc_encrypt(key: &[u8], iv: &[u8], plaintext: &[u8]) -> PyResult<Vec<u8>> {
+    check_length("AES-256 key", key, AES_256_KEY_LENGTH)?;
+    check_length("IV", iv, AES_BLOCK_LENGTH)?;
+    Ok(cbc::Encryptor::<aes::Aes256>::new_from_slices(key, iv)
+        .expect("key and IV have valid lengths")
+        .encrypt_padded_vec_mut::<Pkcs7>(plaintext))
+}
+
+pub fn cbc_decrypt(key: &[u8], iv: &[u8], ciphertext: &[u8]) -> PyResult<Vec<u8>> {
+    check_length("AES-256 key", key, AES_256_KEY_LENGTH)?;
+    check_length("IV", iv, AES_BLOCK_LENGTH)?;
+    cbc::Decryptor::<aes::Aes256>::new_from_slices(key, iv)
+        .expect("key and IV have valid lengths")
+        .decrypt_padded_vec_mut::<Pkcs7>(ciphertext)
+        .map_err(|_| {
+            SignalProtocolError::err_from_str("invalid AES-CBC ciphertext or padding".to_string())
+        })
+}
+
+/// HKDF-SHA256 extract: the pseudorandom key for `ikm`. Without a salt, a
+/// salt of zeros is used.
+#[pyfunction]
+#[pyo3(signature = (ikm, salt=None))]
+pub fn hkdf_extract<'py>(
+    py: Python<'py>,
+    ikm: BytesLike,
+    salt: Option<BytesLike>,
+) -> Bound<'py, PyBytes> {
+    let (prk, _) = Hkdf::<Sha256>::extract(salt.as_deref(), &ikm);
+    PyBytes::new(py, &prk)
+}
+
+/// HKDF-SHA256 expand: `length` bytes of output keying material from the
+/// pseudorandom key `prk`.
+#[pyfunction]
+pub fn hkdf_expand<'py>(
+    py: Python<'py>,
+    prk: BytesLike,
+    info: BytesLike,
+    length: usize,
+) -> PyResult<Bound<'py, PyBytes>> {
+    if prk.len() < HKDF_PRK_LENGTH {
+        return Err(InvalidArgumentException::new_err(format!(
+            "HKDF pseudorandom key must be at least {} bytes, got {}",
+            HKDF_PRK_LENGTH,
+            prk.len()
+        )));
+    }
+    let hkdf = Hkdf::<Sha256>::from_prk(&prk).expect("length was checked");
+    let mut okm = vec![0u8; length];
+    hkdf.expand(&info, &mut okm).map_err(|_| {
+        InvalidArgumentException::new_err(format!(
+            "HKDF output length must be at most {}, got {}",
+            HKDF_MAX_LENGTH, length
+        ))
+    })?;
+    Ok(to_bytes(py, okm))
+}
+
+/// HKDF-SHA256 extract and expand, as used throughout the protocol.
+#[pyfunction]
+#[pyo3(signature = (ikm, info, length, salt=None))]
+pub fn hkdf_derive<'py>(
+    py: Python<'py>,
+    ikm: BytesLike,
+    info: BytesLike,
+    length: usize,
+    salt: Option<BytesLike>,
+) -> PyResult<Bound<'py, PyBytes>> {
+    Ok(to_bytes(
+        py,
+        hkdf_sha256(&ikm, salt.as_deref(), &info, length)?,
+    ))
+}
+
+#[pyfunction]
+pub fn sha256<'py>(py: Python<'py>, data: BytesLike) -> Bound<'py, PyBytes> {
+    PyBytes::new(py, &Sha256::digest(&data[..]))
+}
+
+#[pyfunction]
+pub fn sha512<'py>(py: Python<'py>, data: BytesLike) -> Bound<'py, PyBytes> {
+    PyBytes::new(py, &Sha512::digest(&data[..]))
+}
+
+#[pyfunction]
+pub fn hmac_sha256<'py>(py: Python<'py>, key: BytesLike, data: BytesLike) -> Bound<'py, PyBytes> {
+    PyBytes::new(py, &hmac_sha256_digest(&key, &data))
+}
+
+/// AES-256-CBC with PKCS#7 padding.
+#[pyfunction]
+pub fn aes_256_cbc_encrypt<'py>(
+    py: Python<'py>,
+    key: BytesLike,
+    iv: BytesLike,
+    plaintext: BytesLike,
+) -> PyResult<Bound<'py, PyBytes>> {
+    Ok(to_bytes(py, cbc_encrypt(&key, &iv, &plaintext)?))
+}
+
+#[pyfunction]
+pub fn aes_256_cbc_decrypt<'py>(
+    py: Python<'py>,
+    key: BytesLike,
+    iv: BytesLike,
+    ciphertext: BytesLike,
+) -> PyResult<Bound<'py, PyBytes>> {
+    Ok(to_bytes(py, cbc_decrypt(&key, &iv, &ciphertext)?))
+}
+
+/// signal-crypto's Aes256Ctr32: AES-256 in counter mode with a 12 byte nonce
+/// and a 32-bit big-endian block counter starting at `initial_counter`, for at
+/// most 2^32 - 1 - `initial_counter` blocks. Encryption and decryption are the
+/// same operation.
+#[pyfunction]
+#[pyo3(signature = (key, nonce, data, initial_counter=0))]
+pub fn aes_256_ctr32<'py>(
+    py: Python<'py>,
+    key: BytesLike,
+    nonce: BytesLike,
+    data: BytesLike,
+    initial_counter: u32,
+) -> PyResult<Bound<'py, PyBytes>> {
+    check_length("AES-256 key", &key, AES_256_KEY_LENGTH)?;
+    check_length("nonce", &nonce, AEAD_NONCE_LENGTH)?;
+    // Aes256Ctr32 panics once the counter is exhausted; this stops a block
+    // early, as the RustCrypto counter it is built on does.
+    let blocks = (data.len() as u64 + AES_BLOCK_LENGTH as u64 - 1) / AES_BLOCK_LENGTH as u64;
+    if blocks > u64::from(u32::MAX - initial_counter) {
+        return Err(InvalidArgumentException::new_err(
+            "data exceeds the AES-CTR counter range",
+        ));
+    }
+    let mut output = data.to_vec();
+    signal_crypto::Aes256Ctr32::from_key(&key, &nonce, initial_counter)
+        .expect("key and nonce have valid lengths")
+        .process(&mut output);
+    Ok(to_bytes(py, output))
+}
+
+fn tag_error() -> PyErr {
+    SignalProtocolError::err_from_str("invalid AEAD tag".to_string())
+}
+
+fn aead_error(_: aes_gcm_siv::aead::Error) -> PyErr {
+    tag_error()
+}
+
+/// AES-256-GCM. Returns the ciphertext followed by the 16 byte tag.
+#[pyfunction]
+#[pyo3(signature = (key, nonce, plaintext, associated_data=None))]
+pub fn aes_256_gcm_encrypt<'py>(
+    py: Python<'py>,
+    key: BytesLike,
+    nonce: BytesLike,
+    plaintext: BytesLike,
+    associated_data: Option<BytesLike>,
+) -> PyResult<Bound<'py, PyBytes>> {
+    check_length("AES-256 key", &key, AES_256_KEY_LENGTH)?;
+    check_length("nonce", &nonce, AEAD_NONCE_LENGTH)?;
+    let mut gcm = signal_crypto::Aes256GcmEncryption::new(
+        &key,
+        &nonce,
+        associated_data.as_deref().unwrap_or_default(),
+    )
+    .expect("key and nonce have valid lengths");
+    let mut ciphertext = plaintext.to_vec();
+    gcm.encrypt(&mut ciphertext);
+    ciphertext.extend_from_slice(&gcm.compute_tag());
+    Ok(to_bytes(py, ciphertext))
+}
+
+/// AES-256-GCM decryption of the ciphertext followed by the tag. Raises
+/// SignalProtocolException if the tag does not match.
+#[pyfunction]
+#[pyo3(signature = (key, nonce, ciphertext, associated_data=None))]
+pub fn aes_256_gcm_decrypt<'py>(
+    py: Python<'py>,
+    key: BytesLike,
+    nonce: BytesLike,
+    ciphertext: BytesLike,
+    associated_data: Option<BytesLike>,
+) -> PyResult<Bound<'py, PyBytes>> {
+    check_length("AES-256 key", &key, AES_256_KEY_LENGTH)?;
+    check_length("nonce", &nonce, AEAD_NONCE_LENGTH)?;
+    if ciphertext.len() < AEAD_TAG_LENGTH {
+        return Err(tag_error());
+    }
+    let (ciphertext, tag) = ciphertext.split_at(ciphertext.len() - AEAD_TAG_LENGTH);
+    let mut gcm = signal_crypto::Aes256GcmDecryption::new(
+        &key,
+        &nonce,
+        associated_data.as_deref().unwrap_or_default(),
+    )
+    .expect("key and nonce have valid lengths");
+    let mut plaintext = ciphertext.to_vec();
+    gcm.decrypt(&mut plaintext);
+    if gcm.verify_tag(tag).is_err() {
+        plaintext.zeroize();
+        return Err(tag_error());
+    }
+    Ok(to_bytes(py, plaintext))
+}
+
+/// AES-256-GCM-SIV (RFC 8452), which tolerates nonce reuse.
+#[pyclass(frozen)]
+pub struct Aes256GcmSiv {
+    cipher: aes_gcm_siv::Aes256GcmSiv,
+}
+
+#[pymethods]
+impl Aes256GcmSiv {
+    #[new]
+    fn new(key: BytesLike) -> PyResult<Self> {
+        check_length("AES-256 key", &key, AES_256_KEY_LENGTH)?;
+        Ok(Aes256GcmSiv {
+            cipher: aes_gcm_siv::Aes256GcmSiv::new_from_slice(&key)
+                .expect("key has a valid length"),
+        })
+    }
+
+    /// Returns the ciphertext followed by the 16 byte tag.
+    #[pyo3(signature = (plaintext, nonce, associated_data=None))]
+    fn encrypt<'py>(
+        &self,
+        py: Python<'py>,
+        plaintext: BytesLike,
+        nonce: BytesLike,
+        associated_data: Option<BytesLike>,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        check_length("nonce", &nonce, AEAD_NONCE_LENGTH)?;
+        let payload = Payload {
+            msg: &plaintext,
+            aad: associated_data.as_deref().unwrap_or_default(),
+        };
+        let ciphertext = self
+            .cipher
+            .encrypt(aes_gcm_siv::Nonce::from_slice(&nonce), payload)
+            .map_err(aead_error)?;
+        Ok(to_bytes(py, ciphertext))
+    }
+
+    /// Raises SignalProtocolException if the tag does not match.
+    #[pyo3(signature = (ciphertext, nonce, associated_data=None))]
+    fn decrypt<'py>(
+        &self,
+        py: Python<'py>,
+        ciphertext: BytesLike,
+        nonce: BytesLike,
+        associated_data: Option<BytesLike>,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        check_length("nonce", &nonce, AEAD_NONCE_LENGTH)?;
+        let payload = Payload {
+            msg: &ciphertext,
+            aad: associated_data.as_deref().unwrap_or_default(),
+        };
+        let plaintext = self
+            .cipher
+            .decrypt(aes_gcm_siv::Nonce::from_slice(&nonce), payload)
+            .map_err(aead_error)?;
+        Ok(to_bytes(py, plaintext))
+    }
+}
+
+pub fn init_submodule(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<Aes256GcmSiv>()?;
+    module.add_function(wrap_pyfunction!(hkdf_extract, module)?)?;
+    module.add_function(wrap_pyfunction!(hkdf_expand, module)?)?;
+    module.add_function(wrap_pyfunction!(hkdf_derive, module)?)?;
+    module.add_function(wrap_pyfunction!(sha256, module)?)?;
+    module.add_function(wrap_pyfunction!(sha512, module)?)?;
+    module.add_function(wrap_pyfunction!(hmac_sha256, module)?)?;
+    module.add_function(wrap_pyfunction!(aes_256_cbc_encrypt, module)?)?;
+    module.add_function(wrap_pyfunction!(aes_256_cbc_decrypt, module)?)?;
+    module.add_function(wrap_pyfunction!(aes_256_ctr32, module)?)?;
+    module.add_function(wrap_pyfunction!(aes_256_gcm_encrypt, module)?)?;
+    module.add_function(wrap_pyfunction!(aes_256_gcm_decrypt, module)?)?;
+    Ok(())
+}
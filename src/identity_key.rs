@@ -4,11 +4,32 @@ use pyo3::basic::CompareOp;
 use pyo3::exceptions;
 use pyo3::prelude::*;
 use pyo3::types::PyBytes;
+use pyo3::wrap_pyfunction;
 
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
 use rand::rngs::OsRng;
-
-use crate::curve::{PrivateKey, PublicKey};
-use crate::error::{Result, SignalProtocolError};
+use rand::{Rng, RngCore};
+use zeroize::Zeroizing;
+
+use crate::buffer::BytesLike;
+use crate::curve::{zeroize_key, PrivateKey, PublicKey};
+use crate::error::{InvalidArgumentException, Result, SignalProtocolError};
+
+/// Registration ids are in `1..=MAX_REGISTRATION_ID`, as generated by the official clients.
+pub const MAX_REGISTRATION_ID: u32 = 16380;
+
+/// Format version written by IdentityKeyPair.export_encrypted().
+pub const ENCRYPTED_EXPORT_VERSION: u8 = 1;
+const ENCRYPTED_EXPORT_SALT_LENGTH: usize = 16;
+const ENCRYPTED_EXPORT_NONCE_LENGTH: usize = 12;
+const ENCRYPTED_EXPORT_HEADER_LENGTH: usize =
+    1 + ENCRYPTED_EXPORT_SALT_LENGTH + ENCRYPTED_EXPORT_NONCE_LENGTH;
+// Argon2id parameters of format version 1.
+const ARGON2_MEMORY_KIB: u32 = 64 * 1024;
+const ARGON2_ITERATIONS: u32 = 3;
+const ARGON2_PARALLELISM: u32 = 1;
 
 #[pyclass]
 #[derive(Debug, Clone, Copy)]
@@ -21,21 +42,31 @@ impl IdentityKey {
     // The behavior of libsignal_protocol::IdentityKey::decode is provided
     // by the new() function.
     #[new]
-    pub fn new(public_key: &[u8]) -> PyResult<Self> {
-        match libsignal_protocol::IdentityKey::try_from(public_key) {
+    pub fn new(public_key: BytesLike) -> PyResult<Self> {
+        match libsignal_protocol::IdentityKey::try_from(&public_key[..]) {
             Ok(key) => Ok(Self { key }),
             Err(err) => Err(SignalProtocolError::new_err(err)),
         }
     }
 
     pub fn public_key(&self) -> Result<PublicKey> {
-        Ok(PublicKey::deserialize(&self.key.public_key().serialize())?)
+        Ok(PublicKey::new(*self.key.public_key()))
     }
 
     pub fn serialize(&self, py: Python) -> PyObject {
         PyBytes::new(py, &self.key.serialize()).into()
     }
 
+    /// Verifies a signature made with IdentityKeyPair.sign_alternate_identity(),
+    /// proving that `other` belongs to the same account as this identity key.
+    pub fn verify_alternate_identity(
+        &self,
+        other: &IdentityKey,
+        signature: BytesLike,
+    ) -> Result<bool> {
+        Ok(self.key.verify_alternate_identity(&other.key, &signature)?)
+    }
+
     fn __richcmp__(&self, other: IdentityKey, op: CompareOp) -> PyResult<bool> {
         match op {
             CompareOp::Eq => Ok(self.key.serialize() == other.key.serialize()),
@@ -47,11 +78,26 @@ impl IdentityKey {
 
 
 #[pyclass]
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub struct IdentityKeyPair {
     pub key: libsignal_protocol::IdentityKeyPair,
 }
 
+impl IdentityKeyPair {
+    fn decode(identity_key_pair_bytes: &[u8]) -> PyResult<Self> {
+        match libsignal_protocol::IdentityKeyPair::try_from(identity_key_pair_bytes) {
+            Ok(key) => Ok(Self { key }),
+            Err(err) => Err(SignalProtocolError::new_err(err)),
+        }
+    }
+}
+
+impl Drop for IdentityKeyPair {
+    fn drop(&mut self) {
+        zeroize_key(&mut self.key);
+    }
+}
+
 #[pymethods]
 impl IdentityKeyPair {
     #[new]
@@ -62,11 +108,8 @@ impl IdentityKeyPair {
     }
 
     #[staticmethod]
-    pub fn from_bytes(identity_key_pair_bytes: &[u8]) -> PyResult<Self> {
-        match libsignal_protocol::IdentityKeyPair::try_from(identity_key_pair_bytes) {
-            Ok(key) => Ok(Self { key }),
-            Err(err) => Err(SignalProtocolError::new_err(err)),
-        }
+    pub fn from_bytes(identity_key_pair_bytes: BytesLike) -> PyResult<Self> {
+        IdentityKeyPair::decode(&identity_key_pair_bytes)
     }
 
     #[staticmethod]
@@ -77,29 +120,134 @@ impl IdentityKeyPair {
     }
 
     pub fn identity_key(&self) -> PyResult<IdentityKey> {
-        match IdentityKey::new(&self.key.public_key().serialize()) {
-            Ok(key) => Ok(key),
-            Err(err) => Err(err),
-        }
+        Ok(IdentityKey {
+            key: *self.key.identity_key(),
+        })
     }
 
     pub fn public_key(&self) -> Result<PublicKey> {
-        Ok(PublicKey::deserialize(&self.key.public_key().serialize())?)
+        Ok(PublicKey::new(*self.key.public_key()))
     }
 
     pub fn private_key(&self) -> Result<PrivateKey> {
-        Ok(PrivateKey::deserialize(
-            &self.key.private_key().serialize(),
-        )?)
+        Ok(PrivateKey::new(*self.key.private_key()))
     }
 
     pub fn serialize(&self, py: Python) -> PyObject {
         PyBytes::new(py, &self.key.serialize()).into()
     }
+
+    /// Serializes the key pair encrypted under `passphrase` (str or bytes).
+    ///
+    /// The key is derived with Argon2id and the key pair sealed with
+    /// ChaCha20-Poly1305. The output is version (1 byte) || salt (16 bytes) ||
+    /// nonce (12 bytes) || ciphertext, with the first three authenticated.
+    pub fn export_encrypted(&self, py: Python, passphrase: &Bound<'_, PyAny>) -> PyResult<PyObject> {
+        let passphrase = passphrase_bytes(passphrase)?;
+        let mut header = [0u8; ENCRYPTED_EXPORT_HEADER_LENGTH];
+        header[0] = ENCRYPTED_EXPORT_VERSION;
+        OsRng.fill_bytes(&mut header[1..]);
+        let (salt, nonce) = header[1..].split_at(ENCRYPTED_EXPORT_SALT_LENGTH);
+
+        let plaintext = Zeroizing::new(self.key.serialize().into_vec());
+        let ciphertext = export_cipher(&passphrase, salt)?
+            .encrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: &plaintext,
+                    aad: &header,
+                },
+            )
+            .map_err(|_| SignalProtocolError::err_from_str("encryption failed".to_string()))?;
+        Ok(PyBytes::new(py, &[&header[..], &ciphertext].concat()).into())
+    }
+
+    /// Decrypts the output of export_encrypted().
+    #[staticmethod]
+    pub fn import_encrypted(data: BytesLike, passphrase: &Bound<'_, PyAny>) -> PyResult<Self> {
+        let passphrase = passphrase_bytes(passphrase)?;
+        if data.len() < ENCRYPTED_EXPORT_HEADER_LENGTH {
+            return Err(InvalidArgumentException::new_err(
+                "invalid encrypted identity key pair: too short",
+            ));
+        }
+        let (header, ciphertext) = data.split_at(ENCRYPTED_EXPORT_HEADER_LENGTH);
+        if header[0] != ENCRYPTED_EXPORT_VERSION {
+            return Err(InvalidArgumentException::new_err(format!(
+                "unsupported encrypted identity key pair version {}",
+                header[0]
+            )));
+        }
+        let (salt, nonce) = header[1..].split_at(ENCRYPTED_EXPORT_SALT_LENGTH);
+
+        let plaintext = Zeroizing::new(
+            export_cipher(&passphrase, salt)?
+                .decrypt(
+                    Nonce::from_slice(nonce),
+                    Payload {
+                        msg: ciphertext,
+                        aad: header,
+                    },
+                )
+                .map_err(|_| {
+                    SignalProtocolError::err_from_str(
+                        "failed to decrypt identity key pair: wrong passphrase or corrupted data"
+                            .to_string(),
+                    )
+                })?,
+        );
+        IdentityKeyPair::decode(&plaintext)
+    }
+
+    /// Signs `other` (e.g. the PNI identity key when this is the ACI identity),
+    /// linking the two identities of an account.
+    pub fn sign_alternate_identity(&self, py: Python, other: &IdentityKey) -> Result<PyObject> {
+        let mut csprng = OsRng;
+        let signature = self.key.sign_alternate_identity(&other.key, &mut csprng)?;
+        Ok(PyBytes::new(py, &signature).into())
+    }
+}
+
+/// Generates a random registration id, non-zero and fitting in 14 bits, the way
+/// the official clients do. It must be stored along with the identity key pair.
+#[pyfunction]
+pub fn generate_registration_id() -> u32 {
+    OsRng.gen_range(1..=MAX_REGISTRATION_ID)
+}
+
+fn passphrase_bytes(passphrase: &Bound<'_, PyAny>) -> PyResult<Zeroizing<Vec<u8>>> {
+    let bytes = match passphrase.extract::<String>() {
+        Ok(passphrase) => passphrase.into_bytes(),
+        Err(_) => passphrase
+            .extract::<BytesLike>()
+            .map_err(|_| {
+                InvalidArgumentException::new_err("invalid passphrase: expected str or bytes")
+            })?
+            .to_vec(),
+    };
+    if bytes.is_empty() {
+        return Err(InvalidArgumentException::new_err(
+            "invalid passphrase: must not be empty",
+        ));
+    }
+    Ok(Zeroizing::new(bytes))
+}
+
+fn export_cipher(passphrase: &[u8], salt: &[u8]) -> PyResult<ChaCha20Poly1305> {
+    let params = Params::new(ARGON2_MEMORY_KIB, ARGON2_ITERATIONS, ARGON2_PARALLELISM, Some(32))
+        .map_err(|err| SignalProtocolError::err_from_str(format!("invalid KDF parameters: {}", err)))?;
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase, salt, &mut key[..])
+        .map_err(|err| SignalProtocolError::err_from_str(format!("key derivation failed: {}", err)))?;
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&key[..])))
 }
 
 pub fn init_submodule(module: &Bound<'_, PyModule>) -> PyResult<()> {
     module.add_class::<IdentityKey>()?;
     module.add_class::<IdentityKeyPair>()?;
+    module.add("ENCRYPTED_EXPORT_VERSION", ENCRYPTED_EXPORT_VERSION)?;
+    module.add("MAX_REGISTRATION_ID", MAX_REGISTRATION_ID)?;
+    module.add_wrapped(wrap_pyfunction!(generate_registration_id))?;
     Ok(())
 }
@@ -1,12 +1,23 @@
 use pyo3::prelude::*;
 use std::ffi::CString;
+mod account;
 mod address;
+mod attachments;
+mod buffer;
+mod consts;
+mod content;
 mod curve;
+mod distribution_id;
 mod error;
 mod fingerprint;
 mod group_cipher;
+mod group_session;
+mod hazmat;
 mod identity_key;
+mod multi_account;
+mod proto;
 mod protocol;
+mod provisioning;
 mod ratchet;
 mod sealed_sender;
 mod sender_keys;
@@ -14,6 +25,8 @@ mod session;
 mod session_cipher;
 mod state;
 mod storage;
+mod transaction;
+mod vxeddsa;
 
 /// Signal Protocol in Python
 ///
@@ -24,75 +37,109 @@ mod storage;
 ///
 /// >>> pub, priv = signal_protocol.curve.generate_keypair()
 ///
-/// We do not expose a Python submodule for HKDF (a module in the upstream crate).
-#[pymodule]
+/// HKDF and the other primitives of the upstream crates are in the `hazmat`
+/// submodule, for protocol extensions that need the exact same constructions.
+///
+/// The module supports free-threaded Python: the mutable classes lock their state
+/// and the store releases the GIL for protocol operations.
+#[pymodule(gil_used = false)]
 fn signal_protocol(module: &Bound<'_, PyModule>) -> PyResult<()> {
-    let address_submod = PyModule::new(module.py(), "address")?;
+    let account_submod = new_submodule(module, "account")?;
+    account::init_submodule(&account_submod)?;
+    module.add_submodule(&account_submod)?;
+
+    let attachments_submod = new_submodule(module, "attachments")?;
+    attachments::init_submodule(&attachments_submod)?;
+    module.add_submodule(&attachments_submod)?;
+
+    let address_submod = new_submodule(module, "address")?;
     address::init_submodule(&address_submod)?;
     module.add_submodule(&address_submod)?;
 
-    let curve_submod = PyModule::new(module.py(), "curve")?;
+    let content_submod = new_submodule(module, "content")?;
+    content::init_submodule(&content_submod)?;
+    module.add_submodule(&content_submod)?;
+
+    let curve_submod = new_submodule(module, "curve")?;
     curve::init_curve_submodule(&curve_submod)?;
     module.add_submodule(&curve_submod)?;
 
-    let error_submod = PyModule::new(module.py(), "error")?;
+    let error_submod = new_submodule(module, "error")?;
     error::init_submodule(&error_submod)?;
     module.add_submodule(&error_submod)?;
 
-    let fingerprint_submod = PyModule::new(module.py(), "fingerprint")?;
+    let fingerprint_submod = new_submodule(module, "fingerprint")?;
     fingerprint::init_submodule(&fingerprint_submod)?;
     module.add_submodule(&fingerprint_submod)?;
 
-    let group_cipher_submod = PyModule::new(module.py(), "group_cipher")?;
+    let group_cipher_submod = new_submodule(module, "group_cipher")?;
     group_cipher::init_submodule(&group_cipher_submod)?;
     module.add_submodule(&group_cipher_submod)?;
 
-    let identity_key_submod = PyModule::new(module.py(), "identity_key")?;
+    let group_session_submod = new_submodule(module, "group_session")?;
+    group_session::init_submodule(&group_session_submod)?;
+    module.add_submodule(&group_session_submod)?;
+
+    let hazmat_submod = new_submodule(module, "hazmat")?;
+    hazmat::init_submodule(&hazmat_submod)?;
+    module.add_submodule(&hazmat_submod)?;
+
+    let identity_key_submod = new_submodule(module, "identity_key")?;
     identity_key::init_submodule(&identity_key_submod)?;
     module.add_submodule(&identity_key_submod)?;
 
-    let protocol_submod = PyModule::new(module.py(), "protocol")?;
+    let protocol_submod = new_submodule(module, "protocol")?;
     protocol::init_submodule(&protocol_submod)?;
     module.add_submodule(&protocol_submod)?;
 
-    let ratchet_submod = PyModule::new(module.py(), "ratchet")?;
+    let provisioning_submod = new_submodule(module, "provisioning")?;
+    provisioning::init_submodule(&provisioning_submod)?;
+    module.add_submodule(&provisioning_submod)?;
+
+    let ratchet_submod = new_submodule(module, "ratchet")?;
     ratchet::init_submodule(&ratchet_submod)?;
     module.add_submodule(&ratchet_submod)?;
 
-    let sealed_sender_submod = PyModule::new(module.py(), "sealed_sender")?;
+    let sealed_sender_submod = new_submodule(module, "sealed_sender")?;
     sealed_sender::init_submodule(&sealed_sender_submod)?;
     module.add_submodule(&sealed_sender_submod)?;
 
-    let sender_keys_submod = PyModule::new(module.py(), "sender_keys")?;
+    let sender_keys_submod = new_submodule(module, "sender_keys")?;
     sender_keys::init_submodule(&sender_keys_submod)?;
     module.add_submodule(&sender_keys_submod)?;
 
-    let session_cipher_submod = PyModule::new(module.py(), "session_cipher")?;
+    let session_cipher_submod = new_submodule(module, "session_cipher")?;
     session_cipher::init_submodule(&session_cipher_submod)?;
     module.add_submodule(&session_cipher_submod)?;
 
-    let session_submod = PyModule::new(module.py(), "session")?;
+    let session_submod = new_submodule(module, "session")?;
     session::init_submodule(&session_submod)?;
     module.add_submodule(&session_submod)?;
 
-    let state_submod = PyModule::new(module.py(), "state")?;
+    let state_submod = new_submodule(module, "state")?;
     state::init_submodule(&state_submod)?;
     module.add_submodule(&state_submod)?;
 
-    let storage_submod = PyModule::new(module.py(), "storage")?;
+    let storage_submod = new_submodule(module, "storage")?;
     storage::init_submodule(&storage_submod)?;
     module.add_submodule(&storage_submod)?;
 
     // Workaround to enable imports from submodules. Upstream issue: pyo3 issue #759
     // https://github.com/PyO3/pyo3/issues/759#issuecomment-653964601
     let mods = [
+        "account",
+        "attachments",
         "address",
+        "content",
         "curve",
         "error",
         "fingerprint",
         "group_cipher",
+        "group_session",
+        "hazmat",
         "identity_key",
         "protocol",
+        "provisioning",
         "ratchet",
         "sealed_sender",
         "sender_keys",
@@ -111,3 +158,10 @@ fn signal_protocol(module: &Bound<'_, PyModule>) -> PyResult<()> {
     }
     Ok(())
 }
+
+// Submodules are not covered by gil_used on the parent module.
+fn new_submodule<'py>(module: &Bound<'py, PyModule>, name: &str) -> PyResult<Bound<'py, PyModule>> {
+    let submodule = PyModule::new(module.py(), name)?;
+    submodule.gil_used(false)?;
+    Ok(submodule)
+}
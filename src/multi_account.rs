@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use pyo3::exceptions::PyKeyError;
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict};
+use zeroize::Zeroize;
+
+use crate::buffer::BytesLike;
+use crate::error::{InvalidArgumentException, SignalProtocolError};
+use crate::identity_key::IdentityKeyPair;
+use crate::storage::{lock, InMemSignalProtocolStore, SharedStore};
+
+const STORE_FILE_EXTENSION: &str = "store";
+
+// Account ids are hex encoded in file names, so that any id is a valid and
+// unambiguous file name.
+fn account_file(directory: &Path, account_id: &str) -> PathBuf {
+    directory.join(format!(
+        "{}.{}",
+        hex::encode(account_id),
+        STORE_FILE_EXTENSION
+    ))
+}
+
+fn file_account_id(path: &Path) -> Option<String> {
+    if path.extension()? != STORE_FILE_EXTENSION {
+        return None;
+    }
+    let bytes = hex::decode(path.file_stem()?.to_str()?).ok()?;
+    String::from_utf8(bytes).ok()
+}
+
+fn io_error(path: &Path, err: std::io::Error) -> PyErr {
+    SignalProtocolError::err_from_str(format!("failed to access {}: {}", path.display(), err))
+}
+
+// Writes to a temporary file first, so that a crash never leaves a partial store.
+fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let temporary = path.with_extension(format!("{}.tmp", STORE_FILE_EXTENSION));
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&temporary)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&temporary, path)
+}
+
+fn load_directory(directory: &Path) -> PyResult<HashMap<String, Arc<SharedStore>>> {
+    fs::create_dir_all(directory).map_err(|err| io_error(directory, err))?;
+    let mut accounts = HashMap::new();
+    for entry in fs::read_dir(directory).map_err(|err| io_error(directory, err))? {
+        let path = entry.map_err(|err| io_error(directory, err))?.path();
+        let account_id = match file_account_id(&path) {
+            Some(account_id) => account_id,
+            None => continue,
+        };
+        let mut data = fs::read(&path).map_err(|err| io_error(&path, err))?;
+        let store = SharedStore::deserialize(&data);
+        data.zeroize();
+        accounts.insert(account_id, Arc::new(store?));
+    }
+    Ok(accounts)
+}
+
+/// Protocol stores for many local accounts, keyed by account id.
+///
+/// `get` hands out InMemSignalProtocolStore handles, which work with all cipher
+/// functions and share their records with this store.
+///
+/// With a `path`, the accounts in that directory are loaded on creation and
+/// `save` writes all accounts back to it, one file per account. The files hold
+/// the private identity keys and are only readable by their owner.
+///
+/// Only `save` writes the files: changes made to the stores since the last
+/// `save` are lost if the process exits without calling it again.
+#[pyclass(frozen)]
+pub struct MultiAccountStore {
+    accounts: Mutex<HashMap<String, Arc<SharedStore>>>,
+    path: Option<PathBuf>,
+    // Held while files are written or removed, so that `save` cannot write back
+    // the file of an account removed in the meantime.
+    files: Mutex<()>,
+}
+
+impl MultiAccountStore {
+    fn insert(&self, account_id: String, store: SharedStore) -> PyResult<InMemSignalProtocolStore> {
+        let mut accounts = lock(&self.accounts);
+        if accounts.contains_key(&account_id) {
+            return Err(InvalidArgumentException::new_err(format!(
+                "account {} already exists",
+                account_id
+            )));
+        }
+        let store = Arc::new(store);
+        accounts.insert(account_id, store.clone());
+        Ok(InMemSignalProtocolStore { store })
+    }
+
+    // The accounts ordered by id, without holding the lock.
+    fn snapshot(&self) -> Vec<(String, Arc<SharedStore>)> {
+        let mut accounts: Vec<_> = lock(&self.accounts)
+            .iter()
+            .map(|(account_id, store)| (account_id.clone(), store.clone()))
+            .collect();
+        accounts.sort_by(|a, b| a.0.cmp(&b.0));
+        accounts
+    }
+}
+
+#[pymethods]
+impl MultiAccountStore {
+    #[new]
+    #[pyo3(signature = (path=None))]
+    fn new(py: Python, path: Option<PathBuf>) -> PyResult<Self> {
+        let accounts = match &path {
+            Some(path) => py.allow_threads(|| load_directory(path))?,
+            None => HashMap::new(),
+        };
+        Ok(MultiAccountStore {
+            accounts: Mutex::new(accounts),
+            path,
+            files: Mutex::new(()),
+        })
+    }
+
+    /// Adds an account with an empty store and returns the store.
+    fn add_account(
+        &self,
+        account_id: String,
+        identity_key_pair: &IdentityKeyPair,
+        registration_id: u32,
+    ) -> PyResult<InMemSignalProtocolStore> {
+        self.insert(
+            account_id,
+            SharedStore::new(identity_key_pair.key, registration_id),
+        )
+    }
+
+    /// Adds an account from the output of InMemSignalProtocolStore.serialize or
+    /// `export`, and returns its store.
+    fn import_account(
+        &self,
+        account_id: String,
+        data: BytesLike,
+    ) -> PyResult<InMemSignalProtocolStore> {
+        self.insert(account_id, SharedStore::deserialize(&data)?)
+    }
+
+    fn get(&self, account_id: &str) -> Option<InMemSignalProtocolStore> {
+        lock(&self.accounts)
+            .get(account_id)
+            .map(|store| InMemSignalProtocolStore {
+                store: store.clone(),
+            })
+    }
+
+    /// Removes an account, including its file. Existing handles on its store
+    /// keep working. Returns False if the account did not exist.
+    fn remove_account(&self, py: Python, account_id: &str) -> PyResult<bool> {
+        py.allow_threads(|| {
+            let _files = lock(&self.files);
+            if lock(&self.accounts).remove(account_id).is_none() {
+                return Ok(false);
+            }
+            if let Some(directory) = &self.path {
+                let path = account_file(directory, account_id);
+                match fs::remove_file(&path) {
+                    Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                        return Err(io_error(&path, err))
+                    }
+                    _ => (),
+                }
+            }
+            Ok(true)
+        })
+    }
+
+    /// The account ids, in ascending order.
+    fn accounts(&self) -> Vec<String> {
+        self.snapshot()
+            .into_iter()
+            .map(|(account_id, _)| account_id)
+            .collect()
+    }
+
+    /// Writes a snapshot of all accounts to the directory given on creation.
+    ///
+    /// Accounts removed while saving are removed once it is done. Each store is
+    /// copied record by record, so for a consistent snapshot of an account, save
+    /// it while no operations on it are running.
+    fn save(&self, py: Python) -> PyResult<()> {
+        let directory = self.path.as_ref().ok_or_else(|| {
+            SignalProtocolError::err_from_str("store was created without a path".to_string())
+        })?;
+        py.allow_threads(|| {
+            let _files = lock(&self.files);
+            for (account_id, store) in &self.snapshot() {
+                let path = account_file(directory, account_id);
+                let mut data = store.serialize()?;
+                let written = write_private(&path, &data);
+                data.zeroize();
+                written.map_err(|err| io_error(&path, err))?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Serializes all accounts, as a dict of account id to the output of
+    /// InMemSignalProtocolStore.serialize. Contains the private identity keys.
+    fn export(&self, py: Python) -> PyResult<PyObject> {
+        let result = PyDict::new(py);
+        for (account_id, store) in self.snapshot() {
+            let mut data = py.allow_threads(|| store.serialize())?;
+            result.set_item(account_id, PyBytes::new(py, &data))?;
+            data.zeroize();
+        }
+        Ok(result.into())
+    }
+
+    /// The number of stored prekeys per account, as a dict of account id to a
+    /// dict of pre_keys, signed_pre_keys and kyber_pre_keys. Kyber counts
+    /// include last-resort keys.
+    fn pre_key_status(&self, py: Python) -> PyResult<PyObject> {
+        let result = PyDict::new(py);
+        for (account_id, store) in self.snapshot() {
+            let status = PyDict::new(py);
+            status.set_item("pre_keys", store.pre_key_store.len())?;
+            status.set_item("signed_pre_keys", store.signed_pre_key_store.len())?;
+            status.set_item("kyber_pre_keys", store.kyber_pre_key_store.len())?;
+            result.set_item(account_id, status)?;
+        }
+        Ok(result.into())
+    }
+
+    /// The ids of accounts with fewer than `min_pre_keys` one-time prekeys or
+    /// `min_kyber_pre_keys` Kyber prekeys, or no signed prekey, in ascending order.
+    #[pyo3(signature = (min_pre_keys=10, min_kyber_pre_keys=10))]
+    fn accounts_needing_pre_keys(
+        &self,
+        min_pre_keys: usize,
+        min_kyber_pre_keys: usize,
+    ) -> Vec<String> {
+        self.snapshot()
+            .into_iter()
+            .filter(|(_, store)| {
+                store.pre_key_store.len() < min_pre_keys
+                    || store.kyber_pre_key_store.len() < min_kyber_pre_keys
+                    || store.signed_pre_key_store.is_empty()
+            })
+            .map(|(account_id, _)| account_id)
+            .collect()
+    }
+
+    fn __getitem__(&self, account_id: &str) -> PyResult<InMemSignalProtocolStore> {
+        self.get(account_id)
+            .ok_or_else(|| PyKeyError::new_err(account_id.to_string()))
+    }
+
+    fn __contains__(&self, account_id: &str) -> bool {
+        lock(&self.accounts).contains_key(account_id)
+    }
+
+    fn __len__(&self) -> usize {
+        lock(&self.accounts).len()
+    }
+}
@@ -0,0 +1,349 @@
+//! Protobuf structures mirroring the upstream crate's `storage.proto` and
+//! `fingerprint.proto`.
+//!
+//! The upstream crate keeps its generated protobuf modules private, so the
+//! subset we need for inspecting serialized records is declared here. Only
+//! decoding is ever done with these types: records are always created and
+//! mutated through the upstream API.
+//!
+//! `StoreStructure` is our own format for persisting a protocol store; it wraps
+//! the upstream serialization of each record.
+//!
+//! `Envelope` and `Content` mirror a subset of the Signal service's
+//! `SignalService.proto`, for wrapping messages the way the official clients
+//! do. Unlike the types above, these are also encoded. Fields not declared here
+//! are dropped when parsing.
+//!
+//! `ProvisionEnvelope` and `ProvisionMessage` mirror `Provisioning.proto` of
+//! the service, for linking devices.
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SessionStructure {
+    #[prost(uint32, tag = "1")]
+    pub session_version: u32,
+    #[prost(bytes = "vec", tag = "2")]
+    pub local_identity_public: Vec<u8>,
+    #[prost(bytes = "vec", tag = "3")]
+    pub remote_identity_public: Vec<u8>,
+    #[prost(bytes = "vec", tag = "4")]
+    pub root_key: Vec<u8>,
+    #[prost(uint32, tag = "5")]
+    pub previous_counter: u32,
+    #[prost(message, optional, tag = "6")]
+    pub sender_chain: Option<session_structure::Chain>,
+    #[prost(message, repeated, tag = "7")]
+    pub receiver_chains: Vec<session_structure::Chain>,
+    #[prost(message, optional, tag = "9")]
+    pub pending_pre_key: Option<session_structure::PendingPreKey>,
+    #[prost(uint32, tag = "10")]
+    pub remote_registration_id: u32,
+    #[prost(uint32, tag = "11")]
+    pub local_registration_id: u32,
+    #[prost(bool, tag = "12")]
+    pub needs_refresh: bool,
+    #[prost(bytes = "vec", tag = "13")]
+    pub alice_base_key: Vec<u8>,
+    #[prost(message, optional, tag = "15")]
+    pub pending_kyber_pre_key: Option<session_structure::PendingKyberPreKey>,
+}
+
+pub mod session_structure {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Chain {
+        #[prost(bytes = "vec", tag = "1")]
+        pub sender_ratchet_key: Vec<u8>,
+        #[prost(bytes = "vec", tag = "2")]
+        pub sender_ratchet_key_private: Vec<u8>,
+        #[prost(message, optional, tag = "3")]
+        pub chain_key: Option<chain::ChainKey>,
+        #[prost(message, repeated, tag = "4")]
+        pub message_keys: Vec<chain::MessageKey>,
+    }
+
+    pub mod chain {
+        #[derive(Clone, PartialEq, prost::Message)]
+        pub struct ChainKey {
+            #[prost(uint32, tag = "1")]
+            pub index: u32,
+            #[prost(bytes = "vec", tag = "2")]
+            pub key: Vec<u8>,
+        }
+
+        #[derive(Clone, PartialEq, prost::Message)]
+        pub struct MessageKey {
+            #[prost(uint32, tag = "1")]
+            pub index: u32,
+            #[prost(bytes = "vec", tag = "2")]
+            pub cipher_key: Vec<u8>,
+            #[prost(bytes = "vec", tag = "3")]
+            pub mac_key: Vec<u8>,
+            #[prost(bytes = "vec", tag = "4")]
+            pub iv: Vec<u8>,
+            #[prost(bytes = "vec", tag = "5")]
+            pub seed: Vec<u8>,
+        }
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PendingPreKey {
+        #[prost(uint32, optional, tag = "1")]
+        pub pre_key_id: Option<u32>,
+        #[prost(bytes = "vec", tag = "2")]
+        pub base_key: Vec<u8>,
+        #[prost(int32, tag = "3")]
+        pub signed_pre_key_id: i32,
+        #[prost(uint64, tag = "4")]
+        pub timestamp: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PendingKyberPreKey {
+        #[prost(uint32, tag = "1")]
+        pub pre_key_id: u32,
+        #[prost(bytes = "vec", tag = "2")]
+        pub ciphertext: Vec<u8>,
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct RecordStructure {
+    #[prost(message, optional, tag = "1")]
+    pub current_session: Option<SessionStructure>,
+    #[prost(bytes = "vec", repeated, tag = "2")]
+    pub previous_sessions: Vec<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SenderKeyStateStructure {
+    #[prost(uint32, tag = "5")]
+    pub message_version: u32,
+    #[prost(uint32, tag = "1")]
+    pub chain_id: u32,
+    #[prost(message, optional, tag = "2")]
+    pub sender_chain_key: Option<sender_key_state_structure::SenderChainKey>,
+    #[prost(message, optional, tag = "3")]
+    pub sender_signing_key: Option<sender_key_state_structure::SenderSigningKey>,
+    #[prost(message, repeated, tag = "4")]
+    pub sender_message_keys: Vec<sender_key_state_structure::SenderMessageKey>,
+}
+
+pub mod sender_key_state_structure {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SenderChainKey {
+        #[prost(uint32, tag = "1")]
+        pub iteration: u32,
+        #[prost(bytes = "vec", tag = "2")]
+        pub seed: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SenderMessageKey {
+        #[prost(uint32, tag = "1")]
+        pub iteration: u32,
+        #[prost(bytes = "vec", tag = "2")]
+        pub seed: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SenderSigningKey {
+        #[prost(bytes = "vec", tag = "1")]
+        pub public: Vec<u8>,
+        #[prost(bytes = "vec", tag = "2")]
+        pub private: Vec<u8>,
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SenderKeyRecordStructure {
+    #[prost(message, repeated, tag = "1")]
+    pub sender_key_states: Vec<SenderKeyStateStructure>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct LogicalFingerprint {
+    #[prost(bytes = "vec", tag = "1")]
+    pub content: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CombinedFingerprints {
+    #[prost(uint32, tag = "1")]
+    pub version: u32,
+    #[prost(message, optional, tag = "2")]
+    pub local_fingerprint: Option<LogicalFingerprint>,
+    #[prost(message, optional, tag = "3")]
+    pub remote_fingerprint: Option<LogicalFingerprint>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StoreStructure {
+    #[prost(bytes = "vec", tag = "1")]
+    pub identity_key_pair: Vec<u8>,
+    #[prost(uint32, tag = "2")]
+    pub registration_id: u32,
+    #[prost(message, repeated, tag = "3")]
+    pub sessions: Vec<store_structure::AddressRecord>,
+    #[prost(message, repeated, tag = "4")]
+    pub identities: Vec<store_structure::AddressRecord>,
+    #[prost(message, repeated, tag = "5")]
+    pub pre_keys: Vec<store_structure::IdRecord>,
+    #[prost(message, repeated, tag = "6")]
+    pub signed_pre_keys: Vec<store_structure::IdRecord>,
+    #[prost(message, repeated, tag = "7")]
+    pub kyber_pre_keys: Vec<store_structure::IdRecord>,
+    #[prost(message, repeated, tag = "8")]
+    pub sender_keys: Vec<store_structure::SenderKey>,
+}
+
+pub mod store_structure {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct AddressRecord {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(uint32, tag = "2")]
+        pub device_id: u32,
+        #[prost(bytes = "vec", tag = "3")]
+        pub record: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct IdRecord {
+        #[prost(uint32, tag = "1")]
+        pub id: u32,
+        #[prost(bytes = "vec", tag = "2")]
+        pub record: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SenderKey {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(uint32, tag = "2")]
+        pub device_id: u32,
+        #[prost(bytes = "vec", tag = "3")]
+        pub distribution_id: Vec<u8>,
+        #[prost(bytes = "vec", tag = "4")]
+        pub record: Vec<u8>,
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Envelope {
+    #[prost(int32, optional, tag = "1")]
+    pub r#type: Option<i32>,
+    #[prost(uint64, optional, tag = "5")]
+    pub timestamp: Option<u64>,
+    #[prost(uint32, optional, tag = "7")]
+    pub source_device: Option<u32>,
+    #[prost(bytes = "vec", optional, tag = "8")]
+    pub content: Option<Vec<u8>>,
+    #[prost(string, optional, tag = "9")]
+    pub server_guid: Option<String>,
+    #[prost(uint64, optional, tag = "10")]
+    pub server_timestamp: Option<u64>,
+    #[prost(string, optional, tag = "11")]
+    pub source_service_id: Option<String>,
+    #[prost(string, optional, tag = "13")]
+    pub destination_service_id: Option<String>,
+    #[prost(bool, optional, tag = "14")]
+    pub urgent: Option<bool>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Content {
+    #[prost(message, optional, tag = "1")]
+    pub data_message: Option<content::DataMessage>,
+    #[prost(message, optional, tag = "4")]
+    pub null_message: Option<content::NullMessage>,
+    #[prost(message, optional, tag = "5")]
+    pub receipt_message: Option<content::ReceiptMessage>,
+    #[prost(message, optional, tag = "6")]
+    pub typing_message: Option<content::TypingMessage>,
+    #[prost(bytes = "vec", optional, tag = "7")]
+    pub sender_key_distribution_message: Option<Vec<u8>>,
+    #[prost(bytes = "vec", optional, tag = "8")]
+    pub decryption_error_message: Option<Vec<u8>>,
+}
+
+pub mod content {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct DataMessage {
+        #[prost(string, optional, tag = "1")]
+        pub body: Option<String>,
+        #[prost(uint32, optional, tag = "4")]
+        pub flags: Option<u32>,
+        #[prost(uint32, optional, tag = "5")]
+        pub expire_timer: Option<u32>,
+        #[prost(bytes = "vec", optional, tag = "6")]
+        pub profile_key: Option<Vec<u8>>,
+        #[prost(uint64, optional, tag = "7")]
+        pub timestamp: Option<u64>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct NullMessage {
+        #[prost(bytes = "vec", optional, tag = "1")]
+        pub padding: Option<Vec<u8>>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ReceiptMessage {
+        #[prost(int32, optional, tag = "1")]
+        pub r#type: Option<i32>,
+        // proto2 repeated fields are not packed.
+        #[prost(uint64, repeated, packed = "false", tag = "2")]
+        pub timestamp: Vec<u64>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct TypingMessage {
+        #[prost(uint64, optional, tag = "1")]
+        pub timestamp: Option<u64>,
+        #[prost(int32, optional, tag = "2")]
+        pub action: Option<i32>,
+        #[prost(bytes = "vec", optional, tag = "3")]
+        pub group_id: Option<Vec<u8>>,
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ProvisionEnvelope {
+    #[prost(bytes = "vec", optional, tag = "1")]
+    pub public_key: Option<Vec<u8>>,
+    #[prost(bytes = "vec", optional, tag = "2")]
+    pub body: Option<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ProvisionMessage {
+    #[prost(bytes = "vec", optional, tag = "1")]
+    pub aci_identity_key_public: Option<Vec<u8>>,
+    #[prost(bytes = "vec", optional, tag = "2")]
+    pub aci_identity_key_private: Option<Vec<u8>>,
+    #[prost(string, optional, tag = "3")]
+    pub number: Option<String>,
+    #[prost(string, optional, tag = "4")]
+    pub provisioning_code: Option<String>,
+    #[prost(string, optional, tag = "5")]
+    pub user_agent: Option<String>,
+    #[prost(bytes = "vec", optional, tag = "6")]
+    pub profile_key: Option<Vec<u8>>,
+    #[prost(bool, optional, tag = "7")]
+    pub read_receipts: Option<bool>,
+    #[prost(string, optional, tag = "8")]
+    pub aci: Option<String>,
+    #[prost(uint32, optional, tag = "9")]
+    pub provisioning_version: Option<u32>,
+    #[prost(string, optional, tag = "10")]
+    pub pni: Option<String>,
+    #[prost(bytes = "vec", optional, tag = "11")]
+    pub pni_identity_key_public: Option<Vec<u8>>,
+    #[prost(bytes = "vec", optional, tag = "12")]
+    pub pni_identity_key_private: Option<Vec<u8>>,
+    #[prost(bytes = "vec", optional, tag = "13")]
+    pub master_key: Option<Vec<u8>>,
+    #[prost(string, optional, tag = "15")]
+    pub account_entropy_pool: Option<String>,
+    #[prost(bytes = "vec", optional, tag = "16")]
+    pub media_root_backup_key: Option<Vec<u8>>,
+}
@@ -4,9 +4,10 @@ use pyo3::prelude::*;
 use pyo3::types::PyBytes;
 
 use rand::rngs::OsRng;
-use uuid::Uuid;
 
+use crate::buffer::BytesLike;
 use crate::curve::{PrivateKey, PublicKey};
+use crate::distribution_id::DistributionId;
 use crate::error::{Result, SignalProtocolError};
 use crate::identity_key::IdentityKey;
 
@@ -50,8 +51,8 @@ pub struct PreKeySignalMessage {
 #[pymethods]
 impl PreKeySignalMessage {
     #[staticmethod]
-    pub unsafe fn try_from(data: &[u8]) -> PyResult<Py<PreKeySignalMessage>> {
-        let upstream_data = match libsignal_protocol::PreKeySignalMessage::try_from(data) {
+    pub unsafe fn try_from(data: BytesLike) -> PyResult<Py<PreKeySignalMessage>> {
+        let upstream_data = match libsignal_protocol::PreKeySignalMessage::try_from(&data[..]) {
             Ok(data) => data,
             Err(err) => return Err(SignalProtocolError::new_err(err)),
         };
@@ -164,8 +165,8 @@ pub struct SignalMessage {
 #[pymethods]
 impl SignalMessage {
     #[staticmethod]
-    pub unsafe fn try_from(data: &[u8]) -> PyResult<Py<SignalMessage>> {
-        let upstream_data = match libsignal_protocol::SignalMessage::try_from(data) {
+    pub unsafe fn try_from(data: BytesLike) -> PyResult<Py<SignalMessage>> {
+        let upstream_data = match libsignal_protocol::SignalMessage::try_from(&data[..]) {
             Ok(data) => data,
             Err(err) => return Err(SignalProtocolError::new_err(err)),
         };
@@ -188,17 +189,17 @@ impl SignalMessage {
     #[new]
     pub fn new(
         message_version: u8,
-        mac_key: &[u8],
+        mac_key: BytesLike,
         sender_ratchet_key: PublicKey,
         counter: u32,
         previous_counter: u32,
-        ciphertext: &[u8],
+        ciphertext: BytesLike,
         sender_identity_key: &IdentityKey,
         receiver_identity_key: &IdentityKey,
     ) -> PyResult<(Self, CiphertextMessage)> {
         let upstream_data = match libsignal_protocol::SignalMessage::new(
             message_version,
-            mac_key,
+            &mac_key,
             sender_ratchet_key.key,
             counter,
             previous_counter,
@@ -245,12 +246,12 @@ impl SignalMessage {
         &self,
         sender_identity_key: &IdentityKey,
         receiver_identity_key: &IdentityKey,
-        mac_key: &[u8],
+        mac_key: BytesLike,
     ) -> Result<bool> {
         Ok(self.data.verify_mac(
             &sender_identity_key.key,
             &receiver_identity_key.key,
-            mac_key,
+            &mac_key,
         )?)
     }
 }
@@ -264,8 +265,8 @@ pub struct SenderKeyMessage {
 #[pymethods]
 impl SenderKeyMessage {
     #[staticmethod]
-    pub unsafe fn try_from(data: &[u8]) -> PyResult<Py<SenderKeyMessage>> {
-        let upstream_data = match libsignal_protocol::SenderKeyMessage::try_from(data) {
+    pub unsafe fn try_from(data: BytesLike) -> PyResult<Py<SenderKeyMessage>> {
+        let upstream_data = match libsignal_protocol::SenderKeyMessage::try_from(&data[..]) {
             Ok(data) => data,
             Err(err) => return Err(SignalProtocolError::new_err(err)),
         };
@@ -288,19 +289,19 @@ impl SenderKeyMessage {
     #[new]
     pub fn new(
         message_version: u8,
-        distribution_id: String,
+        distribution_id: DistributionId,
         key_id: u32,
         iteration: u32,
-        ciphertext: &[u8],
+        ciphertext: BytesLike,
         signature_key: &PrivateKey,
     ) -> PyResult<(Self, CiphertextMessage)> {
         let mut csprng = OsRng;
         let upstream_data = match libsignal_protocol::SenderKeyMessage::new(
             message_version,
-            Uuid::parse_str(&distribution_id).unwrap(),
+            distribution_id.0,
             key_id,
             iteration,
-            ciphertext.into(),
+            (&ciphertext[..]).into(),
             &mut csprng,
             &signature_key.key,
         ) {
@@ -351,9 +352,9 @@ pub struct SenderKeyDistributionMessage {
 #[pymethods]
 impl SenderKeyDistributionMessage {
     #[staticmethod]
-    pub unsafe fn try_from(data: &[u8]) -> PyResult<Py<SenderKeyDistributionMessage>> {
+    pub unsafe fn try_from(data: BytesLike) -> PyResult<Py<SenderKeyDistributionMessage>> {
         let upstream_data =
-            match libsignal_protocol::SenderKeyDistributionMessage::try_from(data) {
+            match libsignal_protocol::SenderKeyDistributionMessage::try_from(&data[..]) {
                 Ok(data) => data,
                 Err(err) => return Err(SignalProtocolError::new_err(err)),
             };
@@ -378,15 +379,15 @@ impl SenderKeyDistributionMessage {
     #[new]
     pub fn new(
         message_version: u8,
-        distribution_id: String,
+        distribution_id: DistributionId,
         id: u32,
         iteration: u32,
-        chain_key: &[u8],
+        chain_key: BytesLike,
         signing_key: &PublicKey,
     ) -> PyResult<SenderKeyDistributionMessage> {
         let upstream_data = match libsignal_protocol::SenderKeyDistributionMessage::new(
             message_version,
-            Uuid::parse_str(&distribution_id).unwrap(),
+            distribution_id.0,
             id,
             iteration,
             chain_key.to_vec(),
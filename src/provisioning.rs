@@ -0,0 +1,380 @@
+use hmac::{Hmac, Mac};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::Sha256;
+use uuid::Uuid;
+use zeroize::Zeroize;
+
+use prost::Message;
+
+use crate::address::ServiceId;
+use crate::buffer::BytesLike;
+use crate::curve::{zeroize_key, KeyPair, PublicKey};
+use crate::error::{InvalidArgumentException, SignalProtocolError};
+use crate::hazmat;
+use crate::identity_key::IdentityKeyPair;
+use crate::proto;
+
+const PROVISIONING_INFO: &[u8] = b"TextSecure Provisioning Message";
+const PROVISIONING_VERSION: u8 = 0x01;
+const CIPHER_KEY_LENGTH: usize = 32;
+const MAC_LENGTH: usize = 32;
+
+fn provisioning_error(message: &str) -> PyErr {
+    SignalProtocolError::err_from_str(format!("invalid provisioning envelope: {}", message))
+}
+
+// The AES-256 and HMAC-SHA256 keys shared by the ephemeral key pair and the
+// linking device's key pair.
+fn derive_keys(
+    private_key: &libsignal_protocol::PrivateKey,
+    public_key: &libsignal_protocol::PublicKey,
+) -> PyResult<Vec<u8>> {
+    let mut agreement = private_key
+        .calculate_agreement(public_key)
+        .map_err(|err| SignalProtocolError::err_from_str(err.to_string()))?;
+    let keys = hazmat::hkdf_sha256(&agreement, None, PROVISIONING_INFO, 2 * CIPHER_KEY_LENGTH);
+    agreement.zeroize();
+    keys
+}
+
+fn encrypt_envelope(
+    their_public_key: &libsignal_protocol::PublicKey,
+    plaintext: &[u8],
+) -> PyResult<proto::ProvisionEnvelope> {
+    let mut our_key_pair = libsignal_protocol::KeyPair::generate(&mut OsRng);
+    let keys = derive_keys(&our_key_pair.private_key, their_public_key);
+    let public_key = our_key_pair.public_key.serialize().to_vec();
+    zeroize_key(&mut our_key_pair);
+    let mut keys = keys?;
+    let (cipher_key, mac_key) = keys.split_at(CIPHER_KEY_LENGTH);
+
+    let mut iv = [0u8; hazmat::AES_BLOCK_LENGTH];
+    OsRng.fill_bytes(&mut iv);
+    // version || IV || ciphertext || HMAC-SHA256 of everything before it
+    let mut body = vec![PROVISIONING_VERSION];
+    body.extend_from_slice(&iv);
+    body.extend_from_slice(&hazmat::cbc_encrypt(cipher_key, &iv, plaintext)?);
+    let mac = hazmat::hmac_sha256_digest(mac_key, &body);
+    body.extend_from_slice(&mac);
+    keys.zeroize();
+
+    Ok(proto::ProvisionEnvelope {
+        public_key: Some(public_key),
+        body: Some(body),
+    })
+}
+
+fn decrypt_envelope(
+    our_private_key: &libsignal_protocol::PrivateKey,
+    envelope: &proto::ProvisionEnvelope,
+) -> PyResult<Vec<u8>> {
+    let their_public_key = libsignal_protocol::PublicKey::deserialize(
+        envelope.public_key.as_deref().unwrap_or_default(),
+    )
+    .map_err(|_| provisioning_error("invalid public key"))?;
+    let body = envelope.body.as_deref().unwrap_or_default();
+    if body.len() < 1 + 2 * hazmat::AES_BLOCK_LENGTH + MAC_LENGTH {
+        return Err(provisioning_error("body is too short"));
+    }
+    if body[0] != PROVISIONING_VERSION {
+        return Err(provisioning_error(&format!(
+            "unsupported version {}",
+            body[0]
+        )));
+    }
+
+    let mut keys = derive_keys(our_private_key, &their_public_key)?;
+    let (cipher_key, mac_key) = keys.split_at(CIPHER_KEY_LENGTH);
+    let (authenticated, mac) = body.split_at(body.len() - MAC_LENGTH);
+    let mut verifier =
+        <Hmac<Sha256> as Mac>::new_from_slice(mac_key).expect("HMAC accepts keys of any length");
+    verifier.update(authenticated);
+    let result = match verifier.verify_slice(mac) {
+        Ok(()) => {
+            let (iv, ciphertext) = authenticated[1..].split_at(hazmat::AES_BLOCK_LENGTH);
+            hazmat::cbc_decrypt(cipher_key, iv, ciphertext)
+        }
+        Err(_) => Err(provisioning_error("MAC mismatch")),
+    };
+    keys.zeroize();
+    result
+}
+
+fn identity_key_pair(
+    public_key: &Option<Vec<u8>>,
+    private_key: &Option<Vec<u8>>,
+) -> PyResult<Option<IdentityKeyPair>> {
+    let (public_key, private_key) = match (public_key, private_key) {
+        (Some(public_key), Some(private_key)) => (public_key, private_key),
+        _ => return Ok(None),
+    };
+    let identity_key = libsignal_protocol::IdentityKey::decode(public_key)
+        .map_err(SignalProtocolError::new_err)?;
+    let private_key = libsignal_protocol::PrivateKey::deserialize(private_key)
+        .map_err(|err| SignalProtocolError::err_from_str(err.to_string()))?;
+    Ok(Some(IdentityKeyPair {
+        key: libsignal_protocol::IdentityKeyPair::new(identity_key, private_key),
+    }))
+}
+
+fn service_id(py: Python, uuid: &Option<String>, aci: bool) -> PyResult<Option<PyObject>> {
+    let uuid = match uuid {
+        Some(uuid) => Uuid::parse_str(uuid).map_err(|err| {
+            SignalProtocolError::err_from_str(format!("invalid service id {:?}: {}", uuid, err))
+        })?,
+        None => return Ok(None),
+    };
+    let service_id = if aci {
+        libsignal_core::Aci::from(uuid).into()
+    } else {
+        libsignal_core::Pni::from(uuid).into()
+    };
+    Ok(Some(ServiceId::new_py(py, service_id)?))
+}
+
+/// The account data a primary device sends to a device it links: the identity
+/// key pairs, the service ids, the phone number and the provisioning code to
+/// register the new device with.
+#[pyclass(frozen)]
+pub struct ProvisionMessage {
+    inner: proto::ProvisionMessage,
+}
+
+impl ProvisionMessage {
+    fn decode(data: &[u8]) -> PyResult<Self> {
+        let inner = proto::ProvisionMessage::decode(data).map_err(|err| {
+            SignalProtocolError::err_from_str(format!(
+                "failed to decode provision message: {}",
+                err
+            ))
+        })?;
+        Ok(ProvisionMessage { inner })
+    }
+}
+
+impl Drop for ProvisionMessage {
+    fn drop(&mut self) {
+        self.inner.aci_identity_key_private.zeroize();
+        self.inner.pni_identity_key_private.zeroize();
+        self.inner.master_key.zeroize();
+        self.inner.account_entropy_pool.zeroize();
+        self.inner.media_root_backup_key.zeroize();
+    }
+}
+
+#[pymethods]
+impl ProvisionMessage {
+    #[new]
+    #[pyo3(signature = (
+        aci_identity_key_pair,
+        pni_identity_key_pair,
+        aci,
+        pni,
+        number,
+        provisioning_code,
+        profile_key=None,
+        user_agent=None,
+        read_receipts=false,
+        provisioning_version=None,
+        master_key=None,
+        account_entropy_pool=None,
+        media_root_backup_key=None
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        aci_identity_key_pair: &IdentityKeyPair,
+        pni_identity_key_pair: &IdentityKeyPair,
+        aci: PyRef<ServiceId>,
+        pni: PyRef<ServiceId>,
+        number: String,
+        provisioning_code: String,
+        profile_key: Option<BytesLike>,
+        user_agent: Option<String>,
+        read_receipts: bool,
+        provisioning_version: Option<u32>,
+        master_key: Option<BytesLike>,
+        account_entropy_pool: Option<String>,
+        media_root_backup_key: Option<BytesLike>,
+    ) -> PyResult<Self> {
+        if !matches!(aci.inner, libsignal_core::ServiceId::Aci(_)) {
+            return Err(InvalidArgumentException::new_err("aci must be an Aci"));
+        }
+        if !matches!(pni.inner, libsignal_core::ServiceId::Pni(_)) {
+            return Err(InvalidArgumentException::new_err("pni must be a Pni"));
+        }
+        Ok(ProvisionMessage {
+            inner: proto::ProvisionMessage {
+                aci_identity_key_public: Some(
+                    aci_identity_key_pair
+                        .key
+                        .identity_key()
+                        .serialize()
+                        .to_vec(),
+                ),
+                aci_identity_key_private: Some(aci_identity_key_pair.key.private_key().serialize()),
+                number: Some(number),
+                provisioning_code: Some(provisioning_code),
+                user_agent,
+                profile_key: profile_key.map(|key| key.to_vec()),
+                read_receipts: Some(read_receipts),
+                aci: Some(aci.inner.raw_uuid().to_string()),
+                provisioning_version,
+                pni: Some(pni.inner.raw_uuid().to_string()),
+                pni_identity_key_public: Some(
+                    pni_identity_key_pair
+                        .key
+                        .identity_key()
+                        .serialize()
+                        .to_vec(),
+                ),
+                pni_identity_key_private: Some(pni_identity_key_pair.key.private_key().serialize()),
+                master_key: master_key.map(|key| key.to_vec()),
+                account_entropy_pool,
+                media_root_backup_key: media_root_backup_key.map(|key| key.to_vec()),
+            },
+        })
+    }
+
+    /// Serializes the message, including the private identity keys.
+    fn serialize<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        let mut data = self.inner.encode_to_vec();
+        let result = PyBytes::new(py, &data);
+        data.zeroize();
+        result
+    }
+
+    #[staticmethod]
+    fn deserialize(data: BytesLike) -> PyResult<Self> {
+        ProvisionMessage::decode(&data)
+    }
+
+    #[getter]
+    fn aci_identity_key_pair(&self) -> PyResult<Option<IdentityKeyPair>> {
+        identity_key_pair(
+            &self.inner.aci_identity_key_public,
+            &self.inner.aci_identity_key_private,
+        )
+    }
+
+    #[getter]
+    fn pni_identity_key_pair(&self) -> PyResult<Option<IdentityKeyPair>> {
+        identity_key_pair(
+            &self.inner.pni_identity_key_public,
+            &self.inner.pni_identity_key_private,
+        )
+    }
+
+    #[getter]
+    fn aci(&self, py: Python) -> PyResult<Option<PyObject>> {
+        service_id(py, &self.inner.aci, true)
+    }
+
+    #[getter]
+    fn pni(&self, py: Python) -> PyResult<Option<PyObject>> {
+        service_id(py, &self.inner.pni, false)
+    }
+
+    #[getter]
+    fn number(&self) -> Option<String> {
+        self.inner.number.clone()
+    }
+
+    #[getter]
+    fn provisioning_code(&self) -> Option<String> {
+        self.inner.provisioning_code.clone()
+    }
+
+    #[getter]
+    fn profile_key<'py>(&self, py: Python<'py>) -> Option<Bound<'py, PyBytes>> {
+        self.inner
+            .profile_key
+            .as_ref()
+            .map(|key| PyBytes::new(py, key))
+    }
+
+    #[getter]
+    fn user_agent(&self) -> Option<String> {
+        self.inner.user_agent.clone()
+    }
+
+    #[getter]
+    fn read_receipts(&self) -> bool {
+        self.inner.read_receipts.unwrap_or_default()
+    }
+
+    #[getter]
+    fn provisioning_version(&self) -> Option<u32> {
+        self.inner.provisioning_version
+    }
+
+    #[getter]
+    fn master_key<'py>(&self, py: Python<'py>) -> Option<Bound<'py, PyBytes>> {
+        self.inner
+            .master_key
+            .as_ref()
+            .map(|key| PyBytes::new(py, key))
+    }
+
+    #[getter]
+    fn account_entropy_pool(&self) -> Option<String> {
+        self.inner.account_entropy_pool.clone()
+    }
+
+    #[getter]
+    fn media_root_backup_key<'py>(&self, py: Python<'py>) -> Option<Bound<'py, PyBytes>> {
+        self.inner
+            .media_root_backup_key
+            .as_ref()
+            .map(|key| PyBytes::new(py, key))
+    }
+}
+
+/// Encrypts provision messages for device linking, in a ProvisionEnvelope.
+///
+/// The new device shows the public key of a fresh key pair (in the linking QR
+/// code). The primary device encrypts to it with an ephemeral X25519 key:
+/// HKDF-SHA256 of the agreement yields an AES-256-CBC key and an HMAC-SHA256
+/// key, and the envelope body is version (0x01) || IV || ciphertext || MAC.
+#[pyclass]
+pub struct ProvisioningCipher {}
+
+#[pymethods]
+impl ProvisioningCipher {
+    /// Returns the serialized ProvisionEnvelope.
+    #[staticmethod]
+    fn encrypt<'py>(
+        py: Python<'py>,
+        their_public_key: &PublicKey,
+        provision_message: &ProvisionMessage,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        let mut plaintext = provision_message.inner.encode_to_vec();
+        let envelope = encrypt_envelope(&their_public_key.key, &plaintext);
+        plaintext.zeroize();
+        Ok(PyBytes::new(py, &envelope?.encode_to_vec()))
+    }
+
+    /// Decrypts a serialized ProvisionEnvelope with the key pair whose public
+    /// key was given to the primary device. Raises SignalProtocolException if
+    /// the envelope was not encrypted to `our_key_pair` or was modified.
+    #[staticmethod]
+    fn decrypt(
+        our_key_pair: &KeyPair,
+        provision_envelope: BytesLike,
+    ) -> PyResult<ProvisionMessage> {
+        let envelope = proto::ProvisionEnvelope::decode(&provision_envelope[..])
+            .map_err(|err| provisioning_error(&err.to_string()))?;
+        let mut plaintext = decrypt_envelope(&our_key_pair.key.private_key, &envelope)?;
+        let message = ProvisionMessage::decode(&plaintext);
+        plaintext.zeroize();
+        message
+    }
+}
+
+pub fn init_submodule(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<ProvisionMessage>()?;
+    module.add_class::<ProvisioningCipher>()?;
+    Ok(())
+}
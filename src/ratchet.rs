@@ -81,7 +81,7 @@ pub fn initialize_alice_session(
     let mut csprng = OsRng;
     let state =
         libsignal_protocol::initialize_alice_session_record(&parameters.inner, &mut csprng)?;
-    Ok(SessionRecord { state })
+    Ok(SessionRecord::new(state))
 }
 
 #[pyclass]
@@ -163,7 +163,7 @@ impl BobSignalProtocolParameters {
 #[pyfunction]
 pub fn initialize_bob_session(parameters: &BobSignalProtocolParameters) -> Result<SessionRecord> {
     let state = libsignal_protocol::initialize_bob_session_record(&parameters.inner)?;
-    Ok(SessionRecord { state })
+    Ok(SessionRecord::new(state))
 }
 
 /// fn are_we_alice, ChainKey, RootKey, MessageKey are not exposed as part of the Python API.
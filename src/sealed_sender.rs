@@ -1,4 +1,5 @@
 use crate::address::ProtocolAddress;
+use crate::buffer::BytesLike;
 use crate::curve::{PrivateKey, PublicKey};
 use crate::error::{Result, SignalProtocolError};
 use crate::storage::InMemSignalProtocolStore;
@@ -21,9 +22,9 @@ pub struct ServerCertificate {
 #[pymethods]
 impl ServerCertificate {
     #[staticmethod]
-    fn deserialize(data: &[u8]) -> Result<Self> {
+    fn deserialize(data: BytesLike) -> Result<Self> {
         Ok(ServerCertificate {
-            data: libsignal_protocol::ServerCertificate::deserialize(data)?,
+            data: libsignal_protocol::ServerCertificate::deserialize(&data)?,
         })
     }
 
@@ -78,9 +79,9 @@ pub struct SenderCertificate {
 #[pymethods]
 impl SenderCertificate {
     #[staticmethod]
-    fn deserialize(data: &[u8]) -> Result<Self> {
+    fn deserialize(data: BytesLike) -> Result<Self> {
         Ok(SenderCertificate {
-            data: libsignal_protocol::SenderCertificate::deserialize(data)?,
+            data: libsignal_protocol::SenderCertificate::deserialize(&data)?,
         })
     }
 
@@ -166,14 +167,14 @@ pub struct UnidentifiedSenderMessageContent {
 #[pymethods]
 impl UnidentifiedSenderMessageContent {
     #[staticmethod]
-    fn deserialize(data: &[u8]) -> Result<Self> {
+    fn deserialize(data: BytesLike) -> Result<Self> {
         Ok(UnidentifiedSenderMessageContent {
-            data: libsignal_protocol::UnidentifiedSenderMessageContent::deserialize(data)?,
+            data: libsignal_protocol::UnidentifiedSenderMessageContent::deserialize(&data)?,
         })
     }
 
     #[new]
-    fn new(msg_type_value: u8, sender: SenderCertificate, contents: Vec<u8>, content_hint: u32, group_id: Vec<u8>) -> PyResult<Self> {
+    fn new(msg_type_value: u8, sender: SenderCertificate, contents: BytesLike, content_hint: u32, group_id: BytesLike) -> PyResult<Self> {
         let msg_enum = match msg_type_value {
             2 => libsignal_protocol::CiphertextMessageType::Whisper,
             3 => libsignal_protocol::CiphertextMessageType::PreKey,
@@ -188,9 +189,9 @@ impl UnidentifiedSenderMessageContent {
         match libsignal_protocol::UnidentifiedSenderMessageContent::new(
             msg_enum,
             sender.data,
-            contents,
+            contents.to_vec(),
             libsignal_protocol::ContentHint::from(content_hint),
-            Some(group_id)
+            Some(group_id.to_vec())
         ) {
             Ok(data) => Ok(Self { data }),
             Err(err) => Err(SignalProtocolError::new_err(err)),
@@ -245,27 +246,45 @@ impl SealedSenderDecryptionResult {
 #[pyfunction]
 #[pyo3(signature = (ciphertext, trust_root, timestamp, local_e164, local_uuid, local_device_id, protocol_store))]
 pub fn sealed_sender_decrypt(
-    ciphertext: &[u8],
+    py: Python,
+    ciphertext: BytesLike,
     trust_root: &PublicKey,
     timestamp: u64,
     local_e164: Option<String>,
     local_uuid: String,
     local_device_id: u32,
-    protocol_store: &mut InMemSignalProtocolStore,
+    protocol_store: &InMemSignalProtocolStore,
 ) -> PyResult<SealedSenderDecryptionResult> {
-    match block_on(libsignal_protocol::sealed_sender_decrypt(
-        ciphertext,
-        &trust_root.key,
-        Timestamp::from_epoch_millis(timestamp),
-        local_e164,
-        local_uuid,
-        local_device_id.into(),
-        &mut protocol_store.store.identity_store,
-        &mut protocol_store.store.session_store,
-        &mut protocol_store.store.pre_key_store,
-        &mut protocol_store.store.signed_pre_key_store,
-        &mut protocol_store.store.kyber_pre_key_store
-    )) {
+    let store = &protocol_store.store;
+    let result = py.allow_threads(|| {
+        // The sender is only known once the outer layer is decrypted, which is
+        // repeated by the upstream function under the sender's lock.
+        let usmc = block_on(libsignal_protocol::sealed_sender_decrypt_to_usmc(
+            &ciphertext,
+            &&store.identity_store,
+        ))?;
+        let sender = usmc.sender()?;
+        let sender_address = libsignal_protocol::ProtocolAddress::new(
+            sender.sender_uuid()?.to_string(),
+            sender.sender_device_id()?,
+        );
+        store.with_address_lock(&sender_address, || {
+            block_on(libsignal_protocol::sealed_sender_decrypt(
+                &ciphertext,
+                &trust_root.key,
+                Timestamp::from_epoch_millis(timestamp),
+                local_e164,
+                local_uuid,
+                local_device_id.into(),
+                &mut &store.identity_store,
+                &mut &store.session_store,
+                &mut &store.pre_key_store,
+                &mut &store.signed_pre_key_store,
+                &mut &store.kyber_pre_key_store,
+            ))
+        })
+    });
+    match result {
         Ok(data) => Ok(SealedSenderDecryptionResult { data }),
         Err(err) => Err(SignalProtocolError::new_err(err)),
     }
@@ -275,31 +294,36 @@ pub fn sealed_sender_decrypt(
 pub fn sealed_sender_encrypt(
     destination: &ProtocolAddress,
     sender_cert: &SenderCertificate,
-    ptext: &[u8],
-    protocol_store: &mut InMemSignalProtocolStore,
+    ptext: BytesLike,
+    protocol_store: &InMemSignalProtocolStore,
     py: Python,
 ) -> Result<PyObject> {
+    let store = &protocol_store.store;
     let mut csprng = OsRng;
-    let result = block_on(libsignal_protocol::sealed_sender_encrypt(
-        &destination.state,
-        &sender_cert.data,
-        ptext,
-        &mut protocol_store.store.session_store,
-        &mut protocol_store.store.identity_store,
-        SystemTime::now(),
-        &mut csprng,
-    ))?;
+    let result = py.allow_threads(|| {
+        store.with_address_lock(&destination.state, || {
+            block_on(libsignal_protocol::sealed_sender_encrypt(
+                &destination.state,
+                &sender_cert.data,
+                &ptext,
+                &mut &store.session_store,
+                &mut &store.identity_store,
+                SystemTime::now(),
+                &mut csprng,
+            ))
+        })
+    })?;
     Ok(PyBytes::new(py, &result).into())
 }
 
 #[pyfunction]
 pub fn sealed_sender_decrypt_to_usmc(
-    ciphertext: &[u8],
-    protocol_store: &mut InMemSignalProtocolStore,
+    ciphertext: BytesLike,
+    protocol_store: &InMemSignalProtocolStore,
 ) -> PyResult<UnidentifiedSenderMessageContent> {
     match block_on(libsignal_protocol::sealed_sender_decrypt_to_usmc(
-        ciphertext,
-        &mut protocol_store.store.identity_store,
+        &ciphertext,
+        &&protocol_store.store.identity_store,
     )) {
         Ok(data) => Ok(UnidentifiedSenderMessageContent { data }),
         Err(err) => Err(SignalProtocolError::new_err(err)),
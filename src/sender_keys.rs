@@ -1,9 +1,14 @@
 use pyo3::prelude::*;
-use pyo3::types::PyBytes;
+use pyo3::types::{PyBytes, PyDict, PyList};
 
+use prost::Message;
+
+use crate::buffer::BytesLike;
 use crate::error::{Result, SignalProtocolError};
+use crate::proto;
 
-#[pyclass]
+/// Immutable once created, so it can be shared between threads without locking.
+#[pyclass(frozen)]
 #[derive(Clone, Debug)]
 pub struct SenderKeyRecord {
     pub state: libsignal_protocol::SenderKeyRecord,
@@ -14,8 +19,8 @@ pub struct SenderKeyRecord {
 impl SenderKeyRecord {
 
     #[staticmethod]
-    pub fn deserialize(buf: &[u8]) -> PyResult<Self> {
-        match libsignal_protocol::SenderKeyRecord::deserialize(buf) {
+    pub fn deserialize(buf: BytesLike) -> PyResult<Self> {
+        match libsignal_protocol::SenderKeyRecord::deserialize(&buf) {
             Ok(state) => Ok(Self { state }),
             Err(err) => Err(SignalProtocolError::new_err(err)),
         }
@@ -25,6 +30,87 @@ impl SenderKeyRecord {
         let bytes = self.state.serialize()?;
         Ok(PyBytes::new(py, &bytes).into())
     }
+
+    /// Returns a dict describing the stored sender key states, newest first.
+    /// The top-level chain_id, iteration and signing_key are those of the newest
+    /// state, used for encrypting. Chain keys and the private signing key are
+    /// omitted unless `redact` is False.
+    #[pyo3(signature = (redact=true))]
+    pub fn describe(&self, py: Python, redact: bool) -> PyResult<PyObject> {
+        let bytes = self.state.serialize().map_err(SignalProtocolError::new_err)?;
+        let record = proto::SenderKeyRecordStructure::decode(&bytes[..]).map_err(|err| {
+            SignalProtocolError::err_from_str(format!("failed to decode sender key record: {}", err))
+        })?;
+
+        let result = PyDict::new(py);
+        let states = PyList::empty(py);
+        for state in &record.sender_key_states {
+            states.append(describe_state(py, state, redact)?)?;
+        }
+        match record.sender_key_states.first() {
+            Some(state) => {
+                result.set_item("chain_id", state.chain_id)?;
+                result.set_item("iteration", chain_iteration(state))?;
+                result.set_item("signing_key", signing_key_hex(state))?;
+            }
+            None => {
+                result.set_item("chain_id", py.None())?;
+                result.set_item("iteration", py.None())?;
+                result.set_item("signing_key", py.None())?;
+            }
+        }
+        result.set_item("state_count", record.sender_key_states.len())?;
+        result.set_item("states", states)?;
+        Ok(result.into())
+    }
+}
+
+fn chain_iteration(state: &proto::SenderKeyStateStructure) -> Option<u32> {
+    state.sender_chain_key.as_ref().map(|key| key.iteration)
+}
+
+fn signing_key_hex(state: &proto::SenderKeyStateStructure) -> Option<String> {
+    state
+        .sender_signing_key
+        .as_ref()
+        .map(|key| hex::encode(&key.public))
+}
+
+fn describe_state<'py>(
+    py: Python<'py>,
+    state: &proto::SenderKeyStateStructure,
+    redact: bool,
+) -> PyResult<Bound<'py, PyDict>> {
+    let result = PyDict::new(py);
+    result.set_item("message_version", state.message_version)?;
+    result.set_item("chain_id", state.chain_id)?;
+    result.set_item("iteration", chain_iteration(state))?;
+    result.set_item("signing_key", signing_key_hex(state))?;
+    result.set_item(
+        "has_signing_private_key",
+        state
+            .sender_signing_key
+            .as_ref()
+            .map_or(false, |key| !key.private.is_empty()),
+    )?;
+    result.set_item("skipped_message_key_count", state.sender_message_keys.len())?;
+    if !redact {
+        result.set_item(
+            "chain_key",
+            state
+                .sender_chain_key
+                .as_ref()
+                .map(|key| hex::encode(&key.seed)),
+        )?;
+        result.set_item(
+            "signing_private_key",
+            state
+                .sender_signing_key
+                .as_ref()
+                .map(|key| hex::encode(&key.private)),
+        )?;
+    }
+    Ok(result)
 }
 
 pub fn init_submodule(module: &Bound<'_, PyModule>) -> PyResult<()> {
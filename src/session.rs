@@ -31,19 +31,25 @@ use crate::storage::InMemSignalProtocolStore;
 
 #[pyfunction]
 pub fn process_prekey_bundle(
+    py: Python,
     remote_address: ProtocolAddress,
-    protocol_store: &mut InMemSignalProtocolStore,
+    protocol_store: &InMemSignalProtocolStore,
     bundle: PreKeyBundle,
 ) -> Result<()> {
+    let store = &protocol_store.store;
     let mut csprng = OsRng;
-    block_on(libsignal_protocol::process_prekey_bundle(
-        &remote_address.state,
-        &mut protocol_store.store.session_store,
-        &mut protocol_store.store.identity_store,
-        &bundle.state,
-        SystemTime::now(),
-        &mut csprng,
-    ))?;
+    py.allow_threads(|| {
+        store.with_address_lock(&remote_address.state, || {
+            block_on(libsignal_protocol::process_prekey_bundle(
+                &remote_address.state,
+                &mut &store.session_store,
+                &mut &store.identity_store,
+                &bundle.state,
+                SystemTime::now(),
+                &mut csprng,
+            ))
+        })
+    })?;
     Ok(())
 }
 
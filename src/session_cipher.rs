@@ -1,96 +1,324 @@
+//! Encrypting and decrypting 1:1 messages.
+//!
+//! The ratchet limits (`MAX_FORWARD_JUMPS`, `MAX_MESSAGE_KEYS`, ...) are constants of
+//! the upstream crate at the pinned revision and cannot be raised from here. A
+//! `DecryptionPolicy` can only make them stricter.
+
+use pyo3::exceptions;
 use pyo3::prelude::*;
 use pyo3::types::PyBytes;
 use pyo3::wrap_pyfunction;
 
 use futures::executor::block_on;
+use prost::Message;
 use rand::rngs::OsRng;
 use std::time::SystemTime;
 
 use crate::address::ProtocolAddress;
-use crate::error::Result;
+use crate::buffer::{bytes_or_write, BytesLike, WritableBuffer};
+use crate::consts;
+use crate::error::{InvalidArgumentException, SignalProtocolError};
+use crate::proto;
 use crate::protocol::{CiphertextMessage, PreKeySignalMessage, SignalMessage};
-use crate::storage::InMemSignalProtocolStore;
+use crate::state::decode_session_record;
+use crate::transaction::{ProtocolStoreArg, Stores};
+
+use libsignal_protocol::SessionStore;
+
+/// Limits applied to incoming messages before they are handed to the upstream crate.
+///
+/// The upstream limits (MAX_FORWARD_JUMPS etc. in this module) are compiled into the
+/// upstream revision we depend on and cannot be raised, so a policy can only be
+/// stricter than the defaults.
+#[pyclass]
+#[derive(Clone, Debug)]
+pub struct DecryptionPolicy {
+    #[pyo3(get)]
+    pub max_forward_jump: u32,
+}
+
+#[pymethods]
+impl DecryptionPolicy {
+    #[new]
+    #[pyo3(signature = (max_forward_jump=consts::MAX_FORWARD_JUMPS))]
+    fn new(max_forward_jump: u32) -> PyResult<Self> {
+        if max_forward_jump > consts::MAX_FORWARD_JUMPS {
+            return Err(exceptions::PyValueError::new_err(format!(
+                "max_forward_jump {} exceeds the upstream limit of {}",
+                max_forward_jump,
+                consts::MAX_FORWARD_JUMPS
+            )));
+        }
+        Ok(DecryptionPolicy { max_forward_jump })
+    }
+
+    fn __repr__(&self) -> String {
+        format!("DecryptionPolicy(max_forward_jump={})", self.max_forward_jump)
+    }
+}
 
+impl DecryptionPolicy {
+    /// Rejects `message` if its counter is further ahead of the matching receiver
+    /// chain than the policy allows. Messages starting a new chain are measured
+    /// from index 0.
+    fn check(
+        &self,
+        session_store: &dyn SessionStore,
+        remote_address: &libsignal_protocol::ProtocolAddress,
+        message: &libsignal_protocol::SignalMessage,
+    ) -> PyResult<()> {
+        let record = block_on(session_store.load_session(remote_address))
+            .map_err(SignalProtocolError::new_err)?;
+        let ratchet_key = message.sender_ratchet_key().serialize();
+
+        let mut chain_index = 0;
+        if let Some(record) = record {
+            let record = decode_session_record(&record)?;
+            let mut sessions = Vec::new();
+            if let Some(session) = record.current_session {
+                sessions.push(session);
+            }
+            for bytes in &record.previous_sessions {
+                if let Ok(session) = proto::SessionStructure::decode(&bytes[..]) {
+                    sessions.push(session);
+                }
+            }
+            let chain = sessions
+                .iter()
+                .flat_map(|session| session.receiver_chains.iter())
+                .find(|chain| chain.sender_ratchet_key[..] == ratchet_key[..]);
+            if let Some(chain_key) = chain.and_then(|chain| chain.chain_key.as_ref()) {
+                chain_index = chain_key.index;
+            }
+        }
+
+        let jump = message.counter().saturating_sub(chain_index);
+        if jump > self.max_forward_jump {
+            return Err(SignalProtocolError::err_from_str(format!(
+                "message counter {} is {} ahead of the receiver chain (policy allows {})",
+                message.counter(),
+                jump,
+                self.max_forward_jump
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Returns a CiphertextMessage, or with `out` writes the serialized message to
+/// it and returns the number of bytes written and the message type, which is
+/// needed to decrypt it (see CiphertextMessage.message_type).
 #[pyfunction]
+#[pyo3(signature = (protocol_store, remote_address, msg, out=None))]
 pub fn message_encrypt(
-    protocol_store: &mut InMemSignalProtocolStore,
+    py: Python,
+    mut protocol_store: ProtocolStoreArg,
     remote_address: &ProtocolAddress,
-    msg: &[u8],
-) -> Result<CiphertextMessage> {
-    let ciphertext = block_on(libsignal_protocol::message_encrypt(
-        msg,
-        &remote_address.state,
-        &mut protocol_store.store.session_store,
-        &mut protocol_store.store.identity_store,
-        SystemTime::now(),
-    ))?;
-    Ok(CiphertextMessage::new(ciphertext))
+    msg: BytesLike,
+    out: Option<WritableBuffer>,
+) -> PyResult<PyObject> {
+    let ciphertext = protocol_store.with_stores(&remote_address.state, |stores| {
+        block_on(libsignal_protocol::message_encrypt(
+            &msg,
+            &remote_address.state,
+            stores.session_store,
+            stores.identity_store,
+            SystemTime::now(),
+        ))
+        .map_err(SignalProtocolError::new_err)
+    })?;
+    match out {
+        Some(out) => {
+            let written = out.write(ciphertext.serialize())?;
+            let message_type = ciphertext.message_type() as u8;
+            Ok((written, message_type).into_pyobject(py)?.into_any().unbind())
+        }
+        None => Ok(Py::new(py, CiphertextMessage::new(ciphertext))?.into_any()),
+    }
 }
 
+/// Returns the plaintext, or with `out` writes it to `out` and returns the
+/// number of bytes written.
 #[pyfunction]
+#[pyo3(signature = (protocol_store, remote_address, msg, policy=None, out=None))]
 pub fn message_decrypt(
     py: Python,
-    protocol_store: &mut InMemSignalProtocolStore,
+    mut protocol_store: ProtocolStoreArg,
     remote_address: &ProtocolAddress,
     msg: &CiphertextMessage,
-) -> Result<PyObject> {
+    policy: Option<PyRef<DecryptionPolicy>>,
+    out: Option<WritableBuffer>,
+) -> PyResult<PyObject> {
+    let policy = policy.as_deref().cloned();
+    let plaintext = protocol_store.with_stores(&remote_address.state, |stores| {
+        decrypt(stores, &remote_address.state, &msg.data, policy.as_ref())
+    })?;
+    bytes_or_write(py, &plaintext, out)
+}
+
+// Called with the lock for `remote_address` held.
+fn decrypt(
+    stores: Stores<'_>,
+    remote_address: &libsignal_protocol::ProtocolAddress,
+    msg: &libsignal_protocol::CiphertextMessage,
+    policy: Option<&DecryptionPolicy>,
+) -> PyResult<Vec<u8>> {
+    if let Some(policy) = policy {
+        match msg {
+            libsignal_protocol::CiphertextMessage::SignalMessage(message) => {
+                policy.check(stores.session_store, remote_address, message)?
+            }
+            libsignal_protocol::CiphertextMessage::PreKeySignalMessage(message) => {
+                policy.check(stores.session_store, remote_address, message.message())?
+            }
+            _ => {}
+        }
+    }
     let mut csprng = OsRng;
-    let plaintext = block_on(libsignal_protocol::message_decrypt(
-        &msg.data,
-        &remote_address.state,
-        &mut protocol_store.store.session_store,
-        &mut protocol_store.store.identity_store,
-        &mut protocol_store.store.pre_key_store,
-        &mut protocol_store.store.signed_pre_key_store,
-        &mut protocol_store.store.kyber_pre_key_store,
+    block_on(libsignal_protocol::message_decrypt(
+        msg,
+        remote_address,
+        stores.session_store,
+        stores.identity_store,
+        stores.pre_key_store,
+        stores.signed_pre_key_store,
+        stores.kyber_pre_key_store,
         &mut csprng,
-    ))?;
-    Ok(PyBytes::new(py, &plaintext).into())
+    ))
+    .map_err(SignalProtocolError::new_err)
 }
 
+// Message types as returned by CiphertextMessage.message_type().
+fn parse_message(message_type: u8, data: &[u8]) -> PyResult<libsignal_protocol::CiphertextMessage> {
+    let message = match message_type {
+        2 => libsignal_protocol::SignalMessage::try_from(data)
+            .map(libsignal_protocol::CiphertextMessage::SignalMessage),
+        3 => libsignal_protocol::PreKeySignalMessage::try_from(data)
+            .map(libsignal_protocol::CiphertextMessage::PreKeySignalMessage),
+        _ => {
+            return Err(InvalidArgumentException::new_err(format!(
+                "unsupported message type {}",
+                message_type
+            )))
+        }
+    };
+    message.map_err(SignalProtocolError::new_err)
+}
+
+/// Decrypts a list of (address, message type, serialized message) tuples in
+/// order and with a single release of the GIL. The message types are those of
+/// CiphertextMessage.message_type(): 2 for a SignalMessage and 3 for a
+/// PreKeySignalMessage.
+///
+/// Returns a list with the plaintext of each message, or the exception raised
+/// decrypting it: a failed message does not stop the batch.
 #[pyfunction]
+#[pyo3(signature = (protocol_store, messages, policy=None))]
+pub fn decrypt_batch(
+    py: Python,
+    mut protocol_store: ProtocolStoreArg,
+    messages: Vec<(PyRef<ProtocolAddress>, u8, BytesLike)>,
+    policy: Option<PyRef<DecryptionPolicy>>,
+) -> PyResult<Vec<PyObject>> {
+    let policy = policy.as_deref().cloned();
+    let messages: Vec<_> = messages
+        .into_iter()
+        .map(|(address, message_type, data)| (address.state.clone(), message_type, data))
+        .collect();
+    let results = protocol_store.without_gil(|access| {
+        Ok(messages
+            .iter()
+            .map(|(address, message_type, data)| -> PyResult<Vec<u8>> {
+                let msg = parse_message(*message_type, data)?;
+                access.with_address(address, |stores| {
+                    decrypt(stores, address, &msg, policy.as_ref())
+                })
+            })
+            .collect::<Vec<_>>())
+    })?;
+    Ok(results
+        .into_iter()
+        .map(|result| match result {
+            Ok(plaintext) => PyBytes::new(py, &plaintext).into_any().unbind(),
+            Err(err) => err.into_value(py).into_any(),
+        })
+        .collect())
+}
+
+#[pyfunction]
+#[pyo3(signature = (protocol_store, remote_address, msg, policy=None, out=None))]
 pub fn message_decrypt_prekey(
     py: Python,
-    protocol_store: &mut InMemSignalProtocolStore,
+    mut protocol_store: ProtocolStoreArg,
     remote_address: &ProtocolAddress,
     msg: &PreKeySignalMessage,
-) -> Result<PyObject> {
-    let mut csprng = OsRng;
-    let plaintext = block_on(libsignal_protocol::message_decrypt_prekey(
-        &msg.data,
-        &remote_address.state,
-        &mut protocol_store.store.session_store,
-        &mut protocol_store.store.identity_store,
-        &mut protocol_store.store.pre_key_store,
-        &mut protocol_store.store.signed_pre_key_store,
-        &mut protocol_store.store.kyber_pre_key_store,
-        &mut csprng,
-    ))?;
-    Ok(PyBytes::new(py, &plaintext).into())
+    policy: Option<PyRef<DecryptionPolicy>>,
+    out: Option<WritableBuffer>,
+) -> PyResult<PyObject> {
+    let policy = policy.as_deref().cloned();
+    let plaintext = protocol_store.with_stores(&remote_address.state, |stores| {
+        if let Some(policy) = policy {
+            policy.check(
+                stores.session_store,
+                &remote_address.state,
+                msg.data.message(),
+            )?;
+        }
+        let mut csprng = OsRng;
+        block_on(libsignal_protocol::message_decrypt_prekey(
+            &msg.data,
+            &remote_address.state,
+            stores.session_store,
+            stores.identity_store,
+            stores.pre_key_store,
+            stores.signed_pre_key_store,
+            stores.kyber_pre_key_store,
+            &mut csprng,
+        ))
+        .map_err(SignalProtocolError::new_err)
+    })?;
+    bytes_or_write(py, &plaintext, out)
 }
 
 #[pyfunction]
+#[pyo3(signature = (protocol_store, remote_address, msg, policy=None, out=None))]
 pub fn message_decrypt_signal(
     py: Python,
-    protocol_store: &mut InMemSignalProtocolStore,
+    mut protocol_store: ProtocolStoreArg,
     remote_address: &ProtocolAddress,
     msg: &SignalMessage,
-) -> Result<PyObject> {
-    let mut csprng = OsRng;
-    let plaintext = block_on(libsignal_protocol::message_decrypt_signal(
-        &msg.data,
-        &remote_address.state,
-        &mut protocol_store.store.session_store,
-        &mut protocol_store.store.identity_store,
-        &mut csprng,
-    ))?;
-    Ok(PyBytes::new(py, &plaintext).into())
+    policy: Option<PyRef<DecryptionPolicy>>,
+    out: Option<WritableBuffer>,
+) -> PyResult<PyObject> {
+    let policy = policy.as_deref().cloned();
+    let plaintext = protocol_store.with_stores(&remote_address.state, |stores| {
+        if let Some(policy) = policy {
+            policy.check(stores.session_store, &remote_address.state, &msg.data)?;
+        }
+        let mut csprng = OsRng;
+        block_on(libsignal_protocol::message_decrypt_signal(
+            &msg.data,
+            &remote_address.state,
+            stores.session_store,
+            stores.identity_store,
+            &mut csprng,
+        ))
+        .map_err(SignalProtocolError::new_err)
+    })?;
+    bytes_or_write(py, &plaintext, out)
 }
 
+/// The upstream limits are exposed as module constants.
 pub fn init_submodule(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add("MAX_FORWARD_JUMPS", consts::MAX_FORWARD_JUMPS)?;
+    module.add("MAX_MESSAGE_KEYS", consts::MAX_MESSAGE_KEYS)?;
+    module.add("MAX_RECEIVER_CHAINS", consts::MAX_RECEIVER_CHAINS)?;
+    module.add("ARCHIVED_STATES_MAX_LENGTH", consts::ARCHIVED_STATES_MAX_LENGTH)?;
+    module.add_class::<DecryptionPolicy>()?;
     module.add_wrapped(wrap_pyfunction!(message_encrypt))?;
     module.add_wrapped(wrap_pyfunction!(message_decrypt))?;
     module.add_wrapped(wrap_pyfunction!(message_decrypt_prekey))?;
     module.add_wrapped(wrap_pyfunction!(message_decrypt_signal))?;
+    module.add_wrapped(wrap_pyfunction!(decrypt_batch))?;
     Ok(())
 }
@@ -1,17 +1,25 @@
 use pyo3::prelude::*;
-use pyo3::types::PyBytes;
+use pyo3::types::{PyBytes, PyDict, PyList};
 use pyo3::wrap_pyfunction;
 
+use prost::Message;
+use sha2::{Digest, Sha256};
+
+use std::sync::{Mutex, MutexGuard};
 use std::time::SystemTime;
+use crate::buffer::BytesLike;
 use crate::curve::{KeyPair, PrivateKey, PublicKey};
-use crate::error::{Result, SignalProtocolError};
+use crate::error::{InvalidArgumentException, Result, SignalProtocolError};
 use crate::identity_key::IdentityKey;
+use crate::proto;
+use crate::storage::lock;
 
 use libsignal_protocol::GenericSignedPreKey;
 use libsignal_protocol::Timestamp;
 // Newtypes from upstream crate not exposed as part of the public API
 pub type SignedPreKeyId = u32;
 pub type PreKeyId = u32;
+pub type KyberPreKeyId = u32;
 
 
 
@@ -31,7 +39,7 @@ impl PreKeyBundle {
         pre_key: Option<(PreKeyId, PublicKey)>,
         signed_pre_key_id: SignedPreKeyId,
         signed_pre_key_public: PublicKey,
-        signed_pre_key_signature: Vec<u8>,
+        signed_pre_key_signature: BytesLike,
         identity_key: IdentityKey,
     ) -> PyResult<Self> {
         let pre_key: Option<(libsignal_protocol::PreKeyId, libsignal_protocol::PublicKey)> = match pre_key
@@ -48,7 +56,7 @@ impl PreKeyBundle {
             pre_key,
             signed_pre_key_id.into(),
             signed_pre_key.into(),
-            signed_pre_key_signature,
+            signed_pre_key_signature.to_vec(),
             identity_key_direct,
         ) {
             Ok(state) => Ok(PreKeyBundle { state }),
@@ -107,7 +115,7 @@ pub struct PreKeyRecord {
 #[pymethods]
 impl PreKeyRecord {
     #[new]
-    fn new(id: PreKeyId, keypair: &KeyPair) -> Self {
+    pub fn new(id: PreKeyId, keypair: &KeyPair) -> Self {
         let key =
             libsignal_protocol::KeyPair::new(keypair.key.public_key, keypair.key.private_key);
         PreKeyRecord {
@@ -116,8 +124,8 @@ impl PreKeyRecord {
     }
 
     #[staticmethod]
-    fn deserialize(data: &[u8]) -> PyResult<Self> {
-        match libsignal_protocol::PreKeyRecord::deserialize(data) {
+    fn deserialize(data: BytesLike) -> PyResult<Self> {
+        match libsignal_protocol::PreKeyRecord::deserialize(&data) {
             Ok(state) => Ok(PreKeyRecord { state }),
             Err(err) => Err(SignalProtocolError::new_err(err)),
         }
@@ -161,17 +169,23 @@ impl PreKeyRecord {
 /// manykeys = state.generate_n_prekeys(100, prekeyid)  # generates 100 keys
 /// ```
 #[pyfunction]
-pub fn generate_n_prekeys(n: u16, id: PreKeyId) -> Vec<PreKeyRecord> {
+pub fn generate_n_prekeys(n: u16, id: PreKeyId) -> PyResult<Vec<PreKeyRecord>> {
+    if n > 0 && id.checked_add(u32::from(n) - 1).is_none() {
+        return Err(InvalidArgumentException::new_err(format!(
+            "prekey ids starting at {} overflow for {} prekeys",
+            id, n
+        )));
+    }
     let mut keyvec: Vec<PreKeyRecord> = Vec::new();
     let mut i: u32 = id;
     for _n in 0..n {
         let keypair = KeyPair::generate();
         let prekey = PreKeyRecord::new(i, &keypair);
         keyvec.push(prekey);
-        i += 1;
+        i = i.wrapping_add(1);
     }
 
-    keyvec
+    Ok(keyvec)
 }
 
 #[pyclass]
@@ -183,7 +197,7 @@ pub struct SignedPreKeyRecord {
 #[pymethods]
 impl SignedPreKeyRecord {
     #[new]
-    fn new(id: SignedPreKeyId, timestamp: u64, keypair: &KeyPair, signature: &[u8]) -> Self {
+    fn new(id: SignedPreKeyId, timestamp: u64, keypair: &KeyPair, signature: BytesLike) -> Self {
         let key =
             libsignal_protocol::KeyPair::new(keypair.key.public_key, keypair.key.private_key);
         SignedPreKeyRecord {
@@ -194,8 +208,8 @@ impl SignedPreKeyRecord {
     }
 
     #[staticmethod]
-    fn deserialize(data: &[u8]) -> PyResult<Self> {
-        match libsignal_protocol::SignedPreKeyRecord::deserialize(data) {
+    fn deserialize(data: BytesLike) -> PyResult<Self> {
+        match libsignal_protocol::SignedPreKeyRecord::deserialize(&data) {
             Ok(state) => Ok(SignedPreKeyRecord { state }),
             Err(err) => Err(SignalProtocolError::new_err(err)),
         }
@@ -238,10 +252,78 @@ impl SignedPreKeyRecord {
     }
 }
 
+/// A signed Kyber (ML-KEM) prekey. Only Kyber1024 keys are generated, as by the
+/// official clients.
 #[pyclass]
 #[derive(Clone)]
+pub struct KyberPreKeyRecord {
+    pub state: libsignal_protocol::KyberPreKeyRecord,
+}
+
+#[pymethods]
+impl KyberPreKeyRecord {
+    /// Generates a Kyber1024 key pair, signed with `signing_key` (the identity key).
+    #[staticmethod]
+    pub fn generate(id: KyberPreKeyId, signing_key: &PrivateKey) -> Result<Self> {
+        Ok(KyberPreKeyRecord {
+            state: libsignal_protocol::KyberPreKeyRecord::generate(
+                libsignal_protocol::kem::KeyType::Kyber1024,
+                id.into(),
+                &signing_key.key,
+            )?,
+        })
+    }
+
+    #[staticmethod]
+    fn deserialize(data: BytesLike) -> PyResult<Self> {
+        match libsignal_protocol::KyberPreKeyRecord::deserialize(&data) {
+            Ok(state) => Ok(KyberPreKeyRecord { state }),
+            Err(err) => Err(SignalProtocolError::new_err(err)),
+        }
+    }
+
+    pub fn id(&self) -> Result<KyberPreKeyId> {
+        Ok(self.state.id()?.into())
+    }
+
+    fn timestamp(&self) -> Result<u64> {
+        Ok(self.state.timestamp()?.epoch_millis())
+    }
+
+    /// The serialized KEM public key, as covered by the signature.
+    pub fn public_key(&self, py: Python) -> Result<PyObject> {
+        let key = self.state.public_key()?.serialize();
+        Ok(PyBytes::new(py, &key).into())
+    }
+
+    pub fn signature(&self, py: Python) -> Result<PyObject> {
+        let sig = self.state.signature()?;
+        Ok(PyBytes::new(py, &sig).into())
+    }
+
+    fn serialize(&self, py: Python) -> Result<PyObject> {
+        let result = self.state.serialize()?;
+        Ok(PyBytes::new(py, &result).into())
+    }
+}
+
+/// The record is locked by every method, so a SessionRecord can be shared
+/// between threads.
+#[pyclass(frozen)]
 pub struct SessionRecord {
-    pub state: libsignal_protocol::SessionRecord,
+    state: Mutex<libsignal_protocol::SessionRecord>,
+}
+
+impl SessionRecord {
+    pub fn new(state: libsignal_protocol::SessionRecord) -> Self {
+        SessionRecord {
+            state: Mutex::new(state),
+        }
+    }
+
+    pub fn state(&self) -> MutexGuard<'_, libsignal_protocol::SessionRecord> {
+        lock(&self.state)
+    }
 }
 
 /// session_state_mut() is not exposed as part of the Python API.
@@ -249,48 +331,46 @@ pub struct SessionRecord {
 impl SessionRecord {
     #[staticmethod]
     pub fn new_fresh() -> Self {
-        SessionRecord {
-            state: libsignal_protocol::SessionRecord::new_fresh(),
-        }
+        SessionRecord::new(libsignal_protocol::SessionRecord::new_fresh())
     }
 
     #[staticmethod]
-    fn deserialize(bytes: &[u8]) -> PyResult<Self> {
-        match libsignal_protocol::SessionRecord::deserialize(bytes) {
-            Ok(state) => Ok(SessionRecord { state }),
+    fn deserialize(bytes: BytesLike) -> PyResult<Self> {
+        match libsignal_protocol::SessionRecord::deserialize(&bytes) {
+            Ok(state) => Ok(SessionRecord::new(state)),
             Err(err) => Err(SignalProtocolError::new_err(err)),
         }
     }
 
-    fn archive_current_state(&mut self) -> Result<()> {
-        self.state.archive_current_state()?;
+    fn archive_current_state(&self) -> Result<()> {
+        self.state().archive_current_state()?;
         Ok(())
     }
 
     fn serialize(&self, py: Python) -> Result<PyObject> {
-        let result = self.state.serialize()?;
+        let result = self.state().serialize()?;
         Ok(PyBytes::new(py, &result).into())
     }
 
     fn session_version(&self) -> Result<u32> {
-        Ok(self.state.session_version()?)
+        Ok(self.state().session_version()?)
     }
 
     fn remote_registration_id(&self) -> Result<u32> {
-        Ok(self.state.remote_registration_id()?)
+        Ok(self.state().remote_registration_id()?)
     }
 
     fn local_registration_id(&self) -> Result<u32> {
-        Ok(self.state.local_registration_id()?)
+        Ok(self.state().local_registration_id()?)
     }
 
     fn local_identity_key_bytes(&self, py: Python) -> Result<PyObject> {
-        let result = self.state.local_identity_key_bytes()?;
+        let result = self.state().local_identity_key_bytes()?;
         Ok(PyBytes::new(py, &result).into())
     }
 
     fn remote_identity_key_bytes(&self, py: Python) -> Result<Option<PyObject>> {
-        match self.state.remote_identity_key_bytes()? {
+        match self.state().remote_identity_key_bytes()? {
             Some(result) => Ok(Some(PyBytes::new(py, &result).into())),
             None => Ok(None),
         }
@@ -302,29 +382,167 @@ impl SessionRecord {
         sender: &PublicKey,
         py: Python,
     ) -> Result<Option<PyObject>> {
-        match self.state.get_receiver_chain_key_bytes(&sender.key)? {
+        match self.state().get_receiver_chain_key_bytes(&sender.key)? {
             Some(result) => Ok(Some(PyBytes::new(py, &result[..]).into())),
             None => Ok(None),
         }
     }
 
     fn has_sender_chain(&self) -> Result<bool> {
-        Ok(self.state.has_usable_sender_chain(SystemTime::now())?)
+        Ok(self.state().has_usable_sender_chain(SystemTime::now())?)
     }
 
     fn alice_base_key(&self, py: Python) -> Result<PyObject> {
-        let result = self.state.alice_base_key()?;
+        let result = self.state().alice_base_key()?;
         Ok(PyBytes::new(py, &result).into())
     }
 
     fn get_sender_chain_key_bytes(&self, py: Python) -> Result<PyObject> {
-        let result = self.state.get_sender_chain_key_bytes()?;
+        let result = self.state().get_sender_chain_key_bytes()?;
         Ok(PyBytes::new(py, &result).into())
     }
+
+    /// Returns the number of skipped message keys held for each receiver chain of
+    /// the current session, keyed by the serialized ratchet public key of the chain.
+    fn skipped_message_key_counts(&self, py: Python) -> PyResult<PyObject> {
+        let record = decode_session_record(&self.state())?;
+        let result = PyDict::new(py);
+        if let Some(session) = &record.current_session {
+            for chain in &session.receiver_chains {
+                result.set_item(
+                    PyBytes::new(py, &chain.sender_ratchet_key),
+                    chain.message_keys.len(),
+                )?;
+            }
+        }
+        Ok(result.into())
+    }
+
+    /// Returns a dict describing the current and archived session states, for
+    /// diagnosing desynchronized sessions. Root, chain and message keys and the
+    /// private half of the sender ratchet key are omitted unless `redact` is False.
+    #[pyo3(signature = (redact=true))]
+    fn describe(&self, py: Python, redact: bool) -> PyResult<PyObject> {
+        let record = decode_session_record(&self.state())?;
+
+        let result = PyDict::new(py);
+        match &record.current_session {
+            Some(session) => result.set_item("current_session", describe_session(py, session, redact)?)?,
+            None => result.set_item("current_session", py.None())?,
+        }
+
+        let archived = PyList::empty(py);
+        for bytes in &record.previous_sessions {
+            let session = proto::SessionStructure::decode(&bytes[..]).map_err(|err| {
+                SignalProtocolError::err_from_str(format!("failed to decode archived session: {}", err))
+            })?;
+            archived.append(describe_session(py, &session, redact)?)?;
+        }
+        result.set_item("archived_state_count", record.previous_sessions.len())?;
+        result.set_item("archived_sessions", archived)?;
+        Ok(result.into())
+    }
+}
+
+pub(crate) fn decode_session_record(
+    record: &libsignal_protocol::SessionRecord,
+) -> PyResult<proto::RecordStructure> {
+    let bytes = record.serialize().map_err(SignalProtocolError::new_err)?;
+    proto::RecordStructure::decode(&bytes[..]).map_err(|err| {
+        SignalProtocolError::err_from_str(format!("failed to decode session record: {}", err))
+    })
+}
+
+fn key_fingerprint(key: &[u8]) -> String {
+    hex::encode(Sha256::digest(key))
+}
+
+fn describe_session<'py>(
+    py: Python<'py>,
+    session: &proto::SessionStructure,
+    redact: bool,
+) -> PyResult<Bound<'py, PyDict>> {
+    let result = PyDict::new(py);
+    result.set_item("session_version", session.session_version)?;
+    result.set_item("local_registration_id", session.local_registration_id)?;
+    result.set_item("remote_registration_id", session.remote_registration_id)?;
+    result.set_item(
+        "local_identity_fingerprint",
+        key_fingerprint(&session.local_identity_public),
+    )?;
+    result.set_item(
+        "remote_identity_fingerprint",
+        key_fingerprint(&session.remote_identity_public),
+    )?;
+    result.set_item("alice_base_key", hex::encode(&session.alice_base_key))?;
+    result.set_item("previous_counter", session.previous_counter)?;
+    result.set_item("needs_refresh", session.needs_refresh)?;
+    if !redact {
+        result.set_item("root_key", hex::encode(&session.root_key))?;
+    }
+
+    match &session.sender_chain {
+        Some(chain) => result.set_item("sender_chain", describe_chain(py, chain, redact)?)?,
+        None => result.set_item("sender_chain", py.None())?,
+    }
+
+    let receiver_chains = PyList::empty(py);
+    for chain in &session.receiver_chains {
+        receiver_chains.append(describe_chain(py, chain, redact)?)?;
+    }
+    result.set_item("receiver_chain_count", session.receiver_chains.len())?;
+    result.set_item("receiver_chains", receiver_chains)?;
+
+    match &session.pending_pre_key {
+        Some(pending) => {
+            let item = PyDict::new(py);
+            item.set_item("pre_key_id", pending.pre_key_id)?;
+            item.set_item("signed_pre_key_id", pending.signed_pre_key_id)?;
+            item.set_item("base_key", hex::encode(&pending.base_key))?;
+            item.set_item("timestamp", pending.timestamp)?;
+            result.set_item("pending_pre_key", item)?;
+        }
+        None => result.set_item("pending_pre_key", py.None())?,
+    }
+
+    match &session.pending_kyber_pre_key {
+        Some(pending) => {
+            let item = PyDict::new(py);
+            item.set_item("pre_key_id", pending.pre_key_id)?;
+            result.set_item("pending_kyber_pre_key", item)?;
+        }
+        None => result.set_item("pending_kyber_pre_key", py.None())?,
+    }
+    Ok(result)
+}
+
+fn describe_chain<'py>(
+    py: Python<'py>,
+    chain: &proto::session_structure::Chain,
+    redact: bool,
+) -> PyResult<Bound<'py, PyDict>> {
+    let result = PyDict::new(py);
+    result.set_item("ratchet_key", hex::encode(&chain.sender_ratchet_key))?;
+    result.set_item("counter", chain.chain_key.as_ref().map(|key| key.index))?;
+    result.set_item("skipped_message_key_count", chain.message_keys.len())?;
+    if !redact {
+        result.set_item(
+            "ratchet_private_key",
+            hex::encode(&chain.sender_ratchet_key_private),
+        )?;
+        result.set_item(
+            "chain_key",
+            chain.chain_key.as_ref().map(|key| hex::encode(&key.key)),
+        )?;
+        let indices: Vec<u32> = chain.message_keys.iter().map(|key| key.index).collect();
+        result.set_item("skipped_message_key_indices", indices)?;
+    }
+    Ok(result)
 }
 
 /// UnacknowledgedPreKeyMessageItems is not exposed as part of the upstream public API.
 pub fn init_submodule(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<KyberPreKeyRecord>()?;
     module.add_class::<PreKeyBundle>()?;
     module.add_class::<PreKeyRecord>()?;
     module.add_class::<SessionRecord>()?;
@@ -1,144 +1,697 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use async_trait::async_trait;
 use futures::executor::block_on;
+use prost::Message;
 use pyo3::prelude::*;
+use pyo3::types::PyBytes;
 
 use uuid::Uuid;
+use zeroize::Zeroize;
 
 use crate::address::ProtocolAddress;
+use crate::buffer::BytesLike;
+use crate::curve::zeroize_key;
+use crate::distribution_id::DistributionId;
 use crate::error::{Result, SignalProtocolError};
-use crate::identity_key::{IdentityKey, IdentityKeyPair};
+use crate::identity_key::{generate_registration_id, IdentityKey, IdentityKeyPair};
+use crate::multi_account::MultiAccountStore;
+use crate::proto;
 use crate::sender_keys::SenderKeyRecord;
-use crate::state::{PreKeyId, PreKeyRecord, SessionRecord, SignedPreKeyId, SignedPreKeyRecord};
+use crate::state::{
+    KyberPreKeyId, KyberPreKeyRecord, PreKeyId, PreKeyRecord, SessionRecord, SignedPreKeyId,
+    SignedPreKeyRecord,
+};
+use crate::transaction::StoreTransaction;
 
 // traits
 use libsignal_protocol::{
-    IdentityKeyStore, PreKeyStore, SenderKeyStore, SessionStore, SignedPreKeyStore
+    Direction, GenericSignedPreKey, IdentityKeyStore, KyberPreKeyStore, PreKeyStore,
+    SenderKeyStore, SessionStore, SignedPreKeyStore,
 };
 
-#[pyclass]
+pub(crate) type UpstreamResult<T> = std::result::Result<T, libsignal_protocol::SignalProtocolError>;
+
+// Records are replaced whole, so a map is consistent even if a holder panicked.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Sorts and dedups addresses into the order their locks are taken in, see
+// `SharedStore::with_address_locks`.
+pub(crate) fn sort_addresses(addresses: &mut Vec<libsignal_protocol::ProtocolAddress>) {
+    addresses.sort_by(|a, b| {
+        (a.name(), u32::from(a.device_id())).cmp(&(b.name(), u32::from(b.device_id())))
+    });
+    addresses.dedup();
+}
+
+/// A map of records with its own lock, held only while a record is read or written.
+///
+/// Every write gives the record a new version, so that a transaction can tell
+/// whether a record it read has been written since.
+pub struct RecordMap<K, V> {
+    records: Mutex<Records<K, V>>,
+}
+
+/// The version of a record in a RecordMap; absent records have version 0.
+pub type Version = u64;
+
+/// The contents of a RecordMap, see `RecordMap::lock`.
+pub struct Records<K, V> {
+    map: HashMap<K, (V, Version)>,
+    last_version: Version,
+}
+
+impl<K: Eq + Hash, V> Records<K, V> {
+    pub fn version(&self, key: &K) -> Version {
+        self.map.get(key).map_or(0, |(_, version)| *version)
+    }
+
+    pub fn insert(&mut self, key: K, record: V) -> Option<V> {
+        self.last_version += 1;
+        self.map
+            .insert(key, (record, self.last_version))
+            .map(|(record, _)| record)
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        self.map.remove(key).map(|(record, _)| record)
+    }
+}
+
+impl<K, V> Default for RecordMap<K, V> {
+    fn default() -> Self {
+        RecordMap {
+            records: Mutex::new(Records {
+                map: HashMap::new(),
+                last_version: 0,
+            }),
+        }
+    }
+}
+
+impl<K: Eq + Hash, V: Clone> RecordMap<K, V> {
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.get_versioned(key).0
+    }
+
+    pub fn get_versioned(&self, key: &K) -> (Option<V>, Version) {
+        match lock(&self.records).map.get(key) {
+            Some((record, version)) => (Some(record.clone()), *version),
+            None => (None, 0),
+        }
+    }
+
+    pub fn insert(&self, key: K, record: V) -> Option<V> {
+        lock(&self.records).insert(key, record)
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        lock(&self.records).remove(key)
+    }
+
+    pub fn len(&self) -> usize {
+        lock(&self.records).map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        lock(&self.records).map.is_empty()
+    }
+
+    /// Holds the lock, so that several records can be checked and written at once.
+    pub fn lock(&self) -> MutexGuard<'_, Records<K, V>> {
+        lock(&self.records)
+    }
+}
+
+impl<K: Clone, V: Clone> RecordMap<K, V> {
+    /// A copy of all records, in no particular order.
+    pub fn entries(&self) -> Vec<(K, V)> {
+        lock(&self.records)
+            .map
+            .iter()
+            .map(|(key, (record, _))| (key.clone(), record.clone()))
+            .collect()
+    }
+}
+
+// The sub-stores implement the upstream traits on shared references, like
+// `impl Write for &File`, so that they can be used through the Arc handle.
+pub type InMemSessionStore = RecordMap<libsignal_protocol::ProtocolAddress, libsignal_protocol::SessionRecord>;
+pub type InMemPreKeyStore = RecordMap<libsignal_protocol::PreKeyId, libsignal_protocol::PreKeyRecord>;
+pub type InMemSignedPreKeyStore =
+    RecordMap<libsignal_protocol::SignedPreKeyId, libsignal_protocol::SignedPreKeyRecord>;
+pub type InMemKyberPreKeyStore =
+    RecordMap<libsignal_protocol::KyberPreKeyId, libsignal_protocol::KyberPreKeyRecord>;
+/// Sender key store keyed by sender address and distribution id.
+///
+/// Used instead of the upstream InMemSenderKeyStore, which cannot remove records.
+pub type InMemSenderKeyStore =
+    RecordMap<(libsignal_protocol::ProtocolAddress, Uuid), libsignal_protocol::SenderKeyRecord>;
+
+#[async_trait(?Send)]
+impl SessionStore for &InMemSessionStore {
+    async fn load_session(
+        &self,
+        address: &libsignal_protocol::ProtocolAddress,
+    ) -> UpstreamResult<Option<libsignal_protocol::SessionRecord>> {
+        Ok(self.get(address))
+    }
+
+    async fn store_session(
+        &mut self,
+        address: &libsignal_protocol::ProtocolAddress,
+        record: &libsignal_protocol::SessionRecord,
+    ) -> UpstreamResult<()> {
+        self.insert(address.clone(), record.clone());
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl PreKeyStore for &InMemPreKeyStore {
+    async fn get_pre_key(
+        &self,
+        prekey_id: libsignal_protocol::PreKeyId,
+    ) -> UpstreamResult<libsignal_protocol::PreKeyRecord> {
+        self.get(&prekey_id)
+            .ok_or(libsignal_protocol::SignalProtocolError::InvalidPreKeyId)
+    }
+
+    async fn save_pre_key(
+        &mut self,
+        prekey_id: libsignal_protocol::PreKeyId,
+        record: &libsignal_protocol::PreKeyRecord,
+    ) -> UpstreamResult<()> {
+        self.insert(prekey_id, record.clone());
+        Ok(())
+    }
+
+    async fn remove_pre_key(&mut self, prekey_id: libsignal_protocol::PreKeyId) -> UpstreamResult<()> {
+        self.remove(&prekey_id);
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl SignedPreKeyStore for &InMemSignedPreKeyStore {
+    async fn get_signed_pre_key(
+        &self,
+        signed_prekey_id: libsignal_protocol::SignedPreKeyId,
+    ) -> UpstreamResult<libsignal_protocol::SignedPreKeyRecord> {
+        self.get(&signed_prekey_id)
+            .ok_or(libsignal_protocol::SignalProtocolError::InvalidSignedPreKeyId)
+    }
+
+    async fn save_signed_pre_key(
+        &mut self,
+        signed_prekey_id: libsignal_protocol::SignedPreKeyId,
+        record: &libsignal_protocol::SignedPreKeyRecord,
+    ) -> UpstreamResult<()> {
+        self.insert(signed_prekey_id, record.clone());
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl KyberPreKeyStore for &InMemKyberPreKeyStore {
+    async fn get_kyber_pre_key(
+        &self,
+        kyber_prekey_id: libsignal_protocol::KyberPreKeyId,
+    ) -> UpstreamResult<libsignal_protocol::KyberPreKeyRecord> {
+        self.get(&kyber_prekey_id)
+            .ok_or(libsignal_protocol::SignalProtocolError::InvalidKyberPreKeyId)
+    }
+
+    async fn save_kyber_pre_key(
+        &mut self,
+        kyber_prekey_id: libsignal_protocol::KyberPreKeyId,
+        record: &libsignal_protocol::KyberPreKeyRecord,
+    ) -> UpstreamResult<()> {
+        self.insert(kyber_prekey_id, record.clone());
+        Ok(())
+    }
+
+    /// Like the upstream in-memory store, used keys are kept (last-resort keys are reused).
+    async fn mark_kyber_pre_key_used(
+        &mut self,
+        _kyber_prekey_id: libsignal_protocol::KyberPreKeyId,
+    ) -> UpstreamResult<()> {
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl SenderKeyStore for &InMemSenderKeyStore {
+    async fn store_sender_key(
+        &mut self,
+        sender: &libsignal_protocol::ProtocolAddress,
+        distribution_id: Uuid,
+        record: &libsignal_protocol::SenderKeyRecord,
+    ) -> UpstreamResult<()> {
+        self.insert((sender.clone(), distribution_id), record.clone());
+        Ok(())
+    }
+
+    async fn load_sender_key(
+        &mut self,
+        sender: &libsignal_protocol::ProtocolAddress,
+        distribution_id: Uuid,
+    ) -> UpstreamResult<Option<libsignal_protocol::SenderKeyRecord>> {
+        Ok(self.get(&(sender.clone(), distribution_id)))
+    }
+}
+
+/// Our own identity and the identities of remote addresses (trusted on first use).
+pub struct InMemIdentityKeyStore {
+    key_pair: libsignal_protocol::IdentityKeyPair,
+    registration_id: u32,
+    known_keys: RecordMap<libsignal_protocol::ProtocolAddress, libsignal_protocol::IdentityKey>,
+}
+
+impl InMemIdentityKeyStore {
+    fn new(key_pair: libsignal_protocol::IdentityKeyPair, registration_id: u32) -> Self {
+        InMemIdentityKeyStore {
+            key_pair,
+            registration_id,
+            known_keys: RecordMap::default(),
+        }
+    }
+
+    pub fn get(&self, address: &libsignal_protocol::ProtocolAddress) -> Option<libsignal_protocol::IdentityKey> {
+        self.known_keys.get(address)
+    }
+
+    /// The identities of remote addresses.
+    pub fn known_keys(
+        &self,
+    ) -> &RecordMap<libsignal_protocol::ProtocolAddress, libsignal_protocol::IdentityKey> {
+        &self.known_keys
+    }
+
+    /// Returns whether a different identity was replaced.
+    pub fn save(
+        &self,
+        address: &libsignal_protocol::ProtocolAddress,
+        identity: &libsignal_protocol::IdentityKey,
+    ) -> bool {
+        match self.known_keys.insert(address.clone(), *identity) {
+            Some(previous) => previous != *identity,
+            None => false,
+        }
+    }
+}
+
+impl Drop for InMemIdentityKeyStore {
+    fn drop(&mut self) {
+        zeroize_key(&mut self.key_pair);
+    }
+}
+
+#[async_trait(?Send)]
+impl IdentityKeyStore for &InMemIdentityKeyStore {
+    async fn get_identity_key_pair(&self) -> UpstreamResult<libsignal_protocol::IdentityKeyPair> {
+        Ok(self.key_pair)
+    }
+
+    async fn get_local_registration_id(&self) -> UpstreamResult<u32> {
+        Ok(self.registration_id)
+    }
+
+    async fn save_identity(
+        &mut self,
+        address: &libsignal_protocol::ProtocolAddress,
+        identity: &libsignal_protocol::IdentityKey,
+    ) -> UpstreamResult<bool> {
+        Ok(self.save(address, identity))
+    }
+
+    async fn is_trusted_identity(
+        &self,
+        address: &libsignal_protocol::ProtocolAddress,
+        identity: &libsignal_protocol::IdentityKey,
+        _direction: Direction,
+    ) -> UpstreamResult<bool> {
+        match self.get(address) {
+            Some(known) => Ok(known == *identity),
+            None => Ok(true),
+        }
+    }
+
+    async fn get_identity(
+        &self,
+        address: &libsignal_protocol::ProtocolAddress,
+    ) -> UpstreamResult<Option<libsignal_protocol::IdentityKey>> {
+        Ok(self.get(address))
+    }
+}
+
+/// The state behind all handles on a store.
+pub struct SharedStore {
+    pub session_store: InMemSessionStore,
+    pub identity_store: InMemIdentityKeyStore,
+    pub pre_key_store: InMemPreKeyStore,
+    pub signed_pre_key_store: InMemSignedPreKeyStore,
+    pub kyber_pre_key_store: InMemKyberPreKeyStore,
+    pub sender_key_store: InMemSenderKeyStore,
+    // One entry per address ever locked; they are small and not reclaimed.
+    address_locks: Mutex<HashMap<libsignal_protocol::ProtocolAddress, Arc<Mutex<()>>>>,
+}
+
+impl SharedStore {
+    pub fn new(key_pair: libsignal_protocol::IdentityKeyPair, registration_id: u32) -> Self {
+        SharedStore {
+            session_store: RecordMap::default(),
+            identity_store: InMemIdentityKeyStore::new(key_pair, registration_id),
+            pre_key_store: RecordMap::default(),
+            signed_pre_key_store: RecordMap::default(),
+            kyber_pre_key_store: RecordMap::default(),
+            sender_key_store: RecordMap::default(),
+            address_locks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Serializes all records, including the private identity key.
+    ///
+    /// Each record map is copied under its own lock, so records written
+    /// concurrently may or may not be included.
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        let address_record = |address: &libsignal_protocol::ProtocolAddress, record| {
+            proto::store_structure::AddressRecord {
+                name: address.name().to_string(),
+                device_id: address.device_id().into(),
+                record,
+            }
+        };
+        let id_record = |id: u32, record| proto::store_structure::IdRecord { id, record };
+
+        let mut structure = proto::StoreStructure {
+            identity_key_pair: self.identity_store.key_pair.serialize().into_vec(),
+            registration_id: self.identity_store.registration_id,
+            ..Default::default()
+        };
+        for (address, record) in self.session_store.entries() {
+            structure
+                .sessions
+                .push(address_record(&address, record.serialize()?));
+        }
+        for (address, identity) in self.identity_store.known_keys.entries() {
+            structure
+                .identities
+                .push(address_record(&address, identity.serialize().into_vec()));
+        }
+        for (id, record) in self.pre_key_store.entries() {
+            structure.pre_keys.push(id_record(id.into(), record.serialize()?));
+        }
+        for (id, record) in self.signed_pre_key_store.entries() {
+            structure
+                .signed_pre_keys
+                .push(id_record(id.into(), record.serialize()?));
+        }
+        for (id, record) in self.kyber_pre_key_store.entries() {
+            structure
+                .kyber_pre_keys
+                .push(id_record(id.into(), record.serialize()?));
+        }
+        for ((sender, distribution_id), record) in self.sender_key_store.entries() {
+            structure.sender_keys.push(proto::store_structure::SenderKey {
+                name: sender.name().to_string(),
+                device_id: sender.device_id().into(),
+                distribution_id: distribution_id.as_bytes().to_vec(),
+                record: record.serialize()?,
+            });
+        }
+        // Only the encoded copy of the private key is handed out.
+        let bytes = structure.encode_to_vec();
+        structure.identity_key_pair.zeroize();
+        Ok(bytes)
+    }
+
+    /// Restores a store from the output of `serialize`.
+    pub fn deserialize(data: &[u8]) -> PyResult<Self> {
+        let err = SignalProtocolError::new_err;
+        let mut structure = proto::StoreStructure::decode(data).map_err(|err| {
+            SignalProtocolError::err_from_str(format!("failed to decode store: {}", err))
+        })?;
+        let key_pair =
+            libsignal_protocol::IdentityKeyPair::try_from(&structure.identity_key_pair[..]);
+        structure.identity_key_pair.zeroize();
+        let store = SharedStore::new(key_pair.map_err(err)?, structure.registration_id);
+
+        let address = |name: &str, device_id: u32| {
+            libsignal_protocol::ProtocolAddress::new(name.to_string(), device_id.into())
+        };
+        for entry in &structure.sessions {
+            store.session_store.insert(
+                address(&entry.name, entry.device_id),
+                libsignal_protocol::SessionRecord::deserialize(&entry.record).map_err(err)?,
+            );
+        }
+        for entry in &structure.identities {
+            store.identity_store.known_keys.insert(
+                address(&entry.name, entry.device_id),
+                libsignal_protocol::IdentityKey::decode(&entry.record).map_err(err)?,
+            );
+        }
+        for entry in &structure.pre_keys {
+            store.pre_key_store.insert(
+                entry.id.into(),
+                libsignal_protocol::PreKeyRecord::deserialize(&entry.record).map_err(err)?,
+            );
+        }
+        for entry in &structure.signed_pre_keys {
+            store.signed_pre_key_store.insert(
+                entry.id.into(),
+                libsignal_protocol::SignedPreKeyRecord::deserialize(&entry.record).map_err(err)?,
+            );
+        }
+        for entry in &structure.kyber_pre_keys {
+            store.kyber_pre_key_store.insert(
+                entry.id.into(),
+                libsignal_protocol::KyberPreKeyRecord::deserialize(&entry.record).map_err(err)?,
+            );
+        }
+        for entry in &structure.sender_keys {
+            let distribution_id = Uuid::from_slice(&entry.distribution_id).map_err(|err| {
+                SignalProtocolError::err_from_str(format!("invalid distribution id: {}", err))
+            })?;
+            store.sender_key_store.insert(
+                (address(&entry.name, entry.device_id), distribution_id),
+                libsignal_protocol::SenderKeyRecord::deserialize(&entry.record).map_err(err)?,
+            );
+        }
+        Ok(store)
+    }
+
+    /// Runs `f` holding the lock for `address`, so that operations on one address
+    /// are serialized while those on different addresses run in parallel.
+    /// Transaction commits take the same locks, see `with_address_locks`.
+    ///
+    /// Call this with the GIL released: the holder may itself be waiting for it.
+    pub fn with_address_lock<R>(
+        &self,
+        address: &libsignal_protocol::ProtocolAddress,
+        f: impl FnOnce() -> R,
+    ) -> R {
+        let address_lock = lock(&self.address_locks)
+            .entry(address.clone())
+            .or_default()
+            .clone();
+        let _guard = lock(&address_lock);
+        f()
+    }
+
+    /// Runs `f` holding the locks for all of `addresses`, which must be sorted
+    /// by `sort_addresses` so that two holders of several locks cannot deadlock.
+    ///
+    /// Call this with the GIL released, see `with_address_lock`.
+    pub fn with_address_locks<R>(
+        &self,
+        addresses: &[libsignal_protocol::ProtocolAddress],
+        f: impl FnOnce() -> R,
+    ) -> R {
+        let address_locks: Vec<_> = {
+            let mut locks = lock(&self.address_locks);
+            addresses
+                .iter()
+                .map(|address| locks.entry(address.clone()).or_default().clone())
+                .collect()
+        };
+        let _guards: Vec<_> = address_locks
+            .iter()
+            .map(|address_lock| lock(address_lock))
+            .collect();
+        f()
+    }
+}
+
+/// A handle on an in-memory protocol store.
+///
+/// Copies of a handle (e.g. a store passed to several threads) share the same
+/// records. Records are locked individually, and operations for the same
+/// address are serialized by `with_address_lock`, so that two decrypts cannot
+/// both advance the same ratchet state.
+///
+/// A StoreTransaction works on its own copy of the records it uses. Its commit
+/// holds the same address locks and is refused if any of those records was
+/// written since the transaction read it, so it cannot fork the state either.
+#[pyclass(frozen)]
 #[derive(Clone)]
 pub struct InMemSignalProtocolStore {
-    pub store: libsignal_protocol::InMemSignalProtocolStore,
+    pub store: Arc<SharedStore>,
 }
 
-
 /// libsignal_protocol::IdentityKeyStore
 /// is_trusted_identity is not implemented (it requries traits::Direction as arg)
 #[pymethods]
 impl InMemSignalProtocolStore {
     #[new]
-    fn new(key_pair: &IdentityKeyPair, registration_id: u32) -> PyResult<InMemSignalProtocolStore> {
-        match libsignal_protocol::InMemSignalProtocolStore::new(key_pair.key, registration_id)
-        {
-            Ok(store) => Ok(Self { store }),
-            Err(err) => Err(SignalProtocolError::new_err(err)),
-        }
+    pub fn new(key_pair: &IdentityKeyPair, registration_id: u32) -> PyResult<InMemSignalProtocolStore> {
+        Ok(InMemSignalProtocolStore {
+            store: Arc::new(SharedStore::new(key_pair.key, registration_id)),
+        })
     }
 
-    fn get_identity_key_pair(&self) -> Result<IdentityKeyPair> {
-        let key = block_on(self.store.identity_store.get_identity_key_pair())?;
-        Ok(IdentityKeyPair { key })
+    /// Creates a store for a new account, with a freshly generated identity key
+    /// pair and registration id. Both must be persisted by the caller.
+    #[staticmethod]
+    fn generate() -> PyResult<InMemSignalProtocolStore> {
+        InMemSignalProtocolStore::new(&IdentityKeyPair::generate(), generate_registration_id())
     }
 
-    fn get_local_registration_id(&self) -> Result<u32> {
-        Ok(block_on(
-            self.store.identity_store.get_local_registration_id(),
-        )?)
+    /// Serializes all records of the store, see `deserialize`.
+    ///
+    /// The result contains the private identity key and must be kept as safe as it.
+    fn serialize<'py>(&self, py: Python<'py>) -> Result<Bound<'py, PyBytes>> {
+        let bytes = py.allow_threads(|| self.store.serialize())?;
+        Ok(PyBytes::new(py, &bytes))
     }
 
-    fn save_identity(&mut self, address: &ProtocolAddress, identity: &IdentityKey) -> Result<bool> {
-        Ok(block_on(self.store.identity_store.save_identity(
-            &address.state,
-            &identity.key,
-        ))?)
+    /// Creates a new store from the output of `serialize`.
+    #[staticmethod]
+    fn deserialize(data: BytesLike) -> PyResult<InMemSignalProtocolStore> {
+        Ok(InMemSignalProtocolStore {
+            store: Arc::new(SharedStore::deserialize(&data)?),
+        })
     }
 
-    fn get_identity(&self, address: &ProtocolAddress) -> Result<Option<IdentityKey>> {
-        let key = block_on(self.store.identity_store.get_identity(&address.state))?;
+    /// Starts a transaction on this store, see StoreTransaction.
+    fn transaction(&self) -> StoreTransaction {
+        StoreTransaction::new(self.store.clone())
+    }
 
-        match key {
-            Some(key) => Ok(Some(IdentityKey { key })),
-            None => Ok(None),
+    fn get_identity_key_pair(&self) -> IdentityKeyPair {
+        IdentityKeyPair {
+            key: self.store.identity_store.key_pair,
         }
     }
 
-    /// libsignal_protocol::SessionStore
-    pub fn load_session(&self, address: &ProtocolAddress) -> Result<Option<SessionRecord>> {
-        let session = block_on(self.store.load_session(&address.state))?;
+    fn get_local_registration_id(&self) -> u32 {
+        self.store.identity_store.registration_id
+    }
 
-        match session {
-            None => Ok(None),
-            Some(state) => Ok(Some(SessionRecord { state })),
-        }
+    fn save_identity(&self, address: &ProtocolAddress, identity: &IdentityKey) -> bool {
+        self.store.identity_store.save(&address.state, &identity.key)
     }
 
-    fn store_session(&mut self, address: &ProtocolAddress, record: &SessionRecord) -> Result<()> {
-        block_on(
-            self.store
-                .store_session(&address.state, &record.state),
-        )?;
-        Ok(())
+    fn get_identity(&self, address: &ProtocolAddress) -> Option<IdentityKey> {
+        self.store
+            .identity_store
+            .get(&address.state)
+            .map(|key| IdentityKey { key })
+    }
+
+    /// libsignal_protocol::SessionStore
+    pub fn load_session(&self, address: &ProtocolAddress) -> Option<SessionRecord> {
+        self.store
+            .session_store
+            .get(&address.state)
+            .map(SessionRecord::new)
+    }
+
+    fn store_session(&self, address: &ProtocolAddress, record: &SessionRecord) {
+        self.store
+            .session_store
+            .insert(address.state.clone(), record.state().clone());
     }
 
     /// libsignal_protocol::PreKeyStore
     fn get_pre_key(&self, id: PreKeyId) -> Result<PreKeyRecord> {
-        let state = block_on(self.store.pre_key_store.get_pre_key(id.into()))?;
+        let state = block_on((&self.store.pre_key_store).get_pre_key(id.into()))?;
         Ok(PreKeyRecord { state })
     }
 
-    fn save_pre_key(&mut self, id: PreKeyId, record: &PreKeyRecord) -> Result<()> {
-        block_on(
-            self.store
-                .pre_key_store
-                .save_pre_key(id.into(), &record.state),
-        )?;
-        Ok(())
+    fn save_pre_key(&self, id: PreKeyId, record: &PreKeyRecord) {
+        self.store
+            .pre_key_store
+            .insert(id.into(), record.state.clone());
     }
 
-    fn remove_pre_key(&mut self, id: PreKeyId) -> Result<()> {
-        block_on(self.store.pre_key_store.remove_pre_key(id.into()))?;
-        Ok(())
+    fn remove_pre_key(&self, id: PreKeyId) {
+        self.store.pre_key_store.remove(&id.into());
     }
 
     fn get_signed_pre_key(&self, id: SignedPreKeyId) -> Result<SignedPreKeyRecord> {
-        let state = block_on(self.store.get_signed_pre_key(id.into()))?;
+        let state = block_on((&self.store.signed_pre_key_store).get_signed_pre_key(id.into()))?;
         Ok(SignedPreKeyRecord { state })
     }
 
     /// libsignal_protocol::SenderKeyStore
-    fn save_signed_pre_key(
-        &mut self,
-        id: SignedPreKeyId,
-        record: &SignedPreKeyRecord,
-    ) -> Result<()> {
-        block_on(
-            self.store
-                .save_signed_pre_key(id.into(), &record.state.to_owned()),
-        )?;
+    fn save_signed_pre_key(&self, id: SignedPreKeyId, record: &SignedPreKeyRecord) {
+        self.store
+            .signed_pre_key_store
+            .insert(id.into(), record.state.clone());
+    }
+
+    /// libsignal_protocol::KyberPreKeyStore
+    fn get_kyber_pre_key(&self, id: KyberPreKeyId) -> Result<KyberPreKeyRecord> {
+        let state = block_on((&self.store.kyber_pre_key_store).get_kyber_pre_key(id.into()))?;
+        Ok(KyberPreKeyRecord { state })
+    }
+
+    fn save_kyber_pre_key(&self, id: KyberPreKeyId, record: &KyberPreKeyRecord) {
+        self.store
+            .kyber_pre_key_store
+            .insert(id.into(), record.state.clone());
+    }
+
+    fn mark_kyber_pre_key_used(&self, id: KyberPreKeyId) -> Result<()> {
+        block_on((&self.store.kyber_pre_key_store).mark_kyber_pre_key_used(id.into()))?;
         Ok(())
     }
 
     fn store_sender_key(
-        &mut self,
+        &self,
         sender: &ProtocolAddress,
-        distribution_id: String,
+        distribution_id: DistributionId,
         record: &SenderKeyRecord,
-    ) -> Result<()> {
-        Ok(block_on(self.store.store_sender_key(
-            &sender.state,
-            Uuid::parse_str(&distribution_id).unwrap(),
-            &record.state,
-        ))?)
+    ) {
+        self.store
+            .sender_key_store
+            .insert((sender.state.clone(), distribution_id.0), record.state.clone());
     }
 
     fn load_sender_key(
-        &mut self,
+        &self,
         sender: &ProtocolAddress,
-        distribution_id: String,
-    ) -> Result<Option<SenderKeyRecord>> {
-        match block_on(self.store.load_sender_key(&sender.state, Uuid::parse_str(&distribution_id).unwrap()))? {
-            Some(state) => Ok(Some(SenderKeyRecord { state })),
-            None => Ok(None),
-        }
+        distribution_id: DistributionId,
+    ) -> Option<SenderKeyRecord> {
+        self.store
+            .sender_key_store
+            .get(&(sender.state.clone(), distribution_id.0))
+            .map(|state| SenderKeyRecord { state })
+    }
+
+    /// Removes the sender key for `sender`, e.g. after they left the group.
+    /// Returns whether a sender key was stored.
+    fn remove_sender_key(&self, sender: &ProtocolAddress, distribution_id: DistributionId) -> bool {
+        self.store
+            .sender_key_store
+            .remove(&(sender.state.clone(), distribution_id.0))
+            .is_some()
     }
 }
 
@@ -149,5 +702,7 @@ impl InMemSignalProtocolStore {
 /// One will need to operate on the InMemSignalProtocolStore instead.
 pub fn init_submodule(module: &Bound<'_, PyModule>) -> PyResult<()> {
     module.add_class::<InMemSignalProtocolStore>()?;
+    module.add_class::<StoreTransaction>()?;
+    module.add_class::<MultiAccountStore>()?;
     Ok(())
 }
@@ -0,0 +1,539 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use pyo3::prelude::*;
+
+use uuid::Uuid;
+
+use crate::address::ProtocolAddress;
+use crate::error::SignalProtocolError;
+use crate::state::SessionRecord;
+use crate::storage::{
+    sort_addresses, InMemIdentityKeyStore, InMemSignalProtocolStore, RecordMap, Records,
+    SharedStore, UpstreamResult, Version,
+};
+
+use libsignal_protocol::{
+    Direction, IdentityKeyStore, KyberPreKeyStore, PreKeyStore, SenderKeyStore, SessionStore,
+    SignedPreKeyStore,
+};
+
+/// The records a transaction wrote to one sub-store, None for a removed one,
+/// and the versions of the store's records it read or wrote.
+struct Changes<K, V> {
+    writes: HashMap<K, Option<V>>,
+    reads: HashMap<K, Version>,
+}
+
+impl<K, V> Default for Changes<K, V> {
+    fn default() -> Self {
+        Changes {
+            writes: HashMap::new(),
+            reads: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Changes<K, V> {
+    fn overlay<'a>(&'a mut self, base: &'a RecordMap<K, V>) -> Overlay<'a, K, V> {
+        Overlay {
+            base,
+            writes: &mut self.writes,
+            reads: RefCell::new(&mut self.reads),
+        }
+    }
+
+    /// Whether no record the transaction has seen was written since.
+    fn is_current(&self, records: &Records<K, V>) -> bool {
+        self.reads
+            .iter()
+            .all(|(key, version)| records.version(key) == *version)
+    }
+
+    fn apply(self, records: &mut Records<K, V>) {
+        for (key, record) in self.writes {
+            match record {
+                Some(record) => records.insert(key, record),
+                None => records.remove(&key),
+            };
+        }
+    }
+}
+
+/// Changes made during a transaction, keyed like the sub-stores they shadow.
+#[derive(Default)]
+struct StoreChanges {
+    sessions: Changes<libsignal_protocol::ProtocolAddress, libsignal_protocol::SessionRecord>,
+    identities: Changes<libsignal_protocol::ProtocolAddress, libsignal_protocol::IdentityKey>,
+    pre_keys: Changes<libsignal_protocol::PreKeyId, libsignal_protocol::PreKeyRecord>,
+    signed_pre_keys:
+        Changes<libsignal_protocol::SignedPreKeyId, libsignal_protocol::SignedPreKeyRecord>,
+    kyber_pre_keys: Changes<libsignal_protocol::KyberPreKeyId, libsignal_protocol::KyberPreKeyRecord>,
+    sender_keys:
+        Changes<(libsignal_protocol::ProtocolAddress, Uuid), libsignal_protocol::SenderKeyRecord>,
+}
+
+impl StoreChanges {
+    /// The addresses whose records were read or written, in a fixed order.
+    fn addresses(&self) -> Vec<libsignal_protocol::ProtocolAddress> {
+        let mut addresses: Vec<_> = self
+            .sessions
+            .reads
+            .keys()
+            .chain(self.identities.reads.keys())
+            .chain(self.sender_keys.reads.keys().map(|(sender, _)| sender))
+            .cloned()
+            .collect();
+        sort_addresses(&mut addresses);
+        addresses
+    }
+
+    /// Applies the changes if none of the records they were based on has been
+    /// written since, and returns whether they were applied.
+    ///
+    /// The locks for the addresses involved keep decrypts outside the transaction
+    /// from writing in between, and the record maps are locked, in a fixed order,
+    /// while they are checked and written.
+    fn commit(self, store: &SharedStore) -> bool {
+        store.with_address_locks(&self.addresses(), || {
+            let mut sessions = store.session_store.lock();
+            let mut identities = store.identity_store.known_keys().lock();
+            let mut pre_keys = store.pre_key_store.lock();
+            let mut signed_pre_keys = store.signed_pre_key_store.lock();
+            let mut kyber_pre_keys = store.kyber_pre_key_store.lock();
+            let mut sender_keys = store.sender_key_store.lock();
+            let current = self.sessions.is_current(&sessions)
+                && self.identities.is_current(&identities)
+                && self.pre_keys.is_current(&pre_keys)
+                && self.signed_pre_keys.is_current(&signed_pre_keys)
+                && self.kyber_pre_keys.is_current(&kyber_pre_keys)
+                && self.sender_keys.is_current(&sender_keys);
+            if current {
+                self.sessions.apply(&mut sessions);
+                self.identities.apply(&mut identities);
+                self.pre_keys.apply(&mut pre_keys);
+                self.signed_pre_keys.apply(&mut signed_pre_keys);
+                self.kyber_pre_keys.apply(&mut kyber_pre_keys);
+                self.sender_keys.apply(&mut sender_keys);
+            }
+            current
+        })
+    }
+}
+
+/// One sub-store as a transaction sees it: the records it wrote, and those of
+/// the store otherwise. The version of every record read from the store is
+/// kept for the check on commit.
+///
+/// Each overlay shadows a single sub-store, so that the upstream functions can
+/// be handed one mutable reference per store as usual.
+struct Overlay<'a, K, V> {
+    base: &'a RecordMap<K, V>,
+    writes: &'a mut HashMap<K, Option<V>>,
+    // The upstream traits read through shared references.
+    reads: RefCell<&'a mut HashMap<K, Version>>,
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Overlay<'_, K, V> {
+    fn get(&self, key: &K) -> Option<V> {
+        if let Some(record) = self.writes.get(key) {
+            return record.clone();
+        }
+        let (record, version) = self.base.get_versioned(key);
+        self.reads.borrow_mut().entry(key.clone()).or_insert(version);
+        record
+    }
+
+    fn set(&mut self, key: K, record: Option<V>) {
+        // Writing a record that was never read still conflicts with later writes.
+        if !self.reads.get_mut().contains_key(&key) {
+            let version = self.base.get_versioned(&key).1;
+            self.reads.get_mut().insert(key.clone(), version);
+        }
+        self.writes.insert(key, record);
+    }
+}
+
+type SessionOverlay<'a> =
+    Overlay<'a, libsignal_protocol::ProtocolAddress, libsignal_protocol::SessionRecord>;
+
+#[async_trait(?Send)]
+impl SessionStore for SessionOverlay<'_> {
+    async fn load_session(
+        &self,
+        address: &libsignal_protocol::ProtocolAddress,
+    ) -> UpstreamResult<Option<libsignal_protocol::SessionRecord>> {
+        Ok(self.get(address))
+    }
+
+    async fn store_session(
+        &mut self,
+        address: &libsignal_protocol::ProtocolAddress,
+        record: &libsignal_protocol::SessionRecord,
+    ) -> UpstreamResult<()> {
+        self.set(address.clone(), Some(record.clone()));
+        Ok(())
+    }
+}
+
+struct IdentityOverlay<'a> {
+    base: &'a InMemIdentityKeyStore,
+    known_keys: Overlay<'a, libsignal_protocol::ProtocolAddress, libsignal_protocol::IdentityKey>,
+}
+
+#[async_trait(?Send)]
+impl IdentityKeyStore for IdentityOverlay<'_> {
+    async fn get_identity_key_pair(&self) -> UpstreamResult<libsignal_protocol::IdentityKeyPair> {
+        self.base.get_identity_key_pair().await
+    }
+
+    async fn get_local_registration_id(&self) -> UpstreamResult<u32> {
+        self.base.get_local_registration_id().await
+    }
+
+    async fn save_identity(
+        &mut self,
+        address: &libsignal_protocol::ProtocolAddress,
+        identity: &libsignal_protocol::IdentityKey,
+    ) -> UpstreamResult<bool> {
+        let previous = self.known_keys.get(address);
+        self.known_keys.set(address.clone(), Some(*identity));
+        Ok(matches!(previous, Some(key) if key != *identity))
+    }
+
+    /// Trusted on first use, like InMemIdentityKeyStore.
+    async fn is_trusted_identity(
+        &self,
+        address: &libsignal_protocol::ProtocolAddress,
+        identity: &libsignal_protocol::IdentityKey,
+        _direction: Direction,
+    ) -> UpstreamResult<bool> {
+        match self.known_keys.get(address) {
+            Some(key) => Ok(key == *identity),
+            None => Ok(true),
+        }
+    }
+
+    async fn get_identity(
+        &self,
+        address: &libsignal_protocol::ProtocolAddress,
+    ) -> UpstreamResult<Option<libsignal_protocol::IdentityKey>> {
+        Ok(self.known_keys.get(address))
+    }
+}
+
+type PreKeyOverlay<'a> = Overlay<'a, libsignal_protocol::PreKeyId, libsignal_protocol::PreKeyRecord>;
+
+#[async_trait(?Send)]
+impl PreKeyStore for PreKeyOverlay<'_> {
+    async fn get_pre_key(
+        &self,
+        prekey_id: libsignal_protocol::PreKeyId,
+    ) -> UpstreamResult<libsignal_protocol::PreKeyRecord> {
+        self.get(&prekey_id)
+            .ok_or(libsignal_protocol::SignalProtocolError::InvalidPreKeyId)
+    }
+
+    async fn save_pre_key(
+        &mut self,
+        prekey_id: libsignal_protocol::PreKeyId,
+        record: &libsignal_protocol::PreKeyRecord,
+    ) -> UpstreamResult<()> {
+        self.set(prekey_id, Some(record.clone()));
+        Ok(())
+    }
+
+    async fn remove_pre_key(&mut self, prekey_id: libsignal_protocol::PreKeyId) -> UpstreamResult<()> {
+        self.set(prekey_id, None);
+        Ok(())
+    }
+}
+
+type SignedPreKeyOverlay<'a> =
+    Overlay<'a, libsignal_protocol::SignedPreKeyId, libsignal_protocol::SignedPreKeyRecord>;
+
+#[async_trait(?Send)]
+impl SignedPreKeyStore for SignedPreKeyOverlay<'_> {
+    async fn get_signed_pre_key(
+        &self,
+        signed_prekey_id: libsignal_protocol::SignedPreKeyId,
+    ) -> UpstreamResult<libsignal_protocol::SignedPreKeyRecord> {
+        self.get(&signed_prekey_id)
+            .ok_or(libsignal_protocol::SignalProtocolError::InvalidSignedPreKeyId)
+    }
+
+    async fn save_signed_pre_key(
+        &mut self,
+        signed_prekey_id: libsignal_protocol::SignedPreKeyId,
+        record: &libsignal_protocol::SignedPreKeyRecord,
+    ) -> UpstreamResult<()> {
+        self.set(signed_prekey_id, Some(record.clone()));
+        Ok(())
+    }
+}
+
+type KyberPreKeyOverlay<'a> =
+    Overlay<'a, libsignal_protocol::KyberPreKeyId, libsignal_protocol::KyberPreKeyRecord>;
+
+#[async_trait(?Send)]
+impl KyberPreKeyStore for KyberPreKeyOverlay<'_> {
+    async fn get_kyber_pre_key(
+        &self,
+        kyber_prekey_id: libsignal_protocol::KyberPreKeyId,
+    ) -> UpstreamResult<libsignal_protocol::KyberPreKeyRecord> {
+        self.get(&kyber_prekey_id)
+            .ok_or(libsignal_protocol::SignalProtocolError::InvalidKyberPreKeyId)
+    }
+
+    async fn save_kyber_pre_key(
+        &mut self,
+        kyber_prekey_id: libsignal_protocol::KyberPreKeyId,
+        record: &libsignal_protocol::KyberPreKeyRecord,
+    ) -> UpstreamResult<()> {
+        self.set(kyber_prekey_id, Some(record.clone()));
+        Ok(())
+    }
+
+    /// The store keeps used keys, see InMemKyberPreKeyStore.
+    async fn mark_kyber_pre_key_used(
+        &mut self,
+        _kyber_prekey_id: libsignal_protocol::KyberPreKeyId,
+    ) -> UpstreamResult<()> {
+        Ok(())
+    }
+}
+
+type SenderKeyOverlay<'a> =
+    Overlay<'a, (libsignal_protocol::ProtocolAddress, Uuid), libsignal_protocol::SenderKeyRecord>;
+
+#[async_trait(?Send)]
+impl SenderKeyStore for SenderKeyOverlay<'_> {
+    async fn store_sender_key(
+        &mut self,
+        sender: &libsignal_protocol::ProtocolAddress,
+        distribution_id: Uuid,
+        record: &libsignal_protocol::SenderKeyRecord,
+    ) -> UpstreamResult<()> {
+        self.set((sender.clone(), distribution_id), Some(record.clone()));
+        Ok(())
+    }
+
+    async fn load_sender_key(
+        &mut self,
+        sender: &libsignal_protocol::ProtocolAddress,
+        distribution_id: Uuid,
+    ) -> UpstreamResult<Option<libsignal_protocol::SenderKeyRecord>> {
+        Ok(self.get(&(sender.clone(), distribution_id)))
+    }
+}
+
+/// A copy-on-write view of an InMemSignalProtocolStore, created with
+/// `InMemSignalProtocolStore.transaction()`.
+///
+/// Pass the transaction instead of the store to the session_cipher and
+/// group_cipher functions: records they write are kept in the transaction
+/// and only applied to the store by `commit()`. Leaving a `with` block
+/// without committing discards them, as does `rollback()`.
+///
+/// If a record the transaction read or wrote was written to the store since,
+/// e.g. by a decrypt outside the transaction, `commit()` raises instead and
+/// discards the changes; the messages can then be decrypted again.
+#[pyclass]
+pub struct StoreTransaction {
+    store: Option<Arc<SharedStore>>,
+    changes: StoreChanges,
+}
+
+impl StoreTransaction {
+    pub fn new(store: Arc<SharedStore>) -> Self {
+        StoreTransaction {
+            store: Some(store),
+            changes: StoreChanges::default(),
+        }
+    }
+
+    fn open_store(&self) -> PyResult<&Arc<SharedStore>> {
+        match &self.store {
+            Some(store) => Ok(store),
+            None => Err(SignalProtocolError::err_from_str(
+                "transaction is already closed".to_string(),
+            )),
+        }
+    }
+
+    fn close(&mut self) {
+        self.store = None;
+        self.changes = StoreChanges::default();
+    }
+}
+
+#[pymethods]
+impl StoreTransaction {
+    /// Applies the records written during the transaction to the store and
+    /// closes the transaction. Raises if they conflict with records written to
+    /// the store since they were read, closing the transaction all the same.
+    fn commit(&mut self, py: Python) -> PyResult<()> {
+        let store = self.open_store()?.clone();
+        let changes = std::mem::take(&mut self.changes);
+        self.close();
+        if py.allow_threads(|| changes.commit(&store)) {
+            Ok(())
+        } else {
+            Err(SignalProtocolError::err_from_str(
+                "transaction conflicts with changes made to the store since it read them"
+                    .to_string(),
+            ))
+        }
+    }
+
+    /// Discards the records written during the transaction and closes it.
+    fn rollback(&mut self) -> PyResult<()> {
+        self.open_store()?;
+        self.close();
+        Ok(())
+    }
+
+    /// Loads the session for `address` as the transaction sees it.
+    fn load_session(&self, address: &ProtocolAddress) -> PyResult<Option<SessionRecord>> {
+        let store = self.open_store()?;
+        let session = match self.changes.sessions.writes.get(&address.state) {
+            Some(record) => record.clone(),
+            None => store.session_store.get(&address.state),
+        };
+        Ok(session.map(SessionRecord::new))
+    }
+
+    #[getter]
+    fn closed(&self) -> bool {
+        self.store.is_none()
+    }
+
+    fn __enter__(slf: PyRef<'_, Self>) -> PyResult<PyRef<'_, Self>> {
+        slf.open_store()?;
+        Ok(slf)
+    }
+
+    /// Uncommitted changes are discarded; exceptions are not suppressed.
+    fn __exit__(
+        &mut self,
+        _exc_type: &Bound<'_, PyAny>,
+        _exc_value: &Bound<'_, PyAny>,
+        _traceback: &Bound<'_, PyAny>,
+    ) -> bool {
+        self.close();
+        false
+    }
+}
+
+/// The sub-stores handed to the upstream functions.
+pub struct Stores<'a> {
+    pub session_store: &'a mut dyn SessionStore,
+    pub identity_store: &'a mut dyn IdentityKeyStore,
+    pub pre_key_store: &'a mut dyn PreKeyStore,
+    pub signed_pre_key_store: &'a mut dyn SignedPreKeyStore,
+    pub kyber_pre_key_store: &'a mut dyn KyberPreKeyStore,
+    pub sender_key_store: &'a mut dyn SenderKeyStore,
+}
+
+/// Accepts either an InMemSignalProtocolStore or a StoreTransaction on it.
+pub enum ProtocolStoreArg<'py> {
+    Store(PyRef<'py, InMemSignalProtocolStore>),
+    Transaction(PyRefMut<'py, StoreTransaction>),
+}
+
+impl<'py> FromPyObject<'py> for ProtocolStoreArg<'py> {
+    fn extract_bound(ob: &Bound<'py, PyAny>) -> PyResult<Self> {
+        if let Ok(transaction) = ob.downcast::<StoreTransaction>() {
+            return Ok(ProtocolStoreArg::Transaction(transaction.try_borrow_mut()?));
+        }
+        Ok(ProtocolStoreArg::Store(ob.extract()?))
+    }
+}
+
+/// The store behind a ProtocolStoreArg, usable with the GIL released.
+pub struct StoreAccess<'a> {
+    store: &'a SharedStore,
+    changes: Option<&'a mut StoreChanges>,
+}
+
+impl StoreAccess<'_> {
+    /// Calls `f` with the store's sub-stores, or with overlays on them for a
+    /// transaction, holding the lock for `address`.
+    pub fn with_address<R>(
+        &mut self,
+        address: &libsignal_protocol::ProtocolAddress,
+        f: impl FnOnce(Stores<'_>) -> R,
+    ) -> R {
+        let store = self.store;
+        store.with_address_lock(address, || match &mut self.changes {
+            None => f(Stores {
+                session_store: &mut &store.session_store,
+                identity_store: &mut &store.identity_store,
+                pre_key_store: &mut &store.pre_key_store,
+                signed_pre_key_store: &mut &store.signed_pre_key_store,
+                kyber_pre_key_store: &mut &store.kyber_pre_key_store,
+                sender_key_store: &mut &store.sender_key_store,
+            }),
+            Some(changes) => f(Stores {
+                session_store: &mut changes.sessions.overlay(&store.session_store),
+                identity_store: &mut IdentityOverlay {
+                    base: &store.identity_store,
+                    known_keys: changes
+                        .identities
+                        .overlay(store.identity_store.known_keys()),
+                },
+                pre_key_store: &mut changes.pre_keys.overlay(&store.pre_key_store),
+                signed_pre_key_store: &mut changes
+                    .signed_pre_keys
+                    .overlay(&store.signed_pre_key_store),
+                kyber_pre_key_store: &mut changes
+                    .kyber_pre_keys
+                    .overlay(&store.kyber_pre_key_store),
+                sender_key_store: &mut changes.sender_keys.overlay(&store.sender_key_store),
+            }),
+        })
+    }
+}
+
+impl ProtocolStoreArg<'_> {
+    /// Calls `f` with the GIL released. `f` may use the store for any number of
+    /// addresses, e.g. to process a batch of messages.
+    pub fn without_gil<R: Send>(
+        &mut self,
+        f: impl FnOnce(&mut StoreAccess<'_>) -> PyResult<R> + Send,
+    ) -> PyResult<R> {
+        match self {
+            ProtocolStoreArg::Store(protocol_store) => {
+                let mut access = StoreAccess {
+                    store: &protocol_store.store,
+                    changes: None,
+                };
+                protocol_store.py().allow_threads(|| f(&mut access))
+            }
+            ProtocolStoreArg::Transaction(transaction) => {
+                let py = transaction.py();
+                let transaction = &mut **transaction;
+                // A handle of our own, so that the changes can be borrowed mutably.
+                let store = transaction.open_store()?.clone();
+                let mut access = StoreAccess {
+                    store: &store,
+                    changes: Some(&mut transaction.changes),
+                };
+                py.allow_threads(|| f(&mut access))
+            }
+        }
+    }
+
+    /// Calls `f` with the store's sub-stores, or with overlays on them for a
+    /// transaction, holding the lock for `address` and with the GIL released.
+    pub fn with_stores<R: Send>(
+        &mut self,
+        address: &libsignal_protocol::ProtocolAddress,
+        f: impl FnOnce(Stores<'_>) -> PyResult<R> + Send,
+    ) -> PyResult<R> {
+        self.without_gil(|access| access.with_address(address, f))
+    }
+}
@@ -0,0 +1,271 @@
+//! VXEdDSA, the verifiable random function variant of XEdDSA, as specified in
+//! <https://signal.org/docs/specifications/xeddsa/>.
+//!
+//! The upstream crate only implements XEdDSA. Points and scalars are
+//! curve25519-dalek's. Elligator 2 also needs field arithmetic, which
+//! curve25519-dalek 4 keeps private: it is done with fiat-crypto's
+//! formally verified, constant-time implementation, the one curve25519-dalek's
+//! own fiat backend is built on.
+
+use curve25519_dalek::constants::ED25519_BASEPOINT_POINT;
+use curve25519_dalek::edwards::{CompressedEdwardsY, EdwardsPoint};
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use curve25519_dalek::scalar::{clamp_integer, Scalar};
+use fiat_crypto::curve25519_64::{
+    fiat_25519_add, fiat_25519_carry, fiat_25519_carry_mul, fiat_25519_from_bytes,
+    fiat_25519_loose_field_element, fiat_25519_opp, fiat_25519_relax,
+    fiat_25519_tight_field_element, fiat_25519_to_bytes,
+};
+use sha2::{Digest, Sha512};
+use zeroize::Zeroizing;
+
+pub const SIGNATURE_LENGTH: usize = 96;
+pub const OUTPUT_LENGTH: usize = 32;
+
+const MONTGOMERY_A: u64 = 486_662;
+// p - 2 and (p - 1) / 2, little-endian.
+const P_MINUS_2: [u8; 32] = exponent(0xEB, 0x7F);
+const P_MINUS_1_HALF: [u8; 32] = exponent(0xF6, 0x3F);
+
+const fn exponent(low: u8, high: u8) -> [u8; 32] {
+    let mut bytes = [0xFFu8; 32];
+    bytes[0] = low;
+    bytes[31] = high;
+    bytes
+}
+
+/// An element of GF(2^255 - 19).
+#[derive(Clone, Copy)]
+struct FieldElement(fiat_25519_tight_field_element);
+
+impl FieldElement {
+    const ZERO: FieldElement = FieldElement::from_u64(0);
+    const ONE: FieldElement = FieldElement::from_u64(1);
+
+    const fn from_u64(value: u64) -> Self {
+        // Limbs are 51 bits: small values fit in the first one.
+        FieldElement(fiat_25519_tight_field_element([value, 0, 0, 0, 0]))
+    }
+
+    /// Ignores the top bit, as X25519 does.
+    fn from_bytes(bytes: &[u8; 32]) -> Self {
+        let mut masked = *bytes;
+        masked[31] &= 0x7F;
+        let mut out = fiat_25519_tight_field_element([0; 5]);
+        fiat_25519_from_bytes(&mut out, &masked);
+        FieldElement(out)
+    }
+
+    /// The canonical encoding, fully reduced mod p.
+    fn to_bytes(self) -> [u8; 32] {
+        let mut out = [0u8; 32];
+        fiat_25519_to_bytes(&mut out, &self.0);
+        out
+    }
+
+    fn relax(&self) -> fiat_25519_loose_field_element {
+        let mut out = fiat_25519_loose_field_element([0; 5]);
+        fiat_25519_relax(&mut out, &self.0);
+        out
+    }
+
+    fn carry(loose: &fiat_25519_loose_field_element) -> Self {
+        let mut out = fiat_25519_tight_field_element([0; 5]);
+        fiat_25519_carry(&mut out, loose);
+        FieldElement(out)
+    }
+
+    fn add(&self, other: &FieldElement) -> Self {
+        let mut out = fiat_25519_loose_field_element([0; 5]);
+        fiat_25519_add(&mut out, &self.0, &other.0);
+        FieldElement::carry(&out)
+    }
+
+    fn neg(&self) -> Self {
+        let mut out = fiat_25519_loose_field_element([0; 5]);
+        fiat_25519_opp(&mut out, &self.0);
+        FieldElement::carry(&out)
+    }
+
+    fn mul(&self, other: &FieldElement) -> Self {
+        let mut out = fiat_25519_tight_field_element([0; 5]);
+        fiat_25519_carry_mul(&mut out, &self.relax(), &other.relax());
+        FieldElement(out)
+    }
+
+    // The exponent is always one of the constants above, so the branches
+    // only depend on public values.
+    fn pow(&self, exponent: &[u8; 32]) -> Self {
+        let mut result = FieldElement::ONE;
+        for i in (0..256).rev() {
+            result = result.mul(&result);
+            if (exponent[i / 8] >> (i % 8)) & 1 == 1 {
+                result = result.mul(self);
+            }
+        }
+        result
+    }
+
+    // inv(0) is 0, as in the specification.
+    fn invert(&self) -> Self {
+        self.pow(&P_MINUS_2)
+    }
+
+    // True for squares and zero, as the specification's is_square().
+    fn is_square(&self) -> bool {
+        let legendre = self.pow(&P_MINUS_1_HALF).to_bytes();
+        legendre == FieldElement::ZERO.to_bytes() || legendre == FieldElement::ONE.to_bytes()
+    }
+}
+
+// hash_i(X) = SHA-512((2^256 - 1 - i) || X), the prefix encoded in 32 bytes little-endian.
+fn hash_i(i: u8, parts: &[&[u8]]) -> [u8; 64] {
+    let mut prefix = [0xFFu8; 32];
+    prefix[0] = 0xFF - i;
+    let mut hasher = Sha512::new();
+    hasher.update(prefix);
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+// Returns the canonical encoding of the Montgomery u coordinate.
+fn elligator2(r: &[u8; 32]) -> [u8; 32] {
+    let r = FieldElement::from_bytes(r);
+    let a = FieldElement::from_u64(MONTGOMERY_A);
+    let denominator = FieldElement::ONE.add(&FieldElement::from_u64(2).mul(&r.mul(&r)));
+    let u1 = a.neg().mul(&denominator.invert());
+    let w1 = u1.mul(&u1.mul(&u1).add(&a.mul(&u1)).add(&FieldElement::ONE));
+    if w1.is_square() {
+        u1.to_bytes()
+    } else {
+        a.neg().add(&u1.neg()).to_bytes()
+    }
+}
+
+fn hash_to_point(parts: &[&[u8]]) -> Option<EdwardsPoint> {
+    let h = hash_i(2, parts);
+    let mut r = [0u8; 32];
+    r.copy_from_slice(&h[..32]);
+    let sign = r[31] >> 7;
+    r[31] &= 0x7F;
+    let point = MontgomeryPoint(elligator2(&r)).to_edwards(sign)?;
+    Some(point.mul_by_cofactor())
+}
+
+// calculate_key_pair: the Edwards public key A with a sign bit of zero, and the
+// matching private scalar a. The sign bit is that of the public key.
+fn calculate_key_pair(private_key: &[u8; 32]) -> (CompressedEdwardsY, Zeroizing<Scalar>) {
+    let k = Zeroizing::new(Scalar::from_bytes_mod_order(clamp_integer(*private_key)));
+    let e = EdwardsPoint::mul_base(&k).compress();
+    if e.as_bytes()[31] & 0x80 != 0 {
+        let mut a = e.to_bytes();
+        a[31] &= 0x7F;
+        (CompressedEdwardsY(a), Zeroizing::new(-*k))
+    } else {
+        (e, k)
+    }
+}
+
+fn scalar_from_hash(hash: [u8; 64]) -> Scalar {
+    Scalar::from_bytes_mod_order_wide(&hash)
+}
+
+// A 32 byte value below 2^253 (= 2^|q|).
+fn is_reduced(bytes: &[u8]) -> bool {
+    bytes[31] & 0xE0 == 0
+}
+
+fn vrf_output(v: &EdwardsPoint) -> [u8; OUTPUT_LENGTH] {
+    let hash = hash_i(5, &[v.mul_by_cofactor().compress().as_bytes()]);
+    let mut output = [0u8; OUTPUT_LENGTH];
+    output.copy_from_slice(&hash[..OUTPUT_LENGTH]);
+    output
+}
+
+/// Signs `message` with the Montgomery private key, returning the signature
+/// (V || h || s) and the VRF output. `random` is the 64 byte nonce Z.
+pub fn sign(
+    private_key: &[u8; 32],
+    message: &[u8],
+    random: &[u8; 64],
+) -> Option<([u8; SIGNATURE_LENGTH], [u8; OUTPUT_LENGTH])> {
+    let (a_point, a) = calculate_key_pair(private_key);
+    let bv = hash_to_point(&[a_point.as_bytes(), message])?;
+    let v = *a * bv;
+    let v_bytes = v.compress();
+    let r = Zeroizing::new(scalar_from_hash(hash_i(
+        3,
+        &[a.as_bytes(), v_bytes.as_bytes(), random],
+    )));
+    let r_point = EdwardsPoint::mul_base(&r).compress();
+    let rv = (*r * bv).compress();
+    let h = scalar_from_hash(hash_i(
+        4,
+        &[
+            a_point.as_bytes(),
+            v_bytes.as_bytes(),
+            r_point.as_bytes(),
+            rv.as_bytes(),
+            message,
+        ],
+    ));
+    let s = *r + h * *a;
+
+    let mut signature = [0u8; SIGNATURE_LENGTH];
+    signature[..32].copy_from_slice(v_bytes.as_bytes());
+    signature[32..64].copy_from_slice(h.as_bytes());
+    signature[64..].copy_from_slice(s.as_bytes());
+    Some((signature, vrf_output(&v)))
+}
+
+/// Verifies a signature made by sign() against the Montgomery public key `u`,
+/// returning the VRF output if it is valid.
+pub fn verify(u: &[u8; 32], message: &[u8], signature: &[u8]) -> Option<[u8; OUTPUT_LENGTH]> {
+    if signature.len() != SIGNATURE_LENGTH {
+        return None;
+    }
+    let (v_bytes, rest) = signature.split_at(32);
+    let (h_bytes, s_bytes) = rest.split_at(32);
+    // u >= p, including any u with the top bit set, does not round trip.
+    if FieldElement::from_bytes(u).to_bytes() != *u || !is_reduced(h_bytes) || !is_reduced(s_bytes)
+    {
+        return None;
+    }
+
+    let a_point = MontgomeryPoint(*u).to_edwards(0)?;
+    let a_bytes = a_point.compress();
+    let v_compressed = CompressedEdwardsY::from_slice(v_bytes).ok()?;
+    let v = v_compressed.decompress()?;
+    // Rejects non-canonical encodings of V, including y >= p.
+    if v.compress() != v_compressed {
+        return None;
+    }
+    let bv = hash_to_point(&[a_bytes.as_bytes(), message])?;
+    if a_point.is_small_order() || v.is_small_order() || bv.is_identity() {
+        return None;
+    }
+
+    // An h of q or more can never match h_check below. s only multiplies points
+    // of order q, so reducing it mod q gives the same R and Rv.
+    let h = Option::<Scalar>::from(Scalar::from_canonical_bytes(h_bytes.try_into().ok()?))?;
+    let s = Scalar::from_bytes_mod_order(s_bytes.try_into().ok()?);
+    let r_point = (s * ED25519_BASEPOINT_POINT - h * a_point).compress();
+    let rv = (s * bv - h * v).compress();
+    let h_check = scalar_from_hash(hash_i(
+        4,
+        &[
+            a_bytes.as_bytes(),
+            v_bytes,
+            r_point.as_bytes(),
+            rv.as_bytes(),
+            message,
+        ],
+    ));
+    if h_check == h {
+        Some(vrf_output(&v))
+    } else {
+        None
+    }
+}
@@ -0,0 +1,64 @@
+from signal_protocol import account, address, curve, identity_key, session, session_cipher
+from signal_protocol import state, storage
+
+
+def test_provision_payload_and_store():
+    store, payload = account.provision(num_one_time=5, num_kyber=3)
+
+    identity = identity_key.IdentityKey(payload["identity_key"])
+    assert identity == store.get_identity_key_pair().identity_key()
+    assert payload["registration_id"] == store.get_local_registration_id()
+
+    signed_pre_key = payload["signed_pre_key"]
+    assert identity.public_key().verify_signature(
+        signed_pre_key["public_key"], signed_pre_key["signature"]
+    )
+    record = store.get_signed_pre_key(signed_pre_key["key_id"])
+    assert record.public_key().serialize() == signed_pre_key["public_key"]
+
+    assert len(payload["pre_keys"]) == 5
+    for pre_key in payload["pre_keys"]:
+        assert 1 <= pre_key["key_id"] <= account.MAX_PRE_KEY_ID
+        record = store.get_pre_key(pre_key["key_id"])
+        assert record.public_key().serialize() == pre_key["public_key"]
+
+    kyber_keys = payload["pq_pre_keys"] + [payload["pq_last_resort_pre_key"]]
+    assert len(kyber_keys) == 4
+    assert len({key["key_id"] for key in kyber_keys}) == 4
+    for kyber_key in kyber_keys:
+        assert identity.public_key().verify_signature(
+            kyber_key["public_key"], kyber_key["signature"]
+        )
+        record = store.get_kyber_pre_key(kyber_key["key_id"])
+        assert record.public_key() == kyber_key["public_key"]
+
+
+def test_provision_defaults():
+    _, payload = account.provision()
+
+    assert len(payload["pre_keys"]) == 100
+    assert len(payload["pq_pre_keys"]) == 100
+
+
+def test_provisioned_account_receives_messages():
+    bob_store, payload = account.provision(num_one_time=1, num_kyber=0)
+    bob_address = address.ProtocolAddress("+14151111112", 1)
+    alice_store = storage.InMemSignalProtocolStore.generate()
+    alice_address = address.ProtocolAddress("+14151111111", 1)
+
+    pre_key = payload["pre_keys"][0]
+    signed_pre_key = payload["signed_pre_key"]
+    bundle = state.PreKeyBundle(
+        payload["registration_id"],
+        1,
+        (pre_key["key_id"], curve.PublicKey.deserialize(pre_key["public_key"])),
+        signed_pre_key["key_id"],
+        curve.PublicKey.deserialize(signed_pre_key["public_key"]),
+        signed_pre_key["signature"],
+        identity_key.IdentityKey(payload["identity_key"]),
+    )
+    session.process_prekey_bundle(bob_address, alice_store, bundle)
+
+    ptext = b"welcome aboard"
+    message = session_cipher.message_encrypt(alice_store, bob_address, ptext)
+    assert session_cipher.message_decrypt(bob_store, alice_address, message) == ptext
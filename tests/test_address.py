@@ -1,4 +1,13 @@
-from signal_protocol import address
+import pickle
+import uuid
+
+import pytest
+
+from signal_protocol import address, session, storage
+from signal_protocol.error import InvalidArgumentException
+from signal_protocol.identity_key import IdentityKeyPair
+
+from tests.utils.sessions import create_pre_key_bundle
 
 DEVICE_ID = 1
 ALICE_PHONE_NUM = "+14151111111"
@@ -23,3 +32,174 @@ def test_basic_address_repr_representation():
 
     assert alice_address.name() in str(alice_address)
     assert str(alice_address.device_id()) in str(alice_address)
+
+
+ALICE_UUID = "9d0652a3-dcc3-4d11-975f-74d61598733f"
+
+
+def test_aci_encodings():
+    aci = address.Aci(ALICE_UUID)
+
+    assert isinstance(aci, address.ServiceId)
+    assert aci.kind() == "ACI"
+    assert aci.uuid() == uuid.UUID(ALICE_UUID)
+    assert aci.service_id_string() == ALICE_UUID
+    assert aci.service_id_binary() == uuid.UUID(ALICE_UUID).bytes
+    assert len(aci.service_id_fixed_width_binary()) == 17
+
+    assert address.Aci(uuid.UUID(ALICE_UUID)) == aci
+    assert address.Aci(uuid.UUID(ALICE_UUID).bytes) == aci
+
+
+def test_pni_encodings():
+    pni = address.Pni(ALICE_UUID)
+
+    assert pni.kind() == "PNI"
+    assert pni.service_id_string() == "PNI:" + ALICE_UUID
+    assert len(pni.service_id_binary()) == 17
+    assert pni != address.Aci(ALICE_UUID)
+
+
+def test_service_id_parse_round_trip():
+    for service_id in [address.Aci(ALICE_UUID), address.Pni(ALICE_UUID)]:
+        from_string = address.ServiceId.parse_from_service_id_string(
+            service_id.service_id_string()
+        )
+        from_binary = address.ServiceId.parse_from_service_id_binary(
+            service_id.service_id_binary()
+        )
+        from_fixed_width = address.ServiceId.parse_from_service_id_fixed_width_binary(
+            service_id.service_id_fixed_width_binary()
+        )
+
+        assert type(from_string) is type(service_id)
+        assert from_string == from_binary == from_fixed_width == service_id
+
+
+def test_service_id_pickle():
+    for service_id in [address.Aci(ALICE_UUID), address.Pni(ALICE_UUID)]:
+        restored = pickle.loads(pickle.dumps(service_id))
+
+        assert type(restored) is type(service_id)
+        assert restored == service_id
+        assert hash(restored) == hash(service_id)
+
+
+def test_service_id_rejects_invalid_input():
+    with pytest.raises(InvalidArgumentException):
+        address.ServiceId.parse_from_service_id_string("not a service id")
+
+    with pytest.raises(InvalidArgumentException):
+        address.ServiceId.parse_from_service_id_binary(b"\x00" * 5)
+
+    with pytest.raises(InvalidArgumentException):
+        address.Aci("not a uuid")
+
+
+def test_address_from_service_id():
+    aci = address.Aci(ALICE_UUID)
+    alice_address = address.ProtocolAddress.from_service_id(aci, DEVICE_ID)
+
+    assert alice_address.name() == ALICE_UUID
+    assert alice_address.device_id() == DEVICE_ID
+    assert alice_address.service_id() == aci
+
+    assert address.ProtocolAddress(ALICE_PHONE_NUM, DEVICE_ID).service_id() is None
+
+
+def test_address_as_dict_key():
+    routes = {address.ProtocolAddress(ALICE_PHONE_NUM, DEVICE_ID): "alice"}
+
+    assert routes[address.ProtocolAddress(ALICE_PHONE_NUM, DEVICE_ID)] == "alice"
+    assert address.ProtocolAddress(ALICE_PHONE_NUM, DEVICE_ID + 1) not in routes
+    assert address.ProtocolAddress(ALICE_PHONE_NUM, DEVICE_ID) != address.ProtocolAddress(
+        ALICE_PHONE_NUM, DEVICE_ID + 1
+    )
+
+
+def test_address_pickle():
+    alice_address = address.ProtocolAddress(ALICE_PHONE_NUM, DEVICE_ID)
+
+    restored = pickle.loads(pickle.dumps(alice_address))
+
+    assert restored == alice_address
+    assert hash(restored) == hash(alice_address)
+
+
+def test_equality_only_comparisons():
+    alice_address = address.ProtocolAddress(ALICE_PHONE_NUM, DEVICE_ID)
+    aci = address.Aci(ALICE_UUID)
+
+    assert alice_address != "not an address"
+    assert aci != ALICE_UUID
+    with pytest.raises(TypeError):
+        alice_address < alice_address
+    with pytest.raises(TypeError):
+        aci >= address.Pni(ALICE_UUID)
+
+
+def test_device_id_range():
+    assert int(address.DeviceId(1)) == 1
+    assert int(address.DeviceId(address.MAX_DEVICE_ID)) == address.MAX_DEVICE_ID
+
+    for invalid in [0, -1, address.MAX_DEVICE_ID + 1, 2**32, "1"]:
+        with pytest.raises(InvalidArgumentException):
+            address.DeviceId(invalid)
+
+    # Only DeviceId and DeviceRegistry enforce the range.
+    unchecked_address = address.ProtocolAddress(ALICE_PHONE_NUM, 10000)
+    assert unchecked_address.device_id() == 10000
+    with pytest.raises(InvalidArgumentException):
+        address.DeviceRegistry().update(unchecked_address, 1234)
+
+
+def test_device_id_behaves_as_int():
+    device_id = address.DeviceId(3)
+
+    assert device_id == 3
+    assert device_id != 4
+    assert device_id < 4
+    assert device_id == address.DeviceId(3)
+    assert hash(device_id) == hash(3)
+    assert [0, 1, 2, 3][device_id] == 3
+    assert pickle.loads(pickle.dumps(device_id)) == device_id
+
+    alice_address = address.ProtocolAddress(ALICE_PHONE_NUM, device_id)
+    assert alice_address.device_id() == 3
+
+
+def test_device_registry_detects_reregistration():
+    registry = address.DeviceRegistry()
+    alice_1 = address.ProtocolAddress(ALICE_PHONE_NUM, 1)
+    alice_2 = address.ProtocolAddress(ALICE_PHONE_NUM, 2)
+
+    assert not registry.update(alice_2, 1234)
+    assert not registry.update(alice_1, 5678)
+    assert not registry.update(alice_1, 5678)
+    assert registry.devices(ALICE_PHONE_NUM) == [1, 2]
+    assert registry.registration_id(alice_1) == 5678
+
+    # Same device, new registration id: the device was re-registered.
+    assert registry.update(alice_1, 9999)
+    assert registry.registration_id(alice_1) == 9999
+
+    assert registry.remove_device(alice_2)
+    assert not registry.remove_device(alice_2)
+    assert alice_2 not in registry
+    assert registry.devices(ALICE_PHONE_NUM) == [1]
+    assert registry.devices("+14152222222") == []
+
+
+def test_device_registry_from_session():
+    alice_store = storage.InMemSignalProtocolStore(IdentityKeyPair.generate(), 1)
+    bob_store = storage.InMemSignalProtocolStore(IdentityKeyPair.generate(), 4321)
+    bob_address = address.ProtocolAddress("+14151111112", DEVICE_ID)
+    session.process_prekey_bundle(
+        bob_address, alice_store, create_pre_key_bundle(bob_store)
+    )
+    registry = address.DeviceRegistry()
+
+    bob_session = alice_store.load_session(bob_address)
+    assert not registry.update_from_session(bob_address, bob_session)
+    assert registry.registration_id(bob_address) == 4321
+    assert registry.update(bob_address, 1234)
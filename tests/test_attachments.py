@@ -0,0 +1,217 @@
+import hashlib
+import hmac
+import io
+
+import pytest
+
+from signal_protocol import attachments
+from signal_protocol.error import InvalidArgumentException, SignalProtocolException
+
+
+def encrypt(plaintext, **kwargs):
+    ciphertext = io.BytesIO()
+    result = attachments.encrypt_attachment(io.BytesIO(plaintext), ciphertext, **kwargs)
+    return result, ciphertext.getvalue()
+
+
+def incremental_mac(key, ciphertext, chunk_size):
+    """The digest after every full chunk, then the digest of all data."""
+    mac = hmac.new(key[32:], digestmod=hashlib.sha256)
+    digests = b""
+    for start in range(0, len(ciphertext), chunk_size):
+        chunk = ciphertext[start : start + chunk_size]
+        mac.update(chunk)
+        if len(chunk) == chunk_size:
+            digests += mac.copy().digest()
+    return digests + mac.digest()
+
+
+def decrypt(ciphertext, key, digest, **kwargs):
+    plaintext = io.BytesIO()
+    written = attachments.decrypt_attachment(
+        io.BytesIO(ciphertext), plaintext, key, digest, **kwargs
+    )
+    assert written == len(plaintext.getvalue())
+    return plaintext.getvalue()
+
+
+def test_padded_size():
+    assert attachments.padded_size(0) == 541
+    assert attachments.padded_size(541) == 541
+    assert attachments.padded_size(542) == 568
+    for size in [1000, 123_456, 10_000_000]:
+        assert size <= attachments.padded_size(size) <= size * 1.05 + 1
+
+
+def test_round_trip():
+    plaintext = bytes(range(256)) * 1000
+    result, ciphertext = encrypt(plaintext)
+
+    assert len(result.key) == attachments.KEY_LENGTH
+    assert result.size == len(plaintext)
+    assert result.encrypted_size == len(ciphertext)
+    assert len(ciphertext) == attachments.encrypted_size(
+        attachments.padded_size(len(plaintext))
+    )
+    assert result.incremental_mac is None
+
+    padded = decrypt(ciphertext, result.key, result.digest)
+    assert padded[: len(plaintext)] == plaintext
+    assert padded[len(plaintext) :] == bytes(len(padded) - len(plaintext))
+    assert decrypt(ciphertext, result.key, result.digest, size=result.size) == plaintext
+
+
+def test_format():
+    key = attachments.generate_key()
+    result, ciphertext = encrypt(b"hello", key=key, pad=False)
+
+    assert result.key == key
+    # IV || one AES block || HMAC-SHA256(IV || ciphertext)
+    assert len(ciphertext) == 16 + 16 + 32
+    mac = hmac.new(key[32:], ciphertext[:-32], hashlib.sha256).digest()
+    assert ciphertext[-32:] == mac
+    assert result.digest == hashlib.sha256(ciphertext).digest()
+    assert decrypt(ciphertext, key, result.digest) == b"hello"
+
+
+def test_tampering_is_detected():
+    result, ciphertext = encrypt(b"attachment" * 100)
+
+    tampered = bytearray(ciphertext)
+    tampered[20] ^= 1
+    output = io.BytesIO()
+    with pytest.raises(SignalProtocolException, match="MAC"):
+        attachments.decrypt_attachment(
+            io.BytesIO(bytes(tampered)), output, result.key, result.digest
+        )
+    assert output.getvalue() == b""
+
+    with pytest.raises(SignalProtocolException, match="digest"):
+        decrypt(ciphertext, result.key, bytes(32))
+    with pytest.raises(SignalProtocolException, match="MAC"):
+        decrypt(ciphertext, attachments.generate_key(), result.digest)
+    with pytest.raises(SignalProtocolException):
+        decrypt(ciphertext[:-1], result.key, result.digest)
+
+
+def test_invalid_arguments():
+    with pytest.raises(InvalidArgumentException):
+        encrypt(b"hello", key=bytes(32))
+    with pytest.raises(InvalidArgumentException):
+        encrypt(b"hello", size=4)
+    with pytest.raises(InvalidArgumentException):
+        encrypt(b"hello", incremental_mac=True)
+
+    result, ciphertext = encrypt(b"hello")
+    with pytest.raises(InvalidArgumentException):
+        decrypt(ciphertext, result.key, result.digest[:16])
+    with pytest.raises(InvalidArgumentException):
+        decrypt(ciphertext, result.key, result.digest, chunk_size=1024)
+
+    result, ciphertext = encrypt(b"hello", incremental_mac=True)
+    for chunk_size in [0, 2 * 1024 * 1024 + 1, 2**62]:
+        with pytest.raises(InvalidArgumentException):
+            decrypt(
+                ciphertext,
+                result.key,
+                result.digest,
+                incremental_mac=result.incremental_mac,
+                chunk_size=chunk_size,
+            )
+
+
+def test_chunk_size():
+    assert attachments.chunk_size(0) == 64 * 1024
+    assert attachments.chunk_size(100 * 1024 * 1024) == 400 * 1024
+    assert attachments.chunk_size(1024 * 1024 * 1024) == 2 * 1024 * 1024
+
+
+@pytest.mark.parametrize("size", [0, 100, 64 * 1024 - 48, 300_000])
+def test_incremental_mac(size):
+    plaintext = b"\x42" * size
+    result, ciphertext = encrypt(plaintext, size=size, incremental_mac=True)
+
+    chunk_size = result.chunk_size
+    assert chunk_size == attachments.chunk_size(len(ciphertext))
+    chunks = len(ciphertext) // chunk_size + 1
+    assert len(result.incremental_mac) == 32 * chunks
+    assert result.incremental_mac == incremental_mac(result.key, ciphertext, chunk_size)
+
+    assert (
+        decrypt(
+            ciphertext,
+            result.key,
+            result.digest,
+            size=size,
+            incremental_mac=result.incremental_mac,
+            chunk_size=chunk_size,
+        )
+        == plaintext
+    )
+
+
+# Computed independently with the `cryptography` package and hmac, with an IV of
+# a0..af, for the plaintext b"Signal attachment KAT" without bucket padding and
+# a chunk size of 32. Official vectors were not available for comparison.
+KAT_KEY = bytes(range(64))
+KAT_PLAINTEXT = b"Signal attachment KAT"
+KAT_CHUNK_SIZE = 32
+KAT_CIPHERTEXT = bytes.fromhex(
+    "a0a1a2a3a4a5a6a7a8a9aaabacadaeafbe8b7cfb6f2ab9cb4766dd2a78bc8aff"
+    "5578777892a5fec81ecb50cf804f58f5919914cc39000e64d3b57882ccc608ae"
+    "03603ae03157f8c4effb886acb7f42e9"
+)
+KAT_DIGEST = bytes.fromhex(
+    "d2be9b475b57c4e7ffa14a06f09345cf368f7c60850bee4faed1e4f6295eade4"
+)
+KAT_INCREMENTAL_MAC = bytes.fromhex(
+    "fc79187e33f18a33fe36771fde0b6317451f7b2c03e16690d29712987a88353f"
+    "34c9bf8764a6a2dceb574cb2d7ede65ae9ddf2ffb1b28d890e1140233b30335a"
+    "3e60aca639979332772aa19a805cfc00b17d7ed34df18dd827d75f6e0805b947"
+)
+
+
+def test_known_answers():
+    assert hashlib.sha256(KAT_CIPHERTEXT).digest() == KAT_DIGEST
+    assert KAT_CIPHERTEXT[-32:] == hmac.new(
+        KAT_KEY[32:], KAT_CIPHERTEXT[:-32], hashlib.sha256
+    ).digest()
+    assert incremental_mac(KAT_KEY, KAT_CIPHERTEXT, KAT_CHUNK_SIZE) == KAT_INCREMENTAL_MAC
+
+    assert decrypt(KAT_CIPHERTEXT, KAT_KEY, KAT_DIGEST) == KAT_PLAINTEXT
+    assert (
+        decrypt(
+            KAT_CIPHERTEXT,
+            KAT_KEY,
+            KAT_DIGEST,
+            incremental_mac=KAT_INCREMENTAL_MAC,
+            chunk_size=KAT_CHUNK_SIZE,
+        )
+        == KAT_PLAINTEXT
+    )
+
+    # Encrypting the same plaintext and key only differs in the random IV.
+    result, ciphertext = encrypt(KAT_PLAINTEXT, key=KAT_KEY, pad=False)
+    assert len(ciphertext) == len(KAT_CIPHERTEXT)
+    assert result.digest == hashlib.sha256(ciphertext).digest()
+
+
+def test_incremental_mac_verifies_each_chunk():
+    plaintext = b"\x42" * 300_000
+    result, ciphertext = encrypt(plaintext, size=len(plaintext), incremental_mac=True)
+
+    # Corrupt the last chunk: the chunks before it are still written.
+    tampered = bytearray(ciphertext)
+    tampered[-100] ^= 1
+    output = io.BytesIO()
+    with pytest.raises(SignalProtocolException, match="incremental MAC"):
+        attachments.decrypt_attachment(
+            io.BytesIO(bytes(tampered)),
+            output,
+            result.key,
+            result.digest,
+            incremental_mac=result.incremental_mac,
+            chunk_size=result.chunk_size,
+        )
+    assert 0 < len(output.getvalue()) < len(plaintext)
+    assert plaintext.startswith(output.getvalue())
@@ -0,0 +1,142 @@
+import array
+import io
+import sys
+
+import pytest
+
+from signal_protocol import address, attachments, curve, group_cipher, protocol, session_cipher, storage
+from signal_protocol.error import InvalidArgumentException
+
+from tests.utils.sessions import initialize_sessions_v3
+
+DISTRIBUTION_ID = "a6fe9593-2ca5-41bc-99e9-60a436fbef77"
+
+
+def session_stores():
+    alice_address = address.ProtocolAddress("+14159999999", 1)
+    bob_address = address.ProtocolAddress("+14158888888", 1)
+    alice_store = storage.InMemSignalProtocolStore.generate()
+    bob_store = storage.InMemSignalProtocolStore.generate()
+    alice_session, bob_session = initialize_sessions_v3()
+    alice_store.store_session(bob_address, alice_session)
+    bob_store.store_session(alice_address, bob_session)
+    return alice_address, alice_store, bob_address, bob_store
+
+
+@pytest.mark.parametrize(
+    "wrap",
+    [bytes, bytearray, memoryview, lambda data: array.array("B", data)],
+)
+def test_buffer_protocol_input(wrap):
+    alice_address, alice_store, bob_address, bob_store = session_stores()
+
+    ctext = session_cipher.message_encrypt(alice_store, bob_address, wrap(b"hello"))
+    message = protocol.SignalMessage.try_from(wrap(ctext.serialize()))
+    assert session_cipher.message_decrypt(bob_store, alice_address, message) == b"hello"
+
+    key_pair = curve.KeyPair.generate()
+    signature = key_pair.private_key().calculate_signature(wrap(b"message"))
+    assert key_pair.public_key().verify_signature(wrap(b"message"), wrap(signature))
+    assert curve.PublicKey.deserialize(wrap(key_pair.public_key().serialize()))
+
+
+def test_buffer_protocol_input_slices():
+    alice_address, alice_store, bob_address, bob_store = session_stores()
+    payload = bytearray(b"header" + b"x" * 4096)
+
+    ctext = session_cipher.message_encrypt(
+        alice_store, bob_address, memoryview(payload)[6:]
+    )
+    assert session_cipher.message_decrypt(bob_store, alice_address, ctext) == b"x" * 4096
+
+    ctext = session_cipher.message_encrypt(
+        alice_store, bob_address, memoryview(payload).toreadonly()[:6]
+    )
+    assert session_cipher.message_decrypt(bob_store, alice_address, ctext) == b"header"
+
+
+@pytest.mark.skipif(sys.version_info < (3, 12), reason="__buffer__ needs Python 3.12")
+def test_bytes_subclass_buffers_are_copied():
+    class MutableBytes(bytes):
+        """Bytes whose buffer is a view on a bytearray."""
+
+        def __buffer__(self, flags):
+            return memoryview(self.backing)
+
+    plaintext = b"\x42" * 100
+    ciphertext = io.BytesIO()
+    result = attachments.encrypt_attachment(io.BytesIO(plaintext), ciphertext)
+    key = MutableBytes(result.key)
+    key.backing = bytearray(result.key)
+
+    class Writer(io.BytesIO):
+        def write(self, data):
+            # Resizing fails while the key's buffer is still exported.
+            key.backing.append(0)
+            key.backing.pop()
+            return super().write(data)
+
+    output = Writer()
+    attachments.decrypt_attachment(
+        io.BytesIO(ciphertext.getvalue()), output, key, result.digest
+    )
+    assert output.getvalue() == plaintext
+
+
+def test_buffer_protocol_rejects_other_objects():
+    alice_address, alice_store, bob_address, bob_store = session_stores()
+
+    with pytest.raises(TypeError):
+        session_cipher.message_encrypt(alice_store, bob_address, "hello")
+    with pytest.raises(BufferError):
+        session_cipher.message_encrypt(
+            alice_store, bob_address, memoryview(b"hello world")[::2]
+        )
+
+
+def test_session_cipher_out_buffer():
+    alice_address, alice_store, bob_address, bob_store = session_stores()
+    out = bytearray(1024)
+
+    written, message_type = session_cipher.message_encrypt(
+        alice_store, bob_address, b"hello", out=out
+    )
+    assert message_type == 2  # CiphertextMessageType::Whisper => 2
+    message = protocol.SignalMessage.try_from(memoryview(out)[:written])
+
+    plaintext = bytearray(16)
+    assert session_cipher.message_decrypt(bob_store, alice_address, message, out=plaintext) == 5
+    assert plaintext[:5] == b"hello"
+
+    ctext = session_cipher.message_encrypt(alice_store, bob_address, b"hello again")
+    with pytest.raises(InvalidArgumentException):
+        session_cipher.message_decrypt(bob_store, alice_address, ctext, out=bytearray(4))
+    with pytest.raises(BufferError):
+        session_cipher.message_encrypt(alice_store, bob_address, b"hello", out=b"\x00" * 1024)
+
+
+def test_group_cipher_out_buffer():
+    sender_address = address.ProtocolAddress("+14159999111", 1)
+    alice_store = storage.InMemSignalProtocolStore.generate()
+    bob_store = storage.InMemSignalProtocolStore.generate()
+    group_cipher.process_sender_key_distribution_message(
+        sender_address,
+        group_cipher.create_sender_key_distribution_message(
+            sender_address, DISTRIBUTION_ID, alice_store
+        ),
+        bob_store,
+    )
+
+    ctext = array.array("B", bytes(512))
+    written = group_cipher.group_encrypt(
+        alice_store, sender_address, DISTRIBUTION_ID, bytearray(b"hello"), out=ctext
+    )
+
+    plaintext = bytearray(5)
+    assert (
+        group_cipher.group_decrypt(
+            memoryview(ctext)[:written], bob_store, sender_address, out=plaintext
+        )
+        == 5
+    )
+    assert plaintext == b"hello"
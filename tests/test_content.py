@@ -0,0 +1,135 @@
+import uuid
+
+import pytest
+
+from signal_protocol import address, content, session_cipher, storage
+from signal_protocol.error import InvalidArgumentException, SignalProtocolException
+
+from tests.utils.sessions import initialize_sessions_v3
+
+ACI = "9d0652a3-dcc3-4d11-975f-74d61598733f"
+
+
+@pytest.mark.parametrize("length", [0, 1, 157, 158, 159, 318, 1000])
+def test_pad(length):
+    plaintext = b"\x01" * length
+    padded = content.pad(plaintext)
+
+    assert len(padded) % content.PADDING_BLOCK_SIZE == content.PADDING_BLOCK_SIZE - 1
+    assert len(padded) > length
+    assert padded[length] == 0x80
+    assert padded[length + 1 :] == bytes(len(padded) - length - 1)
+    assert content.unpad(padded) == plaintext
+
+
+def test_unpad_rejects_invalid_padding():
+    with pytest.raises(SignalProtocolException):
+        content.unpad(b"hello")
+    with pytest.raises(SignalProtocolException):
+        content.unpad(bytes(159))
+
+
+def test_content_round_trip():
+    message = content.Content(
+        data_message=content.DataMessage(
+            1700000000000, body="hello", expire_timer=3600, profile_key=bytes(32)
+        )
+    )
+    parsed = content.Content.deserialize(message.serialize())
+
+    data_message = parsed.data_message
+    assert data_message.timestamp == 1700000000000
+    assert data_message.body == "hello"
+    assert data_message.expire_timer == 3600
+    assert data_message.profile_key == bytes(32)
+    assert data_message.flags == 0
+    assert parsed.receipt_message is None
+    assert not parsed.null_message
+
+
+def test_content_kinds():
+    receipt = content.Content(
+        receipt_message=content.ReceiptMessage(content.ReceiptType.Read, [1, 2, 3])
+    )
+    parsed = content.Content.deserialize(receipt.serialize()).receipt_message
+    assert parsed.type == content.ReceiptType.Read
+    assert parsed.timestamps == [1, 2, 3]
+
+    typing = content.Content(
+        typing_message=content.TypingMessage(content.TypingAction.Stopped, 5)
+    )
+    parsed = content.Content.deserialize(typing.serialize()).typing_message
+    assert parsed.action == content.TypingAction.Stopped
+    assert parsed.timestamp == 5
+    assert parsed.group_id is None
+
+    null = content.Content(null_message=True)
+    assert content.Content.deserialize(null.serialize()).null_message
+
+    skdm = content.Content(sender_key_distribution_message=b"\x33" * 10)
+    assert (
+        content.Content.deserialize(skdm.serialize()).sender_key_distribution_message
+        == b"\x33" * 10
+    )
+
+    with pytest.raises(InvalidArgumentException):
+        content.Content()
+
+
+def test_envelope_round_trip():
+    envelope = content.Envelope(
+        content.EnvelopeType.PrekeyBundle,
+        1700000000000,
+        content=b"ciphertext",
+        source_service_id=address.Aci(ACI),
+        source_device=2,
+        server_guid="guid",
+    )
+    parsed = content.Envelope.deserialize(envelope.serialize())
+
+    assert parsed.type == content.EnvelopeType.PrekeyBundle
+    assert parsed.timestamp == 1700000000000
+    assert parsed.content == b"ciphertext"
+    assert parsed.source_service_id == address.Aci(uuid.UUID(ACI))
+    assert isinstance(parsed.source_service_id, address.Aci)
+    assert parsed.source_device == 2
+    assert parsed.destination_service_id is None
+    assert parsed.server_guid == "guid"
+    assert parsed.server_timestamp is None
+    assert parsed.urgent
+
+
+def test_envelope_type_from_message_type():
+    assert content.EnvelopeType.from_message_type(2) == content.EnvelopeType.Ciphertext
+    assert content.EnvelopeType.from_message_type(3) == content.EnvelopeType.PrekeyBundle
+    with pytest.raises(InvalidArgumentException):
+        content.EnvelopeType.from_message_type(5)
+
+
+def test_encrypt_content():
+    alice_address = address.ProtocolAddress(ACI, 1)
+    bob_address = address.ProtocolAddress("+14158888888", 1)
+    alice_store = storage.InMemSignalProtocolStore.generate()
+    bob_store = storage.InMemSignalProtocolStore.generate()
+    alice_session, bob_session = initialize_sessions_v3()
+    alice_store.store_session(bob_address, alice_session)
+    bob_store.store_session(alice_address, bob_session)
+
+    message = content.Content(data_message=content.DataMessage(42, body="hi"))
+    ciphertext = session_cipher.message_encrypt(
+        alice_store, bob_address, content.pad(message.serialize())
+    )
+    envelope = content.Envelope(
+        content.EnvelopeType.from_message_type(ciphertext.message_type()),
+        42,
+        content=ciphertext.serialize(),
+        source_service_id=address.Aci(ACI),
+        source_device=1,
+    )
+
+    received = content.Envelope.deserialize(envelope.serialize())
+    assert received.type == content.EnvelopeType.Ciphertext
+    plaintext = session_cipher.decrypt_batch(
+        bob_store, [(alice_address, 2, received.content)]
+    )[0]
+    assert content.Content.deserialize(content.unpad(plaintext)).data_message.body == "hi"
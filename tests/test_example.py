@@ -12,7 +12,7 @@ def test_example_doc():
     pre_key_pair = curve.KeyPair.generate()
 
     # Clients must generate a registration_id and store it somewhere safe and persistent.
-    registration_id = 12  # TODO generate (not yet supported in upstream crate)
+    registration_id = identity_key.generate_registration_id()
 
     # The InMemSignalProtocolStore is a single object which provide the four storage
     # interfaces required: IdentityKeyStore (for one's own identity key state and the (public)
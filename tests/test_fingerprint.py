@@ -1,4 +1,9 @@
-from signal_protocol import identity_key, fingerprint
+import uuid
+
+import pytest
+
+from signal_protocol import address, identity_key, fingerprint
+from signal_protocol.error import InvalidArgumentException, SignalProtocolException
 
 
 def test_fingerprint_equality():
@@ -38,3 +43,112 @@ def test_fingerprint_equality():
         alice_key,
     )
     assert str(alice_fingerprint) != str(eve_fingerprint)
+
+
+def make_fingerprints(version=2):
+    iterations = 5200
+    alice_stable_id = b"+14152222222"
+    bob_stable_id = b"+14153333333"
+    alice_key = identity_key.IdentityKeyPair.generate().identity_key()
+    bob_key = identity_key.IdentityKeyPair.generate().identity_key()
+
+    alice_fingerprint = fingerprint.Fingerprint(
+        version, iterations, alice_stable_id, alice_key, bob_stable_id, bob_key
+    )
+    bob_fingerprint = fingerprint.Fingerprint(
+        version, iterations, bob_stable_id, bob_key, alice_stable_id, alice_key
+    )
+    return alice_fingerprint, bob_fingerprint
+
+
+def test_displayable_fingerprint_halves():
+    alice_fingerprint, bob_fingerprint = make_fingerprints()
+
+    alice_display = alice_fingerprint.displayable()
+    bob_display = bob_fingerprint.displayable()
+
+    assert len(alice_display.local()) == 30
+    assert alice_display.local() == bob_display.remote()
+    assert alice_display.remote() == bob_display.local()
+    assert str(alice_display) == alice_fingerprint.display_string()
+    assert str(bob_display) == alice_fingerprint.display_string()
+
+
+def test_scannable_fingerprint_compare():
+    alice_fingerprint, bob_fingerprint = make_fingerprints()
+
+    alice_scannable = alice_fingerprint.scannable()
+    bob_scannable = bob_fingerprint.scannable()
+    assert alice_scannable.version() == 2
+    assert alice_scannable.local_fingerprint() == bob_scannable.remote_fingerprint()
+
+    assert alice_scannable.compare(bob_scannable)
+    assert alice_scannable.compare(bob_fingerprint.qr_payload())
+    assert not alice_scannable.compare(alice_scannable)
+
+    scanned = fingerprint.ScannableFingerprint.deserialize(bob_fingerprint.qr_payload())
+    assert alice_scannable.compare(scanned)
+
+    eve_fingerprint, _ = make_fingerprints()
+    assert not alice_scannable.compare(eve_fingerprint.scannable())
+
+
+def test_scannable_fingerprint_version_mismatch():
+    alice_fingerprint, _ = make_fingerprints(version=2)
+    _, bob_fingerprint = make_fingerprints(version=1)
+
+    with pytest.raises(SignalProtocolException, match="version"):
+        alice_fingerprint.scannable().compare(bob_fingerprint.scannable())
+
+
+def test_safety_number_v2_uses_binary_aci():
+    alice_aci = "9d0652a3-dcc3-4d11-975f-74d61598733f"
+    bob_aci = "796abedb-ca4e-4f18-8803-1fde5b921f9f"
+    alice_address = address.ProtocolAddress(alice_aci, 1)
+    bob_address = address.ProtocolAddress(bob_aci, 1)
+    alice_key = identity_key.IdentityKeyPair.generate().identity_key()
+    bob_key = identity_key.IdentityKeyPair.generate().identity_key()
+
+    alice_number = fingerprint.safety_number(alice_address, alice_key, bob_address, bob_key)
+    bob_number = fingerprint.safety_number(bob_address, bob_key, alice_address, alice_key)
+    assert alice_number.display_string() == bob_number.display_string()
+    assert alice_number.scannable().compare(bob_number.scannable())
+
+    expected = fingerprint.Fingerprint(
+        2,
+        fingerprint.SAFETY_NUMBER_ITERATIONS,
+        uuid.UUID(alice_aci).bytes,
+        alice_key,
+        uuid.UUID(bob_aci).bytes,
+        bob_key,
+    )
+    assert alice_number.display_string() == expected.display_string()
+
+
+def test_safety_number_v1_uses_e164():
+    alice_address = address.ProtocolAddress("+14152222222", 1)
+    bob_address = address.ProtocolAddress("+14153333333", 1)
+    alice_key = identity_key.IdentityKeyPair.generate().identity_key()
+    bob_key = identity_key.IdentityKeyPair.generate().identity_key()
+
+    number = fingerprint.safety_number(
+        alice_address, alice_key, bob_address, bob_key, version=1
+    )
+    expected = fingerprint.Fingerprint(
+        1, 5200, b"+14152222222", alice_key, b"+14153333333", bob_key
+    )
+    assert number.display_string() == expected.display_string()
+    assert number.scannable().version() == 1
+
+
+def test_safety_number_rejects_mismatched_address_kinds():
+    aci_address = address.ProtocolAddress("9d0652a3-dcc3-4d11-975f-74d61598733f", 1)
+    e164_address = address.ProtocolAddress("+14153333333", 1)
+    key = identity_key.IdentityKeyPair.generate().identity_key()
+
+    with pytest.raises(InvalidArgumentException, match="ACI"):
+        fingerprint.safety_number(aci_address, key, e164_address, key)
+    with pytest.raises(InvalidArgumentException, match="E.164"):
+        fingerprint.safety_number(aci_address, key, e164_address, key, version=1)
+    with pytest.raises(InvalidArgumentException, match="version 3"):
+        fingerprint.safety_number(aci_address, key, aci_address, key, version=3)
@@ -0,0 +1,296 @@
+import random
+import uuid
+
+from signal_protocol import (
+    address,
+    curve,
+    fingerprint,
+    group_cipher,
+    group_session,
+    identity_key,
+    protocol,
+    sealed_sender,
+    sender_keys,
+    state,
+    storage,
+)
+
+from tests.utils.protocol import create_signal_message
+
+ITERATIONS = 200
+
+
+def random_bytes():
+    return random.choice(
+        [
+            b"",
+            b"\x05",
+            bytes(16),
+            bytes(31),
+            bytes(33),
+            b"\x05" + bytes(31),
+            b"\x05" + bytes(33),
+            bytes(random.getrandbits(8) for _ in range(random.randint(0, 128))),
+        ]
+    )
+
+
+def random_int():
+    # Mostly out of range for the u8/u16/u32/u64 parameters they are passed to.
+    return random.choice(
+        [0, -1, 2**7, 2**8, 2**16, 2**31, 2**32 - 1, 2**32, 2**63, 2**64, -(2**64)]
+    )
+
+
+def random_str():
+    return random.choice(
+        ["", "garbage", "\x00", "a6fe9593-2ca5-41bc-99e9-60a436fbef7", "+" * 1000]
+    )
+
+
+def random_uuid():
+    return random.choice(
+        [
+            "",
+            "a6fe9593-2ca5-41bc-99e9-60a436fbef7",
+            "a6fe9593-2ca5-41bc-99e9-60a436fbef7g",
+            bytes(15),
+            bytes(17),
+            uuid.UUID(int=0),
+            uuid.uuid4(),
+        ]
+    )
+
+
+# Keys are of the expected class but never match each other: a public key
+# is never the one of the private key it is combined with.
+def random_public_key():
+    return curve.KeyPair.generate().public_key()
+
+
+def random_private_key():
+    return curve.KeyPair.generate().private_key()
+
+
+def random_identity_key():
+    return identity_key.IdentityKeyPair.generate().identity_key()
+
+
+def random_key_pair():
+    return curve.KeyPair(random_public_key(), random_private_key())
+
+
+def random_address():
+    return address.ProtocolAddress(random_str(), random.choice([1, 2, 127]))
+
+
+def random_addresses():
+    return [random_address() for _ in range(random.randint(0, 3))]
+
+
+def random_store():
+    return storage.InMemSignalProtocolStore(identity_key.IdentityKeyPair.generate(), 1)
+
+
+def random_pre_key():
+    return random.choice([None, (random_int(), random_public_key())])
+
+
+def random_optional_int():
+    return random.choice([None, random_int()])
+
+
+def random_optional_str():
+    return random.choice([None, random_str()])
+
+
+def random_signal_message():
+    return create_signal_message()
+
+
+def random_server_certificate():
+    return sealed_sender.ServerCertificate(
+        1, random_public_key(), random_private_key()
+    )
+
+
+def random_sender_certificate():
+    return sealed_sender.SenderCertificate(
+        str(uuid.uuid4()),
+        None,
+        random_public_key(),
+        1,
+        0,
+        random_server_certificate(),
+        random_private_key(),
+    )
+
+
+CONSTRUCTORS = [
+    (address.ProtocolAddress, [random_str, random_int]),
+    (address.Aci, [random_uuid]),
+    (address.Pni, [random_uuid]),
+    (address.ServiceId.parse_from_service_id_string, [random_str]),
+    (curve.KeyPair, [random_public_key, random_private_key]),
+    (curve.KeyPair.from_public_and_private, [random_bytes, random_bytes]),
+    (curve.PublicKey.deserialize, [random_bytes]),
+    (curve.PrivateKey.deserialize, [random_bytes]),
+    (identity_key.IdentityKey, [random_bytes]),
+    (identity_key.IdentityKeyPair, [random_identity_key, random_private_key]),
+    (identity_key.IdentityKeyPair.from_bytes, [random_bytes]),
+    (
+        fingerprint.Fingerprint,
+        [
+            random_int,
+            random_int,
+            random_bytes,
+            random_identity_key,
+            random_bytes,
+            random_identity_key,
+        ],
+    ),
+    (protocol.PreKeySignalMessage.try_from, [random_bytes]),
+    (protocol.SignalMessage.try_from, [random_bytes]),
+    (protocol.SenderKeyMessage.try_from, [random_bytes]),
+    (protocol.SenderKeyDistributionMessage.try_from, [random_bytes]),
+    (
+        protocol.PreKeySignalMessage,
+        [
+            random_int,
+            random_int,
+            random_optional_int,
+            random_int,
+            random_public_key,
+            random_identity_key,
+            random_signal_message,
+        ],
+    ),
+    (
+        protocol.SenderKeyMessage,
+        [
+            random_int,
+            random_uuid,
+            random_int,
+            random_int,
+            random_bytes,
+            random_private_key,
+        ],
+    ),
+    (
+        protocol.SenderKeyDistributionMessage,
+        [
+            random_int,
+            random_uuid,
+            random_int,
+            random_int,
+            random_bytes,
+            random_public_key,
+        ],
+    ),
+    (
+        protocol.SignalMessage,
+        [
+            random_int,
+            random_bytes,
+            random_public_key,
+            random_int,
+            random_int,
+            random_bytes,
+            random_identity_key,
+            random_identity_key,
+        ],
+    ),
+    (
+        state.PreKeyBundle,
+        [
+            random_int,
+            random_int,
+            random_pre_key,
+            random_int,
+            random_public_key,
+            random_bytes,
+            random_identity_key,
+        ],
+    ),
+    (state.PreKeyRecord, [random_int, random_key_pair]),
+    (state.PreKeyRecord.deserialize, [random_bytes]),
+    (
+        state.SignedPreKeyRecord,
+        [random_int, random_int, random_key_pair, random_bytes],
+    ),
+    (state.SignedPreKeyRecord.deserialize, [random_bytes]),
+    (state.SessionRecord.deserialize, [random_bytes]),
+    (state.generate_n_prekeys, [random_int, random_int]),
+    (sender_keys.SenderKeyRecord.deserialize, [random_bytes]),
+    (
+        sealed_sender.ServerCertificate,
+        [random_int, random_public_key, random_private_key],
+    ),
+    (sealed_sender.ServerCertificate.deserialize, [random_bytes]),
+    (
+        sealed_sender.SenderCertificate,
+        [
+            random_str,
+            random_optional_str,
+            random_public_key,
+            random_int,
+            random_int,
+            random_server_certificate,
+            random_private_key,
+        ],
+    ),
+    (sealed_sender.SenderCertificate.deserialize, [random_bytes]),
+    (
+        sealed_sender.UnidentifiedSenderMessageContent,
+        [random_int, random_sender_certificate, random_bytes, random_int, random_bytes],
+    ),
+    (sealed_sender.UnidentifiedSenderMessageContent.deserialize, [random_bytes]),
+    (
+        storage.InMemSignalProtocolStore,
+        [identity_key.IdentityKeyPair.generate, random_int],
+    ),
+    (group_session.GroupSession, [random_address, random_uuid, random_addresses]),
+    (
+        group_cipher.group_encrypt,
+        [random_store, random_address, random_uuid, random_bytes],
+    ),
+    (group_cipher.group_decrypt, [random_bytes, random_store, random_address]),
+    (
+        group_cipher.create_sender_key_distribution_message,
+        [random_address, random_uuid, random_store],
+    ),
+    (group_cipher.rotate_sender_key, [random_address, random_uuid, random_store]),
+]
+
+
+def test_constructors_never_panic_on_invalid_arguments():
+    # Every argument has the type the constructor expects, with invalid
+    # contents: wrong-length bytes, out-of-range integers, malformed UUIDs and
+    # keys that do not belong together.
+    #
+    # A Rust panic surfaces as pyo3_runtime.PanicException, which derives from
+    # BaseException and is therefore not swallowed by `except Exception`.
+    for constructor, generators in CONSTRUCTORS:
+        for _ in range(ITERATIONS):
+            args = [generate() for generate in generators]
+            try:
+                constructor(*args)
+            except Exception:
+                pass
+
+
+def test_deserializers_never_panic_on_random_bytes():
+    deserializers = [
+        constructor
+        for constructor, generators in CONSTRUCTORS
+        if generators == [random_bytes]
+    ]
+    for deserializer in deserializers:
+        for _ in range(ITERATIONS):
+            data = bytes(
+                random.getrandbits(8) for _ in range(random.randint(0, 256))
+            )
+            try:
+                deserializer(data)
+            except Exception:
+                pass
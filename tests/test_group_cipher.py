@@ -1,13 +1,16 @@
 import pytest
 import random
+import uuid
 
 from signal_protocol.address import ProtocolAddress
-from signal_protocol.error import SignalProtocolException
+from signal_protocol.error import InvalidArgumentException, SignalProtocolException
 from signal_protocol.group_cipher import (
     create_sender_key_distribution_message,
     group_decrypt,
+    group_decrypt_batch,
     group_encrypt,
     process_sender_key_distribution_message,
+    rotate_sender_key,
 )
 from signal_protocol.identity_key import IdentityKeyPair
 from signal_protocol.storage import InMemSignalProtocolStore
@@ -309,3 +312,186 @@ def test_group_message_key_limit():
 
     with pytest.raises(SignalProtocolException, match="message with old counter"):
         assert group_decrypt(ciphertexts[0], bob_store, sender_address).decode("utf8")
+
+
+def test_group_sender_key_record_describe():
+    sender_address = ProtocolAddress("+14159999111", DEVICE_ID)
+    distribution_id = "a6fe9593-2ca5-41bc-99e9-60a436fbef77"
+
+    alice_store = InMemSignalProtocolStore(IdentityKeyPair.generate(), 1)
+    sent_distribution_message = create_sender_key_distribution_message(
+        sender_address, distribution_id, alice_store
+    )
+    for i in range(3):
+        group_encrypt(alice_store, sender_address, distribution_id, b"hello")
+
+    description = alice_store.load_sender_key(sender_address, distribution_id).describe()
+    assert description["chain_id"] == sent_distribution_message.id()
+    assert description["iteration"] == 3
+    assert description["signing_key"] == sent_distribution_message.signing_key().serialize().hex()
+    assert description["state_count"] == 1
+    assert description["states"][0]["has_signing_private_key"]
+    assert "chain_key" not in description["states"][0]
+
+    unredacted = alice_store.load_sender_key(sender_address, distribution_id).describe(
+        redact=False
+    )
+    assert len(unredacted["states"][0]["chain_key"]) == 64
+
+
+def test_group_rotate_sender_key_revokes_removed_member():
+    sender_address = ProtocolAddress("+14159999111", DEVICE_ID)
+    distribution_id = "a6fe9593-2ca5-41bc-99e9-60a436fbef77"
+
+    alice_store = InMemSignalProtocolStore(IdentityKeyPair.generate(), 1)
+    bob_store = InMemSignalProtocolStore(IdentityKeyPair.generate(), 2)
+    carol_store = InMemSignalProtocolStore(IdentityKeyPair.generate(), 3)
+
+    first_distribution_message = create_sender_key_distribution_message(
+        sender_address, distribution_id, alice_store
+    )
+    for store in (bob_store, carol_store):
+        process_sender_key_distribution_message(
+            sender_address, first_distribution_message, store
+        )
+
+    # Carol leaves the group
+    second_distribution_message = rotate_sender_key(
+        sender_address, distribution_id, alice_store
+    )
+    assert second_distribution_message.id() != first_distribution_message.id()
+    process_sender_key_distribution_message(
+        sender_address, second_distribution_message, bob_store
+    )
+
+    alice_ciphertext = group_encrypt(
+        alice_store, sender_address, distribution_id, b"carol is gone"
+    )
+    assert group_decrypt(alice_ciphertext, bob_store, sender_address) == b"carol is gone"
+    with pytest.raises(SignalProtocolException, match="missing sender key state"):
+        group_decrypt(alice_ciphertext, carol_store, sender_address)
+
+
+def test_group_remove_sender_key():
+    sender_address = ProtocolAddress("+14159999111", DEVICE_ID)
+    distribution_id = "a6fe9593-2ca5-41bc-99e9-60a436fbef77"
+
+    alice_store = InMemSignalProtocolStore(IdentityKeyPair.generate(), 1)
+    bob_store = InMemSignalProtocolStore(IdentityKeyPair.generate(), 2)
+
+    distribution_message = create_sender_key_distribution_message(
+        sender_address, distribution_id, alice_store
+    )
+    process_sender_key_distribution_message(
+        sender_address, distribution_message, bob_store
+    )
+    alice_ciphertext = group_encrypt(alice_store, sender_address, distribution_id, b"hi")
+
+    assert bob_store.remove_sender_key(sender_address, distribution_id)
+    assert not bob_store.remove_sender_key(sender_address, distribution_id)
+    assert bob_store.load_sender_key(sender_address, distribution_id) is None
+
+    with pytest.raises(SignalProtocolException, match="missing sender key state"):
+        group_decrypt(alice_ciphertext, bob_store, sender_address)
+
+
+def test_group_distribution_id_types():
+    sender_address = ProtocolAddress("+14159999111", DEVICE_ID)
+    distribution_id = uuid.UUID("a6fe9593-2ca5-41bc-99e9-60a436fbef77")
+
+    alice_store = InMemSignalProtocolStore(IdentityKeyPair.generate(), 1)
+    bob_store = InMemSignalProtocolStore(IdentityKeyPair.generate(), 2)
+
+    distribution_message = create_sender_key_distribution_message(
+        sender_address, distribution_id, alice_store
+    )
+    process_sender_key_distribution_message(
+        sender_address, distribution_message, bob_store
+    )
+
+    # The same distribution id given as uuid.UUID, bytes and str
+    for same_id in (distribution_id, distribution_id.bytes, str(distribution_id)):
+        ciphertext = group_encrypt(alice_store, sender_address, same_id, b"hello")
+        assert group_decrypt(ciphertext, bob_store, sender_address) == b"hello"
+        assert alice_store.load_sender_key(sender_address, same_id) is not None
+
+
+@pytest.mark.parametrize(
+    "distribution_id",
+    ["not-a-uuid", "a6fe9593-2ca5-41bc-99e9-60a436fbef7", bytes(15), 42, None],
+)
+def test_group_invalid_distribution_id(distribution_id):
+    sender_address = ProtocolAddress("+14159999111", DEVICE_ID)
+    alice_store = InMemSignalProtocolStore(IdentityKeyPair.generate(), 1)
+
+    with pytest.raises(InvalidArgumentException, match="invalid distribution id"):
+        group_encrypt(alice_store, sender_address, distribution_id, b"hello")
+    with pytest.raises(InvalidArgumentException, match="invalid distribution id"):
+        create_sender_key_distribution_message(
+            sender_address, distribution_id, alice_store
+        )
+    with pytest.raises(InvalidArgumentException, match="invalid distribution id"):
+        alice_store.load_sender_key(sender_address, distribution_id)
+
+
+def test_group_decrypt_transaction():
+    sender_address = ProtocolAddress("+14159999111", DEVICE_ID)
+    distribution_id = "a6fe9593-2ca5-41bc-99e9-60a436fbef77"
+    alice_store = InMemSignalProtocolStore(IdentityKeyPair.generate(), 1)
+    bob_store = InMemSignalProtocolStore(IdentityKeyPair.generate(), 2)
+
+    distribution_message = create_sender_key_distribution_message(
+        sender_address, distribution_id, alice_store
+    )
+    with bob_store.transaction() as tx:
+        process_sender_key_distribution_message(
+            sender_address, distribution_message, tx
+        )
+        tx.commit()
+
+    ciphertext = group_encrypt(alice_store, sender_address, distribution_id, b"hello")
+    before = bob_store.load_sender_key(sender_address, distribution_id).serialize()
+
+    with bob_store.transaction() as tx:
+        assert group_decrypt(ciphertext, tx, sender_address) == b"hello"
+    assert bob_store.load_sender_key(sender_address, distribution_id).serialize() == before
+
+    with bob_store.transaction() as tx:
+        assert group_decrypt(ciphertext, tx, sender_address) == b"hello"
+        tx.commit()
+    with pytest.raises(SignalProtocolException, match="message with old counter"):
+        group_decrypt(ciphertext, bob_store, sender_address)
+
+
+def test_group_decrypt_batch():
+    distribution_id = "a6fe9593-2ca5-41bc-99e9-60a436fbef77"
+    bob_store = InMemSignalProtocolStore(IdentityKeyPair.generate(), 2)
+
+    messages = []
+    for i in range(3):
+        sender_address = ProtocolAddress(f"+1415999911{i}", DEVICE_ID)
+        sender_store = InMemSignalProtocolStore(IdentityKeyPair.generate(), 1)
+        distribution_message = create_sender_key_distribution_message(
+            sender_address, distribution_id, sender_store
+        )
+        process_sender_key_distribution_message(
+            sender_address, distribution_message, bob_store
+        )
+        for n in range(5):
+            ciphertext = group_encrypt(
+                sender_store, sender_address, distribution_id, b"msg %d %d" % (i, n)
+            )
+            messages.append((sender_address, ciphertext))
+    # A replayed message and an unknown sender fail without stopping the batch.
+    messages.insert(4, messages[0])
+    messages.append((ProtocolAddress("+14150000000", DEVICE_ID), messages[1][1]))
+
+    results = group_decrypt_batch(bob_store, messages)
+
+    assert len(results) == len(messages)
+    assert isinstance(results[4], SignalProtocolException)
+    assert isinstance(results[-1], SignalProtocolException)
+    plaintexts = [result for result in results if isinstance(result, bytes)]
+    assert sorted(plaintexts) == sorted(b"msg %d %d" % (i, n) for i in range(3) for n in range(5))
+
+    assert group_decrypt_batch(bob_store, []) == []
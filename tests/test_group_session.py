@@ -0,0 +1,84 @@
+import pytest
+
+from signal_protocol.address import ProtocolAddress
+from signal_protocol.error import SignalProtocolException
+from signal_protocol.group_cipher import (
+    group_decrypt,
+    process_sender_key_distribution_message,
+)
+from signal_protocol.group_session import GroupSession
+from signal_protocol.identity_key import IdentityKeyPair
+from signal_protocol.protocol import SenderKeyDistributionMessage
+from signal_protocol.session import process_prekey_bundle
+from signal_protocol.session_cipher import message_decrypt
+from signal_protocol.storage import InMemSignalProtocolStore
+
+from tests.utils.sessions import create_pre_key_bundle
+
+DISTRIBUTION_ID = "a6fe9593-2ca5-41bc-99e9-60a436fbef77"
+
+
+def make_member(alice_store, name, registration_id):
+    member_store = InMemSignalProtocolStore(IdentityKeyPair.generate(), registration_id)
+    bundle = create_pre_key_bundle(member_store)
+    member_address = ProtocolAddress(name, bundle.device_id())
+    process_prekey_bundle(member_address, alice_store, bundle)
+    return member_address, member_store
+
+
+def receive(alice_address, member_address, member_store, envelopes, ciphertext):
+    for recipient, envelope in envelopes:
+        if recipient.name() == member_address.name():
+            skdm = SenderKeyDistributionMessage.try_from(
+                message_decrypt(member_store, alice_address, envelope)
+            )
+            process_sender_key_distribution_message(alice_address, skdm, member_store)
+    return group_decrypt(ciphertext, member_store, alice_address)
+
+
+def test_group_session_distributes_once_and_rotates_on_removal():
+    alice_address = ProtocolAddress("+14159999111", 1)
+    alice_store = InMemSignalProtocolStore(IdentityKeyPair.generate(), 1)
+    bob_address, bob_store = make_member(alice_store, "+14159999222", 2)
+    carol_address, carol_store = make_member(alice_store, "+14159999333", 3)
+
+    group = GroupSession(alice_address, DISTRIBUTION_ID, [bob_address, carol_address])
+    assert len(group.pending_members()) == 2
+
+    ciphertext, envelopes = group.encrypt(alice_store, b"first")
+    assert len(envelopes) == 2
+    assert group.pending_members() == []
+    assert receive(alice_address, bob_address, bob_store, envelopes, ciphertext) == b"first"
+    assert receive(alice_address, carol_address, carol_store, envelopes, ciphertext) == b"first"
+
+    ciphertext, envelopes = group.encrypt(alice_store, b"second")
+    assert envelopes == []
+    assert group_decrypt(ciphertext, bob_store, alice_address) == b"second"
+
+    assert group.remove_member(carol_address, alice_store)
+    assert not group.remove_member(carol_address, alice_store)
+    assert len(group.members()) == 1
+
+    ciphertext, envelopes = group.encrypt(alice_store, b"third")
+    assert [recipient.name() for recipient, _ in envelopes] == [bob_address.name()]
+    assert receive(alice_address, bob_address, bob_store, envelopes, ciphertext) == b"third"
+    with pytest.raises(SignalProtocolException, match="missing sender key state"):
+        group_decrypt(ciphertext, carol_store, alice_address)
+
+
+def test_group_session_new_member_receives_current_chain():
+    alice_address = ProtocolAddress("+14159999111", 1)
+    alice_store = InMemSignalProtocolStore(IdentityKeyPair.generate(), 1)
+    bob_address, bob_store = make_member(alice_store, "+14159999222", 2)
+
+    group = GroupSession(alice_address, DISTRIBUTION_ID, [bob_address])
+    group.encrypt(alice_store, b"before dave")
+
+    dave_address, dave_store = make_member(alice_store, "+14159999444", 4)
+    assert group.add_member(dave_address)
+    assert not group.add_member(dave_address)
+    assert not group.add_member(alice_address)
+
+    ciphertext, envelopes = group.encrypt(alice_store, b"welcome dave")
+    assert len(envelopes) == 1
+    assert receive(alice_address, dave_address, dave_store, envelopes, ciphertext) == b"welcome dave"
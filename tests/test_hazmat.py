@@ -0,0 +1,173 @@
+import hashlib
+
+import pytest
+
+from signal_protocol import hazmat
+from signal_protocol.error import InvalidArgumentException, SignalProtocolException
+
+# NIST SP 800-38A, F.2.5 and F.5.5
+NIST_AES_256_KEY = bytes.fromhex(
+    "603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4"
+)
+NIST_PLAINTEXT = bytes.fromhex("6bc1bee22e409f96e93d7e117393172a")
+
+
+def test_hkdf_rfc5869():
+    # RFC 5869, test case 1
+    ikm = bytes.fromhex("0b" * 22)
+    salt = bytes.fromhex("000102030405060708090a0b0c")
+    info = bytes.fromhex("f0f1f2f3f4f5f6f7f8f9")
+    prk = bytes.fromhex(
+        "077709362c2e32df0ddc3f0dc47bba6390b6c73bb50f9c3122ec844ad7c2b3e5"
+    )
+    okm = bytes.fromhex(
+        "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf"
+        "34007208d5b887185865"
+    )
+
+    assert hazmat.hkdf_extract(ikm, salt=salt) == prk
+    assert hazmat.hkdf_expand(prk, info, 42) == okm
+    assert hazmat.hkdf_derive(ikm, info, 42, salt=salt) == okm
+
+
+def test_hkdf_without_salt():
+    ikm = b"input key material"
+    assert hazmat.hkdf_extract(ikm) == hazmat.hkdf_extract(ikm, salt=bytes(32))
+    assert hazmat.hkdf_derive(ikm, b"info", 64) == hazmat.hkdf_expand(
+        hazmat.hkdf_extract(ikm), b"info", 64
+    )
+
+
+def test_hkdf_invalid_arguments():
+    with pytest.raises(InvalidArgumentException):
+        hazmat.hkdf_derive(b"ikm", b"info", 255 * 32 + 1)
+    with pytest.raises(InvalidArgumentException):
+        hazmat.hkdf_expand(bytes(16), b"info", 32)
+
+
+def test_hashes():
+    assert hazmat.sha512(b"abc") == bytes.fromhex(
+        "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a"
+        "2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f"
+    )
+    assert hazmat.sha256(b"abc") == hashlib.sha256(b"abc").digest()
+    # RFC 4231, test case 2
+    assert hazmat.hmac_sha256(b"Jefe", b"what do ya want for nothing?") == bytes.fromhex(
+        "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+    )
+
+
+def test_aes_256_cbc():
+    iv = bytes.fromhex("000102030405060708090a0b0c0d0e0f")
+    ciphertext = hazmat.aes_256_cbc_encrypt(NIST_AES_256_KEY, iv, NIST_PLAINTEXT)
+
+    # The second block is the PKCS#7 padding.
+    assert len(ciphertext) == 32
+    assert ciphertext[:16] == bytes.fromhex("f58c4c04d6e5f1ba779eabfb5f7bfbd6")
+    assert hazmat.aes_256_cbc_decrypt(NIST_AES_256_KEY, iv, ciphertext) == NIST_PLAINTEXT
+
+    with pytest.raises(SignalProtocolException):
+        hazmat.aes_256_cbc_decrypt(NIST_AES_256_KEY, iv, ciphertext[:16])
+    with pytest.raises(InvalidArgumentException):
+        hazmat.aes_256_cbc_encrypt(NIST_AES_256_KEY[:16], iv, NIST_PLAINTEXT)
+
+
+def test_aes_256_ctr32():
+    counter_block = bytes.fromhex("f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff")
+    nonce, initial_counter = counter_block[:12], int.from_bytes(counter_block[12:], "big")
+
+    ciphertext = hazmat.aes_256_ctr32(
+        NIST_AES_256_KEY, nonce, NIST_PLAINTEXT, initial_counter=initial_counter
+    )
+    assert ciphertext == bytes.fromhex("601ec313775789a5b7a7f504bbf3d228")
+    assert (
+        hazmat.aes_256_ctr32(
+            NIST_AES_256_KEY, nonce, ciphertext, initial_counter=initial_counter
+        )
+        == NIST_PLAINTEXT
+    )
+
+
+def test_aes_256_ctr32_counter_range():
+    # The keystream is AES-256(key, nonce || counter), as computed with AES-ECB.
+    key, nonce = bytes(range(32)), bytes(range(12))
+    assert hazmat.aes_256_ctr32(
+        key, nonce, bytes(16), initial_counter=0xFFFFFFFE
+    ) == bytes.fromhex("8b64b32ff7b39052bba97a548cd54f64")
+    with pytest.raises(InvalidArgumentException):
+        hazmat.aes_256_ctr32(key, nonce, bytes(17), initial_counter=0xFFFFFFFE)
+    with pytest.raises(InvalidArgumentException):
+        hazmat.aes_256_ctr32(key, bytes(16), bytes(16))
+
+
+def test_aes_256_gcm():
+    # The GCM specification, test cases 13 and 14
+    key, nonce = bytes(32), bytes(12)
+    assert hazmat.aes_256_gcm_encrypt(key, nonce, b"") == bytes.fromhex(
+        "530f8afbc74536b9a963b4f1c4cb738b"
+    )
+    ciphertext = hazmat.aes_256_gcm_encrypt(key, nonce, bytes(16))
+    assert ciphertext == bytes.fromhex(
+        "cea7403d4d606b6e074ec5d3baf39d18d0d1c8a799996bf0265b98b5d48ab919"
+    )
+    assert hazmat.aes_256_gcm_decrypt(key, nonce, ciphertext) == bytes(16)
+
+    with_ad = hazmat.aes_256_gcm_encrypt(key, nonce, b"hello", associated_data=b"ad")
+    assert hazmat.aes_256_gcm_decrypt(key, nonce, with_ad, associated_data=b"ad") == b"hello"
+    with pytest.raises(SignalProtocolException):
+        hazmat.aes_256_gcm_decrypt(key, nonce, with_ad)
+    with pytest.raises(InvalidArgumentException):
+        hazmat.aes_256_gcm_encrypt(key, bytes(16), b"hello")
+    with pytest.raises(SignalProtocolException):
+        hazmat.aes_256_gcm_decrypt(key, nonce, bytes(15))
+
+
+def test_aes_256_gcm_with_associated_data():
+    # The GCM specification, test case 16: a partial last block and
+    # associated data that is not a multiple of the block size.
+    key = bytes.fromhex("feffe9928665731c6d6a8f9467308308" * 2)
+    nonce = bytes.fromhex("cafebabefacedbaddecaf888")
+    plaintext = bytes.fromhex(
+        "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a72"
+        "1c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b39"
+    )
+    associated_data = bytes.fromhex("feedfacedeadbeeffeedfacedeadbeefabaddad2")
+    expected = bytes.fromhex(
+        "522dc1f099567d07f47f37a32a84427d643a8cdcbfe5c0c97598a2bd2555d1aa"
+        "8cb08e48590dbb3da7b08b1056828838c5f61e6393ba7a0abcc9f662"
+        "76fc6ece0f4e1768cddf8853bb2d551b"
+    )
+
+    assert (
+        hazmat.aes_256_gcm_encrypt(
+            key, nonce, plaintext, associated_data=associated_data
+        )
+        == expected
+    )
+    assert (
+        hazmat.aes_256_gcm_decrypt(
+            key, nonce, expected, associated_data=associated_data
+        )
+        == plaintext
+    )
+    tampered = bytearray(expected)
+    tampered[-1] ^= 1
+    with pytest.raises(SignalProtocolException):
+        hazmat.aes_256_gcm_decrypt(
+            key, nonce, bytes(tampered), associated_data=associated_data
+        )
+
+
+def test_aes_256_gcm_siv():
+    # RFC 8452, appendix C.2
+    cipher = hazmat.Aes256GcmSiv(bytes.fromhex("01" + "00" * 31))
+    nonce = bytes.fromhex("030000000000000000000000")
+    assert cipher.encrypt(b"", nonce) == bytes.fromhex("07f5f4169bbf55a8400cd47ea6fd400f")
+
+    ciphertext = cipher.encrypt(b"hello", nonce, associated_data=b"ad")
+    assert len(ciphertext) == 5 + 16
+    assert cipher.decrypt(ciphertext, nonce, associated_data=b"ad") == b"hello"
+    with pytest.raises(SignalProtocolException):
+        cipher.decrypt(ciphertext, nonce)
+    with pytest.raises(InvalidArgumentException):
+        hazmat.Aes256GcmSiv(bytes(16))
@@ -1,4 +1,7 @@
-from signal_protocol import identity_key
+import pytest
+
+from signal_protocol import identity_key, storage
+from signal_protocol.error import InvalidArgumentException, SignalProtocolException
 
 
 def test_identity_key_pair_init_and_serialization():
@@ -27,3 +30,71 @@ def test_identity_key_pair_from_bytes():
         decoded_keypair.private_key().serialize()
         == test_keypair.private_key().serialize()
     )
+
+
+def test_alternate_identity_signature():
+    aci_identity = identity_key.IdentityKeyPair.generate()
+    pni_identity = identity_key.IdentityKeyPair.generate()
+
+    signature = aci_identity.sign_alternate_identity(pni_identity.identity_key())
+
+    assert aci_identity.identity_key().verify_alternate_identity(
+        pni_identity.identity_key(), signature
+    )
+    # The signature links the identities in one direction only.
+    assert not pni_identity.identity_key().verify_alternate_identity(
+        aci_identity.identity_key(), signature
+    )
+    assert not aci_identity.identity_key().verify_alternate_identity(
+        identity_key.IdentityKeyPair.generate().identity_key(), signature
+    )
+
+
+def test_identity_key_pair_encrypted_export():
+    test_keypair = identity_key.IdentityKeyPair.generate()
+
+    exported = test_keypair.export_encrypted("correct horse battery staple")
+
+    assert exported[0] == identity_key.ENCRYPTED_EXPORT_VERSION
+    assert test_keypair.private_key().serialize() not in exported
+
+    imported = identity_key.IdentityKeyPair.import_encrypted(
+        exported, b"correct horse battery staple"
+    )
+    assert imported.serialize() == test_keypair.serialize()
+
+
+def test_identity_key_pair_encrypted_export_rejects_wrong_passphrase_and_tampering():
+    exported = identity_key.IdentityKeyPair.generate().export_encrypted("passphrase")
+
+    with pytest.raises(SignalProtocolException):
+        identity_key.IdentityKeyPair.import_encrypted(exported, "wrong passphrase")
+
+    tampered = exported[:-1] + bytes([exported[-1] ^ 0x01])
+    with pytest.raises(SignalProtocolException):
+        identity_key.IdentityKeyPair.import_encrypted(tampered, "passphrase")
+
+    with pytest.raises(InvalidArgumentException):
+        identity_key.IdentityKeyPair.import_encrypted(b"\x02" + exported[1:], "passphrase")
+
+    with pytest.raises(InvalidArgumentException):
+        identity_key.IdentityKeyPair.generate().export_encrypted("")
+
+
+def test_generate_registration_id():
+    registration_ids = {identity_key.generate_registration_id() for _ in range(200)}
+
+    assert all(1 <= i <= identity_key.MAX_REGISTRATION_ID for i in registration_ids)
+    assert identity_key.MAX_REGISTRATION_ID < 2**14
+    assert len(registration_ids) > 1
+
+
+def test_generate_protocol_store():
+    store = storage.InMemSignalProtocolStore.generate()
+    other_store = storage.InMemSignalProtocolStore.generate()
+
+    assert 1 <= store.get_local_registration_id() <= identity_key.MAX_REGISTRATION_ID
+    assert (
+        store.get_identity_key_pair().serialize()
+        != other_store.get_identity_key_pair().serialize()
+    )
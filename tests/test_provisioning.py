@@ -0,0 +1,89 @@
+import pytest
+
+from signal_protocol import address, curve, identity_key
+from signal_protocol.error import InvalidArgumentException, SignalProtocolException
+from signal_protocol.provisioning import ProvisioningCipher, ProvisionMessage
+
+ACI = "9d0652a3-dcc3-4d11-975f-74d61598733f"
+PNI = "aa2a9ff4-bd4e-4cd7-8cd9-1ae7e2a38cb6"
+
+
+def provision_message(aci_identity, pni_identity):
+    return ProvisionMessage(
+        aci_identity,
+        pni_identity,
+        address.Aci(ACI),
+        address.Pni(PNI),
+        "+14151111111",
+        "123456",
+        profile_key=bytes(range(32)),
+        user_agent="OWI",
+        read_receipts=True,
+        provisioning_version=1,
+        master_key=b"\x07" * 32,
+    )
+
+
+def test_provisioning_round_trip():
+    aci_identity = identity_key.IdentityKeyPair.generate()
+    pni_identity = identity_key.IdentityKeyPair.generate()
+    new_device_key_pair = curve.KeyPair.generate()
+
+    envelope = ProvisioningCipher.encrypt(
+        new_device_key_pair.public_key(),
+        provision_message(aci_identity, pni_identity),
+    )
+    message = ProvisioningCipher.decrypt(new_device_key_pair, envelope)
+
+    assert message.aci_identity_key_pair.serialize() == aci_identity.serialize()
+    assert message.pni_identity_key_pair.serialize() == pni_identity.serialize()
+    assert message.aci == address.Aci(ACI)
+    assert isinstance(message.pni, address.Pni)
+    assert message.pni == address.Pni(PNI)
+    assert message.number == "+14151111111"
+    assert message.provisioning_code == "123456"
+    assert message.profile_key == bytes(range(32))
+    assert message.user_agent == "OWI"
+    assert message.read_receipts
+    assert message.provisioning_version == 1
+    assert message.master_key == b"\x07" * 32
+    assert message.account_entropy_pool is None
+
+
+def test_provision_message_serialization():
+    message = provision_message(
+        identity_key.IdentityKeyPair.generate(), identity_key.IdentityKeyPair.generate()
+    )
+    parsed = ProvisionMessage.deserialize(message.serialize())
+    assert parsed.serialize() == message.serialize()
+
+    empty = ProvisionMessage.deserialize(b"")
+    assert empty.aci_identity_key_pair is None
+    assert empty.aci is None
+
+
+def test_provision_message_checks_service_id_kinds():
+    identity = identity_key.IdentityKeyPair.generate()
+    with pytest.raises(InvalidArgumentException):
+        ProvisionMessage(
+            identity, identity, address.Pni(PNI), address.Pni(PNI), "+14151111111", "123456"
+        )
+
+
+def test_provisioning_rejects_other_keys_and_tampering():
+    identity = identity_key.IdentityKeyPair.generate()
+    new_device_key_pair = curve.KeyPair.generate()
+    envelope = ProvisioningCipher.encrypt(
+        new_device_key_pair.public_key(), provision_message(identity, identity)
+    )
+
+    with pytest.raises(SignalProtocolException, match="MAC"):
+        ProvisioningCipher.decrypt(curve.KeyPair.generate(), envelope)
+
+    tampered = bytearray(envelope)
+    tampered[-40] ^= 1
+    with pytest.raises(SignalProtocolException):
+        ProvisioningCipher.decrypt(new_device_key_pair, bytes(tampered))
+
+    with pytest.raises(SignalProtocolException):
+        ProvisioningCipher.decrypt(new_device_key_pair, b"\x0a\x01\x05")
@@ -1,4 +1,5 @@
 import pytest
+from concurrent.futures import ThreadPoolExecutor
 
 from tests.utils.sessions import (
     create_pre_key_bundle,
@@ -1233,3 +1234,357 @@ def test_basic_large_message():
     )
 
     assert original_message == plaintext
+
+
+def test_session_record_describe():
+    alice_session, bob_session = initialize_sessions_v3()
+
+    alice_address = address.ProtocolAddress("+14159999999", 1)
+    bob_address = address.ProtocolAddress("+14158888888", 1)
+    alice_store = storage.InMemSignalProtocolStore(
+        identity_key.IdentityKeyPair.generate(), 1
+    )
+    bob_store = storage.InMemSignalProtocolStore(
+        identity_key.IdentityKeyPair.generate(), 2
+    )
+    alice_store.store_session(bob_address, alice_session)
+    bob_store.store_session(alice_address, bob_session)
+
+    ctexts = [
+        session_cipher.message_encrypt(alice_store, bob_address, b"ping %d" % i)
+        for i in range(3)
+    ]
+    # Deliver the last message first so Bob keeps two skipped message keys
+    session_cipher.message_decrypt(bob_store, alice_address, ctexts[2])
+
+    description = bob_store.load_session(alice_address).describe()
+    current = description["current_session"]
+    assert current["session_version"] == 3
+    assert len(current["remote_identity_fingerprint"]) == 64
+    assert current["receiver_chain_count"] == 1
+    assert current["receiver_chains"][0]["counter"] == 3
+    assert current["receiver_chains"][0]["skipped_message_key_count"] == 2
+    assert description["archived_state_count"] == 0
+    assert "root_key" not in current
+    assert "chain_key" not in current["receiver_chains"][0]
+
+    unredacted = bob_store.load_session(alice_address).describe(redact=False)
+    assert len(unredacted["current_session"]["root_key"]) == 64
+    assert sorted(
+        unredacted["current_session"]["receiver_chains"][0][
+            "skipped_message_key_indices"
+        ]
+    ) == [0, 1]
+
+    assert state.SessionRecord.new_fresh().describe()["current_session"] is None
+
+
+def test_decryption_policy_limits_forward_jump():
+    assert session_cipher.MAX_FORWARD_JUMPS == 25000
+    assert session_cipher.MAX_MESSAGE_KEYS == 2000
+    with pytest.raises(ValueError):
+        session_cipher.DecryptionPolicy(session_cipher.MAX_FORWARD_JUMPS + 1)
+
+    alice_session, bob_session = initialize_sessions_v3()
+
+    alice_address = address.ProtocolAddress("+14159999999", 1)
+    bob_address = address.ProtocolAddress("+14158888888", 1)
+    alice_store = storage.InMemSignalProtocolStore(
+        identity_key.IdentityKeyPair.generate(), 1
+    )
+    bob_store = storage.InMemSignalProtocolStore(
+        identity_key.IdentityKeyPair.generate(), 2
+    )
+    alice_store.store_session(bob_address, alice_session)
+    bob_store.store_session(alice_address, bob_session)
+
+    ctexts = [
+        session_cipher.message_encrypt(alice_store, bob_address, b"ping %d" % i)
+        for i in range(6)
+    ]
+
+    strict = session_cipher.DecryptionPolicy(max_forward_jump=3)
+    with pytest.raises(SignalProtocolException, match="policy allows 3"):
+        session_cipher.message_decrypt(
+            bob_store, alice_address, ctexts[5], policy=strict
+        )
+
+    assert (
+        session_cipher.message_decrypt(
+            bob_store, alice_address, ctexts[3], policy=strict
+        )
+        == b"ping 3"
+    )
+    # The chain is now at index 4, so counter 5 is within the policy
+    assert (
+        session_cipher.message_decrypt(
+            bob_store, alice_address, ctexts[5], policy=strict
+        )
+        == b"ping 5"
+    )
+
+    counts = bob_store.load_session(alice_address).skipped_message_key_counts()
+    assert list(counts.values()) == [4]
+
+
+def test_decryption_policy_prekey_message():
+    alice_address = address.ProtocolAddress("+14151111111", DEVICE_ID)
+    bob_address = address.ProtocolAddress("+14151111112", DEVICE_ID)
+    alice_store = storage.InMemSignalProtocolStore(
+        identity_key.IdentityKeyPair.generate(), 1
+    )
+    bob_store = storage.InMemSignalProtocolStore(
+        identity_key.IdentityKeyPair.generate(), 2
+    )
+
+    session.process_prekey_bundle(
+        bob_address, alice_store, create_pre_key_bundle(bob_store)
+    )
+    # Until Bob replies, every message is a PreKeySignalMessage.
+    ctexts = [
+        protocol.PreKeySignalMessage.try_from(
+            session_cipher.message_encrypt(
+                alice_store, bob_address, b"ping %d" % i
+            ).serialize()
+        )
+        for i in range(6)
+    ]
+
+    strict = session_cipher.DecryptionPolicy(max_forward_jump=3)
+    # Without a session the new chain is measured from index 0.
+    with pytest.raises(SignalProtocolException, match="policy allows 3"):
+        session_cipher.message_decrypt_prekey(
+            bob_store, alice_address, ctexts[5], policy=strict
+        )
+    # The rejected message was not processed: no session, and the prekeys are unused.
+    assert bob_store.load_session(alice_address) is None
+
+    assert (
+        session_cipher.message_decrypt_prekey(
+            bob_store, alice_address, ctexts[3], policy=strict
+        )
+        == b"ping 3"
+    )
+    assert (
+        session_cipher.message_decrypt_prekey(
+            bob_store, alice_address, ctexts[5], policy=strict
+        )
+        == b"ping 5"
+    )
+
+
+def test_transaction_commit_and_rollback():
+    alice_session, bob_session = initialize_sessions_v3()
+
+    alice_address = address.ProtocolAddress("+14159999999", 1)
+    bob_address = address.ProtocolAddress("+14158888888", 1)
+    alice_store = storage.InMemSignalProtocolStore(
+        identity_key.IdentityKeyPair.generate(), 1
+    )
+    bob_store = storage.InMemSignalProtocolStore(
+        identity_key.IdentityKeyPair.generate(), 2
+    )
+    alice_store.store_session(bob_address, alice_session)
+    bob_store.store_session(alice_address, bob_session)
+
+    ctext = session_cipher.message_encrypt(alice_store, bob_address, b"hello")
+    before = bob_store.load_session(alice_address).serialize()
+
+    # The downstream write fails: nothing reaches the store.
+    with pytest.raises(RuntimeError):
+        with bob_store.transaction() as tx:
+            assert session_cipher.message_decrypt(tx, alice_address, ctext) == b"hello"
+            assert tx.load_session(alice_address).serialize() != before
+            raise RuntimeError("could not persist plaintext")
+    assert tx.closed
+    assert bob_store.load_session(alice_address).serialize() == before
+
+    # Leaving the block without committing also discards the changes.
+    with bob_store.transaction() as tx:
+        session_cipher.message_decrypt(tx, alice_address, ctext)
+    assert bob_store.load_session(alice_address).serialize() == before
+
+    tx = bob_store.transaction()
+    tx.rollback()
+    with pytest.raises(SignalProtocolException, match="closed"):
+        session_cipher.message_decrypt(tx, alice_address, ctext)
+
+    with bob_store.transaction() as tx:
+        assert session_cipher.message_decrypt(tx, alice_address, ctext) == b"hello"
+        tx.commit()
+        with pytest.raises(SignalProtocolException, match="closed"):
+            tx.commit()
+    assert bob_store.load_session(alice_address).serialize() != before
+
+    # The committed ratchet advance rejects the message as a duplicate.
+    with pytest.raises(SignalProtocolException, match="old counter"):
+        session_cipher.message_decrypt(bob_store, alice_address, ctext)
+
+
+def test_transaction_conflicting_commit():
+    alice_session, bob_session = initialize_sessions_v3()
+
+    alice_address = address.ProtocolAddress("+14159999999", 1)
+    bob_address = address.ProtocolAddress("+14158888888", 1)
+    alice_store = storage.InMemSignalProtocolStore(
+        identity_key.IdentityKeyPair.generate(), 1
+    )
+    bob_store = storage.InMemSignalProtocolStore(
+        identity_key.IdentityKeyPair.generate(), 2
+    )
+    alice_store.store_session(bob_address, alice_session)
+    bob_store.store_session(alice_address, bob_session)
+
+    first = session_cipher.message_encrypt(alice_store, bob_address, b"first")
+    second = session_cipher.message_encrypt(alice_store, bob_address, b"second")
+
+    with bob_store.transaction() as tx:
+        assert session_cipher.message_decrypt(tx, alice_address, first) == b"first"
+        # Another thread decrypts directly while the transaction is open.
+        assert (
+            session_cipher.message_decrypt(bob_store, alice_address, second)
+            == b"second"
+        )
+        after_direct = bob_store.load_session(alice_address).serialize()
+        with pytest.raises(SignalProtocolException, match="conflicts"):
+            tx.commit()
+        assert tx.closed
+
+    # The direct decrypt was not overwritten, and the first message can still
+    # be decrypted with the key it skipped.
+    assert bob_store.load_session(alice_address).serialize() == after_direct
+    assert session_cipher.message_decrypt(bob_store, alice_address, first) == b"first"
+
+
+def test_transaction_prekey_message():
+    alice_address = address.ProtocolAddress("+14151111111", DEVICE_ID)
+    bob_address = address.ProtocolAddress("+14151111112", DEVICE_ID)
+    alice_store = storage.InMemSignalProtocolStore(
+        identity_key.IdentityKeyPair.generate(), 1
+    )
+    bob_store = storage.InMemSignalProtocolStore(
+        identity_key.IdentityKeyPair.generate(), 2
+    )
+
+    session.process_prekey_bundle(
+        bob_address, alice_store, create_pre_key_bundle(bob_store)
+    )
+    ctext = session_cipher.message_encrypt(alice_store, bob_address, b"hi bob")
+    assert ctext.message_type() == 3  # CiphertextMessageType::PreKey => 3
+
+    with bob_store.transaction() as tx:
+        assert session_cipher.message_decrypt(tx, alice_address, ctext) == b"hi bob"
+        assert tx.load_session(alice_address) is not None
+    # Rolled back: no session, the identity is unknown and the prekey is unused.
+    assert bob_store.load_session(alice_address) is None
+    assert bob_store.get_identity(alice_address) is None
+
+    with bob_store.transaction() as tx:
+        assert session_cipher.message_decrypt(tx, alice_address, ctext) == b"hi bob"
+        tx.commit()
+    assert bob_store.load_session(alice_address) is not None
+    assert (
+        bob_store.get_identity(alice_address).serialize()
+        == alice_store.get_identity_key_pair().identity_key().serialize()
+    )
+
+
+def test_store_is_shared_between_threads():
+    bob_address = address.ProtocolAddress("+14158888888", 1)
+    bob_store = storage.InMemSignalProtocolStore(
+        identity_key.IdentityKeyPair.generate(), 2
+    )
+
+    contacts = []
+    for i in range(4):
+        alice_session, bob_session = initialize_sessions_v3()
+        alice_address = address.ProtocolAddress(f"+1415999999{i}", 1)
+        alice_store = storage.InMemSignalProtocolStore(
+            identity_key.IdentityKeyPair.generate(), 1
+        )
+        alice_store.store_session(bob_address, alice_session)
+        bob_store.store_session(alice_address, bob_session)
+        ctexts = [
+            session_cipher.message_encrypt(alice_store, bob_address, b"msg %d" % n)
+            for n in range(20)
+        ]
+        contacts.append((alice_address, ctexts))
+
+    # Two workers per contact: decrypts for one address are serialized, so every
+    # message decrypts exactly once even when they race on the same session.
+    def decrypt(alice_address, ctexts):
+        return [
+            session_cipher.message_decrypt(bob_store, alice_address, ctext)
+            for ctext in ctexts
+        ]
+
+    with ThreadPoolExecutor(max_workers=8) as executor:
+        futures = [
+            executor.submit(decrypt, alice_address, ctexts[half::2])
+            for alice_address, ctexts in contacts
+            for half in range(2)
+        ]
+        results = [future.result() for future in futures]
+
+    for i in range(len(contacts)):
+        plaintexts = sorted(results[2 * i] + results[2 * i + 1])
+        assert plaintexts == sorted(b"msg %d" % n for n in range(20))
+
+
+def test_decrypt_batch():
+    bob_address = address.ProtocolAddress("+14158888888", DEVICE_ID)
+    bob_store = storage.InMemSignalProtocolStore(
+        identity_key.IdentityKeyPair.generate(), 2
+    )
+
+    messages = []
+    expected = []
+    for i in range(3):
+        alice_address = address.ProtocolAddress(f"+1415999999{i}", DEVICE_ID)
+        alice_store = storage.InMemSignalProtocolStore(
+            identity_key.IdentityKeyPair.generate(), 1
+        )
+        session.process_prekey_bundle(
+            bob_address, alice_store, create_pre_key_bundle(bob_store)
+        )
+        for n in range(4):
+            ptext = b"msg %d %d" % (i, n)
+            ctext = session_cipher.message_encrypt(alice_store, bob_address, ptext)
+            messages.append((alice_address, ctext.message_type(), ctext.serialize()))
+            expected.append(ptext)
+    messages.append((alice_address, 4, b"not a message"))
+    messages.append((alice_address, 2, b"\x33 garbage"))
+    messages.append(messages[-3])
+
+    results = session_cipher.decrypt_batch(bob_store, messages)
+
+    assert results[: len(expected)] == expected
+    assert isinstance(results[-3], error.InvalidArgumentException)
+    assert isinstance(results[-2], SignalProtocolException)
+    assert isinstance(results[-1], SignalProtocolException)
+    assert all(bob_store.load_session(a) is not None for a, _, _ in messages)
+
+
+def test_decrypt_batch_transaction():
+    alice_address = address.ProtocolAddress("+14151111111", DEVICE_ID)
+    bob_address = address.ProtocolAddress("+14151111112", DEVICE_ID)
+    alice_store = storage.InMemSignalProtocolStore(
+        identity_key.IdentityKeyPair.generate(), 1
+    )
+    bob_store = storage.InMemSignalProtocolStore(
+        identity_key.IdentityKeyPair.generate(), 2
+    )
+    session.process_prekey_bundle(
+        bob_address, alice_store, create_pre_key_bundle(bob_store)
+    )
+    ctext = session_cipher.message_encrypt(alice_store, bob_address, b"hi bob")
+    messages = [(alice_address, ctext.message_type(), ctext.serialize())]
+
+    with bob_store.transaction() as tx:
+        assert session_cipher.decrypt_batch(tx, messages) == [b"hi bob"]
+    assert bob_store.load_session(alice_address) is None
+
+    with bob_store.transaction() as tx:
+        assert session_cipher.decrypt_batch(tx, messages) == [b"hi bob"]
+        tx.commit()
+    assert bob_store.load_session(alice_address) is not None
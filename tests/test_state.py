@@ -1,4 +1,7 @@
+import pytest
+
 from signal_protocol import curve, address, identity_key, state, storage
+from signal_protocol.error import InvalidArgumentException
 
 DEVICE_ID = 1
 
@@ -48,3 +51,9 @@ def test_define_prekey_bundle_under_prekey_exhaustion():
     assert len(prekeyrecords) == 256
     assert prekeyrecords[12].id() != prekeyrecords[13].id()
     assert prekeyrecords[14].key_pair() != prekeyrecords[15].key_pair()
+
+
+def test_generate_n_prekeys_rejects_id_overflow():
+    with pytest.raises(InvalidArgumentException):
+        state.generate_n_prekeys(2, 2**32 - 1)
+    assert len(state.generate_n_prekeys(1, 2**32 - 1)) == 1
@@ -0,0 +1,203 @@
+import pytest
+import threading
+from concurrent.futures import ThreadPoolExecutor
+
+from signal_protocol import (
+    account,
+    address,
+    curve,
+    identity_key,
+    session_cipher,
+    state,
+    storage,
+)
+from signal_protocol.error import InvalidArgumentException, SignalProtocolException
+
+from tests.utils.sessions import initialize_sessions_v3
+
+THREADS = 16
+ROUNDS = 50
+
+
+def hammer(worker, threads=THREADS):
+    barrier = threading.Barrier(threads)
+
+    def run(index):
+        barrier.wait()
+        return worker(index)
+
+    with ThreadPoolExecutor(max_workers=threads) as executor:
+        return list(executor.map(run, range(threads)))
+
+
+def test_store_records_from_many_threads():
+    store = storage.InMemSignalProtocolStore.generate()
+    key_pair = curve.KeyPair.generate()
+
+    def worker(index):
+        for round in range(ROUNDS):
+            pre_key_id = index * ROUNDS + round
+            store.save_pre_key(pre_key_id, state.PreKeyRecord(pre_key_id, key_pair))
+            assert store.get_pre_key(pre_key_id).id() == pre_key_id
+            if round % 2:
+                store.remove_pre_key(pre_key_id)
+
+            peer = address.ProtocolAddress(f"+1415555{index:04}", round % 4 + 1)
+            store.store_session(peer, state.SessionRecord.new_fresh())
+            assert store.load_session(peer) is not None
+            store.save_identity(peer, identity_key.IdentityKeyPair.generate().identity_key())
+
+    hammer(worker)
+
+    for index in range(THREADS):
+        for round in range(ROUNDS):
+            pre_key_id = index * ROUNDS + round
+            if round % 2:
+                with pytest.raises(SignalProtocolException):
+                    store.get_pre_key(pre_key_id)
+            else:
+                assert store.get_pre_key(pre_key_id).id() == pre_key_id
+
+
+def test_shared_records_from_many_threads():
+    alice_session, _ = initialize_sessions_v3()
+    registry = address.DeviceRegistry()
+
+    def worker(index):
+        for round in range(ROUNDS):
+            alice_session.serialize()
+            alice_session.describe()
+            if index == 0 and round == 0:
+                alice_session.archive_current_state()
+            registry.update(address.ProtocolAddress("+14151111111", index + 1), round)
+            # The last round keeps the device.
+            if round % 3 == 0:
+                registry.remove_device(address.ProtocolAddress("+14151111111", index + 1))
+
+    hammer(worker)
+
+    assert alice_session.describe()["archived_state_count"] == 1
+    assert [int(device) for device in registry.devices("+14151111111")] == list(
+        range(1, THREADS + 1)
+    )
+
+
+def test_store_serialization():
+    store, _ = account.provision(num_one_time=5, num_kyber=3)
+    peer = address.ProtocolAddress("+14151111111", 1)
+    alice_session, _ = initialize_sessions_v3()
+    store.store_session(peer, alice_session)
+    store.save_identity(peer, identity_key.IdentityKeyPair.generate().identity_key())
+
+    restored = storage.InMemSignalProtocolStore.deserialize(store.serialize())
+
+    assert restored.get_identity_key_pair().serialize() == store.get_identity_key_pair().serialize()
+    assert restored.get_local_registration_id() == store.get_local_registration_id()
+    assert restored.get_identity(peer) == store.get_identity(peer)
+    assert restored.load_session(peer).serialize() == alice_session.serialize()
+
+    with pytest.raises(SignalProtocolException):
+        storage.InMemSignalProtocolStore.deserialize(b"\xff" * 8)
+
+
+def test_multi_account_store_views():
+    accounts = storage.MultiAccountStore()
+    bob_address = address.ProtocolAddress("+14158888888", 1)
+    alice_address = address.ProtocolAddress("+14159999999", 1)
+    alice_session, bob_session = initialize_sessions_v3()
+
+    alice_store = accounts.add_account(
+        "alice", identity_key.IdentityKeyPair.generate(), identity_key.generate_registration_id()
+    )
+    bob_store = accounts.add_account(
+        "bob", identity_key.IdentityKeyPair.generate(), identity_key.generate_registration_id()
+    )
+    alice_store.store_session(bob_address, alice_session)
+    bob_store.store_session(alice_address, bob_session)
+
+    # Every view on an account shares its records.
+    ctext = session_cipher.message_encrypt(accounts["alice"], bob_address, b"hi")
+    assert session_cipher.message_decrypt(accounts.get("bob"), alice_address, ctext) == b"hi"
+    ctext = session_cipher.message_encrypt(alice_store, bob_address, b"again")
+    assert session_cipher.message_decrypt(bob_store, alice_address, ctext) == b"again"
+
+    assert accounts.accounts() == ["alice", "bob"]
+    assert "alice" in accounts and len(accounts) == 2
+    assert accounts.get("carol") is None
+    with pytest.raises(KeyError):
+        accounts["carol"]
+    with pytest.raises(InvalidArgumentException):
+        accounts.add_account("alice", identity_key.IdentityKeyPair.generate(), 1)
+
+    assert accounts.remove_account("alice")
+    assert not accounts.remove_account("alice")
+    assert accounts.accounts() == ["bob"]
+
+
+def test_multi_account_store_persistence(tmp_path):
+    accounts = storage.MultiAccountStore(tmp_path)
+    bob_address = address.ProtocolAddress("+14158888888", 1)
+    alice_address = address.ProtocolAddress("+14159999999", 1)
+    alice_session, bob_session = initialize_sessions_v3()
+    accounts.add_account("alice/../ümlaut", identity_key.IdentityKeyPair.generate(), 1)
+    accounts.add_account("bob", identity_key.IdentityKeyPair.generate(), 2)
+    accounts["alice/../ümlaut"].store_session(bob_address, alice_session)
+    accounts["bob"].store_session(alice_address, bob_session)
+    ctext = session_cipher.message_encrypt(accounts["alice/../ümlaut"], bob_address, b"hi")
+    accounts.save()
+
+    assert len(list(tmp_path.iterdir())) == 2
+    reopened = storage.MultiAccountStore(str(tmp_path))
+    assert reopened.accounts() == ["alice/../ümlaut", "bob"]
+    assert reopened["bob"].get_local_registration_id() == 2
+    assert session_cipher.message_decrypt(reopened["bob"], alice_address, ctext) == b"hi"
+
+    reopened.remove_account("bob")
+    assert storage.MultiAccountStore(tmp_path).accounts() == ["alice/../ümlaut"]
+
+    with pytest.raises(SignalProtocolException):
+        storage.MultiAccountStore().save()
+
+
+def test_multi_account_store_save_while_removing(tmp_path):
+    accounts = storage.MultiAccountStore(tmp_path)
+    for index in range(THREADS * 4):
+        accounts.add_account(f"account {index}", identity_key.IdentityKeyPair.generate(), 1)
+    accounts.save()
+
+    # Half of the threads save, the others remove their accounts meanwhile.
+    def worker(index):
+        if index % 2:
+            for _ in range(4):
+                accounts.save()
+        else:
+            for account in range(index * 4, index * 4 + 4):
+                assert accounts.remove_account(f"account {account}")
+
+    hammer(worker)
+
+    # No save wrote back the file of an account removed while it was running.
+    assert storage.MultiAccountStore(tmp_path).accounts() == accounts.accounts()
+
+
+def test_multi_account_store_bulk_operations():
+    accounts = storage.MultiAccountStore()
+    for account_id, num_one_time in [("a", 20), ("b", 5)]:
+        store, _ = account.provision(num_one_time=num_one_time, num_kyber=num_one_time)
+        accounts.import_account(account_id, store.serialize())
+    accounts.add_account("c", identity_key.IdentityKeyPair.generate(), 3)
+
+    assert accounts.pre_key_status() == {
+        "a": {"pre_keys": 20, "signed_pre_keys": 1, "kyber_pre_keys": 21},
+        "b": {"pre_keys": 5, "signed_pre_keys": 1, "kyber_pre_keys": 6},
+        "c": {"pre_keys": 0, "signed_pre_keys": 0, "kyber_pre_keys": 0},
+    }
+    assert accounts.accounts_needing_pre_keys() == ["b", "c"]
+    assert accounts.accounts_needing_pre_keys(min_pre_keys=1, min_kyber_pre_keys=1) == ["c"]
+
+    exported = accounts.export()
+    assert sorted(exported) == ["a", "b", "c"]
+    restored = storage.InMemSignalProtocolStore.deserialize(exported["a"])
+    assert restored.get_identity_key_pair().serialize() == (
+        accounts["a"].get_identity_key_pair().serialize()
+    )
@@ -0,0 +1,187 @@
+"""A direct transcription of the pseudocode in the XEdDSA and VXEdDSA
+specification (https://signal.org/docs/specifications/xeddsa/), with plain
+Python integers. It is slow and not constant time: it only exists to check the
+Rust implementation against the specification.
+"""
+
+import hashlib
+
+p = 2**255 - 19
+q = 2**252 + 27742317777372353535851937790883648493
+d = -121665 * pow(121666, p - 2, p) % p
+A = 486662
+c = 3  # log2 of the cofactor
+
+B_Y = 4 * pow(5, p - 2, p) % p
+
+
+def inv(x):
+    return pow(x, p - 2, p)
+
+
+def is_square(x):
+    return pow(x, (p - 1) // 2, p) in (0, 1)
+
+
+def sqrt(x):
+    root = pow(x, (p + 3) // 8, p)
+    if root * root % p != x % p:
+        root = root * pow(2, (p - 1) // 4, p) % p
+    if root * root % p != x % p:
+        return None
+    return root
+
+
+def recover_x(y, s):
+    x = sqrt((y * y - 1) * inv(d * y * y + 1) % p)
+    if x is None:
+        return None
+    # x == 0 with s == 1 is accepted, as curve25519-dalek (and ref10) do.
+    if x & 1 != s:
+        x = (p - x) % p
+    return x
+
+
+B = (recover_x(B_Y, 0), B_Y)
+IDENTITY = (0, 1)
+
+
+def add(P, Q):
+    (x1, y1), (x2, y2) = P, Q
+    t = d * x1 * x2 * y1 * y2 % p
+    return (
+        (x1 * y2 + x2 * y1) * inv(1 + t) % p,
+        (y1 * y2 + x1 * x2) * inv(1 - t) % p,
+    )
+
+
+def neg(P):
+    return ((p - P[0]) % p, P[1])
+
+
+def mul(k, P):
+    result = IDENTITY
+    while k:
+        if k & 1:
+            result = add(result, P)
+        P = add(P, P)
+        k >>= 1
+    return result
+
+
+def encode(P):
+    x, y = P
+    return (y | ((x & 1) << 255)).to_bytes(32, "little")
+
+
+def decode(data):
+    y = int.from_bytes(data, "little")
+    s, y = y >> 255, y & (2**255 - 1)
+    if y >= p:
+        return None
+    x = recover_x(y, s)
+    if x is None or encode((x, y)) != data:
+        return None
+    return (x, y)
+
+
+def hash_i(i, X):
+    return hashlib.sha512((2**256 - 1 - i).to_bytes(32, "little") + X).digest()
+
+
+def hash_int(data):
+    return int.from_bytes(data, "little")
+
+
+def clamp(k):
+    k = bytearray(k)
+    k[0] &= 248
+    k[31] &= 127
+    k[31] |= 64
+    return int.from_bytes(k, "little")
+
+
+def calculate_key_pair(k):
+    E = mul(k, B)
+    A_point = (E[0] if E[0] & 1 == 0 else (p - E[0]) % p, E[1])
+    a = k % q if E[0] & 1 == 0 else -k % q
+    return A_point, a
+
+
+def u_to_y(u):
+    return (u - 1) * inv(u + 1) % p
+
+
+def montgomery_u(P):
+    return (1 + P[1]) * inv(1 - P[1]) % p
+
+
+def convert_mont(u):
+    y = u_to_y(u % 2**255)
+    x = recover_x(y, 0)
+    return None if x is None else (x, y)
+
+
+def elligator2(r):
+    u1 = -A * inv(1 + 2 * r * r) % p
+    w1 = u1 * (u1 * u1 + A * u1 + 1) % p
+    return u1 if is_square(w1) else (-A - u1) % p
+
+
+def hash_to_point(X):
+    h = hash_int(hash_i(2, X))
+    r = h % 2**255
+    s = (h % 2**256) >> 255
+    y = u_to_y(elligator2(r))
+    return mul(2**c, (recover_x(y, s), y))
+
+
+def xeddsa_sign(k, M, Z):
+    A_point, a = calculate_key_pair(clamp(k))
+    r = hash_int(hash_i(1, a.to_bytes(32, "little") + M + Z)) % q
+    R = mul(r, B)
+    h = hash_int(hashlib.sha512(encode(R) + encode(A_point) + M).digest()) % q
+    s = (r + h * a) % q
+    return encode(R) + s.to_bytes(32, "little")
+
+
+def vxeddsa_sign(k, M, Z):
+    A_point, a = calculate_key_pair(clamp(k))
+    Bv = hash_to_point(encode(A_point) + M)
+    V = mul(a, Bv)
+    r = hash_int(hash_i(3, a.to_bytes(32, "little") + encode(V) + Z)) % q
+    R = mul(r, B)
+    Rv = mul(r, Bv)
+    h = hash_int(hash_i(4, encode(A_point) + encode(V) + encode(R) + encode(Rv) + M)) % q
+    s = (r + h * a) % q
+    v = hash_i(5, encode(mul(2**c, V)))[:32]
+    return encode(V) + h.to_bytes(32, "little") + s.to_bytes(32, "little"), v
+
+
+def vxeddsa_verify(u, M, signature):
+    if len(signature) != 96:
+        return None
+    u = int.from_bytes(u, "little")
+    V_bytes = signature[:32]
+    h = int.from_bytes(signature[32:64], "little")
+    s = int.from_bytes(signature[64:], "little")
+    V = decode(V_bytes)
+    if u >= p or V is None or h >= 2**253 or s >= 2**253:
+        return None
+    A_point = convert_mont(u)
+    if A_point is None:
+        return None
+    Bv = hash_to_point(encode(A_point) + M)
+    if IDENTITY in (mul(2**c, A_point), mul(2**c, V), Bv):
+        return None
+    R = add(mul(s, B), neg(mul(h, A_point)))
+    Rv = add(mul(s, Bv), neg(mul(h, V)))
+    hcheck = hash_int(hash_i(4, encode(A_point) + V_bytes + encode(R) + encode(Rv) + M)) % q
+    if hcheck != h:
+        return None
+    return hash_i(5, encode(mul(2**c, V)))[:32]
+
+
+def public_key(k):
+    """The Curve25519 public key u of the private key k, as in X25519."""
+    return montgomery_u(mul(clamp(k), B)).to_bytes(32, "little")